@@ -0,0 +1,444 @@
+//! Keyframe animations in the Bedrock/geckolib format (`.animation.json`) for models from geo.rs.
+//! Any number can be a string instead. Those are lua expressions (not molang) that the build script compiles into
+//! gen.lua so they work on the web too. They can read `query.anim_time` and `query.life_time` (or `q.`)
+//! and math is lua's, so trig functions take radians.
+//! https://learn.microsoft.com/en-us/minecraft/creator/reference/content/animationsreference/examples/animationgettingstarted
+
+use std::collections::BTreeMap;
+use std::fmt;
+use glam::{Mat4, Vec3};
+use serde::Deserialize;
+use crate::geo::Geometry;
+
+#[derive(Clone, Debug)]
+pub struct Animation {
+    pub name: String,
+    /// Seconds.
+    pub length: f32,
+    pub looping: Looping,
+    pub bones: Vec<BoneAnimation>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Looping {
+    /// Goes back to the rest pose at the end.
+    Once,
+    Loop,
+    HoldOnLastFrame,
+}
+
+#[derive(Clone, Debug)]
+pub struct BoneAnimation {
+    pub bone: String,
+    /// Degrees, added to the bone's rotation.
+    pub rotation: Channel,
+    /// Pixels, added to the bone's position.
+    pub position: Channel,
+    pub scale: Channel,
+}
+
+/// Values for one property of a bone over time. No keyframes means it isn't animated.
+#[derive(Clone, Debug, Default)]
+pub struct Channel {
+    /// Sorted by time.
+    pub keyframes: Vec<Keyframe>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub time: f32,
+    /// The value coming into this keyframe and the value leaving it. Only different if it jumps.
+    pub pre: [Value; 3],
+    pub post: [Value; 3],
+    pub lerp: Lerp,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Lerp {
+    Linear,
+    /// Smooth curve through the keyframes on either side. Used if either end of a section asks for it.
+    CatmullRom,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f32),
+    /// Lua source. See the top of this file.
+    Expression(String),
+}
+
+/// What lua expressions can see as `query`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Query {
+    /// Seconds since this animation started.
+    pub anim_time: f32,
+    /// Seconds since the entity was created.
+    pub life_time: f32,
+}
+
+/// An animation file the build script found in assets/animations (see gen::animations).
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationSource {
+    pub name: &'static str,
+    pub json: &'static str,
+}
+
+impl AnimationSource {
+    /// Already parsed once by the build script so it's fine to panic.
+    pub fn load(&self) -> Vec<Animation> {
+        parse(self.json).unwrap_or_else(|e| panic!("Failed to load {}.animation.json: {}", self.name, e))
+    }
+}
+
+#[derive(Debug)]
+pub enum AnimError {
+    Json(serde_json::Error),
+    BadTime { animation: String, time: String },
+    BadLoop { animation: String, value: String },
+}
+
+impl fmt::Display for AnimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnimError::Json(e) => write!(f, "{}", e),
+            AnimError::BadTime { animation, time } => write!(f, "{} has a keyframe at {:?} which isn't a number.", animation, time),
+            AnimError::BadLoop { animation, value } => write!(f, "{} has loop {:?}. Expected true, false or hold_on_last_frame.", animation, value),
+        }
+    }
+}
+
+/// Every animation in a file, sorted by name.
+pub fn parse(json: &str) -> Result<Vec<Animation>, AnimError> {
+    let file: FileJson = serde_json::from_str(json).map_err(AnimError::Json)?;
+    file.animations.into_iter().map(|(name, animation)| animation.convert(name)).collect()
+}
+
+impl Animation {
+    /// Where in the animation it is `elapsed` seconds after it started. None once a non-looping one is over.
+    pub fn time(&self, elapsed: f32) -> Option<f32> {
+        match self.looping {
+            Looping::Loop if self.length > 0.0 => Some(elapsed % self.length),
+            Looping::Loop | Looping::HoldOnLastFrame => Some(elapsed.min(self.length)),
+            Looping::Once => if elapsed <= self.length { Some(elapsed) } else { None },
+        }
+    }
+
+    /// Model space transform of every bone (like Geometry::pose) `elapsed` seconds after the animation started.
+    /// `eval` runs an expression at the given anim_time.
+    pub fn pose(&self, geometry: &Geometry, elapsed: f32, eval: &mut dyn FnMut(&str, f32) -> f32) -> Vec<Mat4> {
+        let Some(time) = self.time(elapsed) else {
+            return geometry.rest_pose();
+        };
+        let mut eval = |source: &str| eval(source, time);
+        geometry.pose(|_, bone| {
+            match self.bones.iter().find(|animation| animation.bone == bone.name) {
+                None => bone.transform(Vec3::ZERO, Vec3::ZERO, Vec3::ONE),
+                Some(animation) => {
+                    // Same axis flips as the geometry.
+                    let rotation = animation.rotation.sample(time, &mut eval).map_or(Vec3::ZERO, |r| Vec3::new(-r.x, -r.y, r.z));
+                    let position = animation.position.sample(time, &mut eval).map_or(Vec3::ZERO, |p| Vec3::new(-p.x, p.y, p.z) / 16.0);
+                    let scale = animation.scale.sample(time, &mut eval).unwrap_or(Vec3::ONE);
+                    bone.transform(rotation, position, scale)
+                }
+            }
+        })
+    }
+
+    /// Every lua expression in the order the build script numbers them. Can have duplicates.
+    pub fn expressions(&self) -> impl Iterator<Item=&str> {
+        self.bones.iter()
+            .flat_map(|bone| [&bone.rotation, &bone.position, &bone.scale])
+            .flat_map(|channel| channel.keyframes.iter())
+            // Post is usually just a copy of pre.
+            .flat_map(|keyframe| keyframe.pre.iter().chain(keyframe.post.iter().filter(|_| keyframe.post != keyframe.pre)))
+            .filter_map(|value| match value {
+                Value::Number(_) => None,
+                Value::Expression(source) => Some(source.as_str()),
+            })
+    }
+}
+
+impl Channel {
+    pub fn sample(&self, time: f32, eval: &mut dyn FnMut(&str) -> f32) -> Option<Vec3> {
+        let keys = &self.keyframes;
+        let first = keys.first()?;
+        let last = keys.last().unwrap();
+        if time <= first.time {
+            return Some(vector(&first.pre, eval));
+        }
+        if time >= last.time {
+            return Some(vector(&last.post, eval));
+        }
+
+        let next = keys.iter().position(|key| key.time > time).unwrap();
+        let (a, b) = (&keys[next - 1], &keys[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        let start = vector(&a.post, eval);
+        let end = vector(&b.pre, eval);
+        if a.lerp == Lerp::CatmullRom || b.lerp == Lerp::CatmullRom {
+            // The ends of the animation act like the curve keeps going straight.
+            let before = if next >= 2 { vector(&keys[next - 2].post, eval) } else { start };
+            let after = if next + 1 < keys.len() { vector(&keys[next + 1].pre, eval) } else { end };
+            Some(catmull_rom(before, start, end, after, t))
+        } else {
+            Some(start.lerp(end, t))
+        }
+    }
+}
+
+fn vector(values: &[Value; 3], eval: &mut dyn FnMut(&str) -> f32) -> Vec3 {
+    let mut result = Vec3::ZERO;
+    for (i, value) in values.iter().enumerate() {
+        result[i] = match value {
+            Value::Number(n) => *n,
+            Value::Expression(source) => eval(source),
+        };
+    }
+    result
+}
+
+/// Passes through b at t=0 and c at t=1.
+pub fn catmull_rom(a: Vec3, b: Vec3, c: Vec3, d: Vec3, t: f32) -> Vec3 {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    0.5 * ((2.0 * b) + (c - a) * t + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2 + (3.0 * b - a - 3.0 * c + d) * t3)
+}
+
+// The file format. BTreeMaps so everything comes out in the same order every time (expressions are numbered by it).
+
+#[derive(Deserialize)]
+struct FileJson {
+    animations: BTreeMap<String, AnimationJson>,
+}
+
+#[derive(Deserialize)]
+struct AnimationJson {
+    #[serde(rename = "loop", default)]
+    looping: LoopJson,
+    animation_length: Option<f32>,
+    #[serde(default)]
+    bones: BTreeMap<String, BoneJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LoopJson {
+    Bool(bool),
+    String(String),
+}
+
+impl Default for LoopJson {
+    fn default() -> Self {
+        LoopJson::Bool(false)
+    }
+}
+
+#[derive(Deserialize)]
+struct BoneJson {
+    rotation: Option<ChannelJson>,
+    position: Option<ChannelJson>,
+    scale: Option<ChannelJson>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ChannelJson {
+    Constant(VectorJson),
+    Keyframes(BTreeMap<String, KeyframeJson>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum KeyframeJson {
+    Vector(VectorJson),
+    Full {
+        pre: Option<VectorJson>,
+        post: Option<VectorJson>,
+        lerp_mode: Option<String>,
+    },
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum VectorJson {
+    /// The same for all three.
+    Single(ValueJson),
+    Vector([ValueJson; 3]),
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(untagged)]
+enum ValueJson {
+    Number(f32),
+    String(String),
+}
+
+impl AnimationJson {
+    fn convert(self, name: String) -> Result<Animation, AnimError> {
+        let looping = match self.looping {
+            LoopJson::Bool(true) => Looping::Loop,
+            LoopJson::Bool(false) => Looping::Once,
+            LoopJson::String(value) if value == "hold_on_last_frame" => Looping::HoldOnLastFrame,
+            LoopJson::String(value) => return Err(AnimError::BadLoop { animation: name, value }),
+        };
+
+        let mut bones = vec![];
+        for (bone, json) in self.bones {
+            bones.push(BoneAnimation {
+                bone,
+                rotation: ChannelJson::convert(json.rotation, &name)?,
+                position: ChannelJson::convert(json.position, &name)?,
+                scale: ChannelJson::convert(json.scale, &name)?,
+            });
+        }
+
+        let last_keyframe = bones.iter()
+            .flat_map(|bone| [&bone.rotation, &bone.position, &bone.scale])
+            .filter_map(|channel| channel.keyframes.last())
+            .map(|keyframe| keyframe.time)
+            .fold(0.0, f32::max);
+
+        Ok(Animation {
+            length: self.animation_length.unwrap_or(last_keyframe),
+            name,
+            looping,
+            bones,
+        })
+    }
+}
+
+impl ChannelJson {
+    fn convert(json: Option<ChannelJson>, animation: &str) -> Result<Channel, AnimError> {
+        let mut keyframes = vec![];
+        match json {
+            None => {}
+            Some(ChannelJson::Constant(value)) => {
+                let value = value.convert();
+                keyframes.push(Keyframe { time: 0.0, pre: value.clone(), post: value, lerp: Lerp::Linear });
+            }
+            Some(ChannelJson::Keyframes(keys)) => {
+                for (time, key) in keys {
+                    let Ok(time) = time.parse::<f32>() else {
+                        return Err(AnimError::BadTime { animation: animation.to_string(), time });
+                    };
+                    keyframes.push(key.convert(time));
+                }
+                // The map sorted them as strings ("10.0" < "2.0").
+                keyframes.sort_by(|a, b| a.time.total_cmp(&b.time));
+            }
+        }
+        Ok(Channel { keyframes })
+    }
+}
+
+impl KeyframeJson {
+    fn convert(self, time: f32) -> Keyframe {
+        match self {
+            KeyframeJson::Vector(value) => {
+                let value = value.convert();
+                Keyframe { time, pre: value.clone(), post: value, lerp: Lerp::Linear }
+            }
+            KeyframeJson::Full { pre, post, lerp_mode } => {
+                let zero = VectorJson::Single(ValueJson::Number(0.0));
+                let pre = pre.or(post.clone()).unwrap_or(zero.clone());
+                let post = post.unwrap_or(pre.clone());
+                let lerp = if lerp_mode.as_deref() == Some("catmullrom") { Lerp::CatmullRom } else { Lerp::Linear };
+                Keyframe { time, pre: pre.convert(), post: post.convert(), lerp }
+            }
+        }
+    }
+}
+
+impl VectorJson {
+    fn convert(self) -> [Value; 3] {
+        match self {
+            VectorJson::Single(value) => {
+                let value = value.convert();
+                [value.clone(), value.clone(), value]
+            }
+            VectorJson::Vector(values) => values.map(ValueJson::convert),
+        }
+    }
+}
+
+impl ValueJson {
+    fn convert(self) -> Value {
+        match self {
+            ValueJson::Number(n) => Value::Number(n),
+            // Blockbench writes plain numbers as strings sometimes.
+            ValueJson::String(s) => match s.trim().parse::<f32>() {
+                Ok(n) => Value::Number(n),
+                Err(_) => Value::Expression(s),
+            },
+        }
+    }
+}
+
+#[test]
+fn parse_critter_animations() {
+    let animations = parse(include_str!("../../assets/animations/critter.animation.json")).unwrap();
+    let names: Vec<&str> = animations.iter().map(|animation| animation.name.as_str()).collect();
+    assert_eq!(names, ["animation.critter.idle", "animation.critter.walk"]);
+
+    let walk = &animations[1];
+    assert_eq!(walk.looping, Looping::Loop);
+    assert_eq!(walk.length, 0.6);
+    assert_eq!(walk.expressions().collect::<Vec<_>>(), ["math.sin(query.anim_time * 20) * 4"]);
+
+    // Linear between keyframes and wraps around.
+    let no_eval = &mut |source: &str| panic!("{}", source);
+    let leg = walk.bones.iter().find(|bone| bone.bone == "leg_front_left").unwrap();
+    assert!(leg.rotation.sample(0.15, no_eval).unwrap().length() < 0.0001);
+    assert_eq!(leg.rotation.sample(0.0, no_eval), Some(Vec3::new(35.0, 0.0, 0.0)));
+    assert_eq!(walk.time(0.75), Some(0.75 % 0.6));
+    assert_eq!(leg.position.sample(0.1, no_eval), None);
+
+    // Catmull-rom goes through every keyframe.
+    let body = walk.bones.iter().find(|bone| bone.bone == "body").unwrap();
+    assert_eq!(body.position.keyframes[1].lerp, Lerp::CatmullRom);
+    for key in &body.position.keyframes {
+        let y = body.position.sample(key.time, no_eval).unwrap().y;
+        assert!((y - if key.time == 0.15 || key.time == 0.45 { 0.4 } else { 0.0 }).abs() < 0.0001);
+    }
+    // And is smooth instead of a straight line in between.
+    let quarter = body.position.sample(0.0375, no_eval).unwrap().y;
+    assert!(quarter > 0.0 && quarter != 0.1, "{}", quarter);
+
+    // Expressions get the anim time.
+    let head = walk.bones.iter().find(|bone| bone.bone == "head").unwrap();
+    let mut calls = vec![];
+    let rotation = head.rotation.sample(0.3, &mut |source| { calls.push(source.to_string()); 2.0 }).unwrap();
+    assert_eq!(rotation, Vec3::new(2.0, 0.0, 0.0));
+    assert_eq!(calls, ["math.sin(query.anim_time * 20) * 4"]);
+
+    let idle = &animations[0];
+    let ear = idle.bones.iter().find(|bone| bone.bone == "ear").unwrap();
+    assert_eq!(ear.rotation.keyframes.iter().map(|key| key.time).collect::<Vec<_>>(), [0.0, 3.5, 3.7, 4.0]);
+}
+
+#[test]
+fn animation_pose() {
+    let geometry = crate::geo::parse(include_str!("../../assets/models/critter.geo.json")).unwrap().remove(0);
+    let walk = parse(include_str!("../../assets/animations/critter.animation.json")).unwrap().remove(1);
+    let leg = geometry.bone("leg_front_left").unwrap();
+    let body = geometry.bone("body").unwrap();
+
+    let rest = geometry.rest_pose();
+    let pose = walk.pose(&geometry, 0.0, &mut |_, _| 0.0);
+    assert_eq!(pose.len(), geometry.bones.len());
+
+    // The leg swings around its pivot so the pivot stays still but the foot moves.
+    let pivot = geometry.bones[leg].pivot;
+    assert!(pose[leg].transform_point3(pivot).distance(rest[leg].transform_point3(pivot)) < 0.0001);
+    let foot = Vec3::new(pivot.x, 0.0, pivot.z);
+    assert!(pose[leg].transform_point3(foot).distance(rest[leg].transform_point3(foot)) > 0.05);
+
+    // Bob is in pixels.
+    let bob = walk.pose(&geometry, 0.15, &mut |_, _| 0.0);
+    assert!((bob[body].transform_point3(Vec3::ZERO).y - 0.4 / 16.0).abs() < 0.0001);
+
+    // Finished non-looping animations go back to the rest pose.
+    let once = Animation { looping: Looping::Once, ..walk };
+    assert_eq!(once.time(1.0), None);
+    assert_eq!(once.pose(&geometry, 1.0, &mut |_, _| 0.0), rest);
+}
@@ -1,5 +1,6 @@
 use std::env;
 use image::{DynamicImage, RgbaImage};
+use image::imageops::FilterType;
 
 #[derive(Copy, Clone, Default, Debug)]
 pub struct Uv {
@@ -8,6 +9,10 @@ pub struct Uv {
     pub size: f32
 }
 
+/// Index of a Uv in the generated table (gen::uvs::ALL). Chunk vertices store this instead of the coordinates.
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub struct UvIndex(pub u16);
+
 pub struct AtlasBuilder {
     texture: Vec<u8>,
     full_width: usize,
@@ -31,12 +36,10 @@ impl AtlasBuilder {
     }
 
     pub fn load_file(&mut self, name: &str) -> Uv {
-        let bytes = load_binary(name, "assets");  // TODO: reuse allocation
-        let img = image::load_from_memory(&bytes).expect("Failed to decode image.");
-        self.load(&img)
+        self.load(&load_image(name))
     }
 
-    fn load(&mut self, img: &DynamicImage) -> Uv {
+    pub fn load(&mut self, img: &DynamicImage) -> Uv {
         let rgba = img.to_rgba8();
         let raw = rgba.as_raw();
         let img_width = rgba.width() as usize;
@@ -74,6 +77,11 @@ impl AtlasBuilder {
         uv
     }
 
+    /// Every frame of an animated texture (see split_frames), next to each other.
+    pub fn load_frames(&mut self, img: &DynamicImage) -> Vec<Uv> {
+        split_frames(img).iter().map(|frame| self.load(frame)).collect()
+    }
+
     pub fn save(&self, path: &str) {
         self.as_image().save(path).unwrap();
     }
@@ -85,6 +93,89 @@ impl AtlasBuilder {
     }
 }
 
+/// Every texture gets its own layer of a texture array so mipmaps can't bleed into neighbours like they would in an atlas.
+/// Layer zero is blank to match the placeholder Uv so uv indexes can be used as layer indexes.
+pub struct TextureArrayBuilder {
+    size: usize,
+    layers: Vec<Vec<u8>>,
+}
+
+impl TextureArrayBuilder {
+    pub fn new(size: usize) -> Self {
+        TextureArrayBuilder {
+            size,
+            layers: vec![vec![0; 4 * size * size]],
+        }
+    }
+
+    /// Images that aren't the right size get scaled (nearest neighbour) since all layers must match.
+    pub fn load(&mut self, img: &DynamicImage) -> usize {
+        let size = self.size as u32;
+        let rgba = if img.width() == size && img.height() == size {
+            img.to_rgba8()
+        } else {
+            img.resize_exact(size, size, FilterType::Nearest).to_rgba8()
+        };
+        self.layers.push(rgba.into_raw());
+        self.layers.len() - 1
+    }
+
+    /// Each frame of an animated texture (see split_frames) is a layer. Returns the first.
+    pub fn load_frames(&mut self, img: &DynamicImage) -> usize {
+        let layers: Vec<usize> = split_frames(img).iter().map(|frame| self.load(frame)).collect();
+        layers[0]
+    }
+
+    /// Saved as one tall image with the layers stacked vertically.
+    pub fn save(&self, path: &str) {
+        self.as_image().save(path).unwrap();
+    }
+
+    pub fn as_image(&self) -> DynamicImage {
+        let img = RgbaImage::from_vec(self.size as u32, (self.size * self.layers.len()) as u32, self.layers.concat()).unwrap();
+        DynamicImage::from(img)
+    }
+}
+
+/// Levels after the full size one, each half as big as the last (box filter). Stops at 1x1.
+/// Colours are weighted by alpha so transparent pixels don't darken the edges of cutout textures.
+pub fn mip_chain(rgba: &[u8], size: usize) -> Vec<Vec<u8>> {
+    assert!(size.is_power_of_two(), "Can only mipmap power of two textures.");
+    assert_eq!(rgba.len(), size * size * 4);
+    let mut levels: Vec<Vec<u8>> = vec![];
+    let mut prev = rgba;
+    let mut prev_size = size;
+    while prev_size > 1 {
+        let next_size = prev_size / 2;
+        let mut next = vec![0u8; next_size * next_size * 4];
+        for y in 0..next_size {
+            for x in 0..next_size {
+                let mut sum = [0u32; 4];
+                for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let i = (((y * 2 + dy) * prev_size) + (x * 2 + dx)) * 4;
+                    let alpha = prev[i + 3] as u32;
+                    for c in 0..3 {
+                        sum[c] += prev[i + c] as u32 * alpha;
+                    }
+                    sum[3] += alpha;
+                }
+                let i = ((y * next_size) + x) * 4;
+                // Fully transparent pixels stay black.
+                for c in 0..3 {
+                    if let Some(average) = sum[c].checked_div(sum[3]) {
+                        next[i + c] = average as u8;
+                    }
+                }
+                next[i + 3] = (sum[3] / 4) as u8;
+            }
+        }
+        levels.push(next);
+        prev = levels.last().unwrap();
+        prev_size = next_size;
+    }
+    levels
+}
+
 impl Uv {
     pub fn top_left(&self) -> [f32; 2] {
         [self.x, self.y]
@@ -103,6 +194,18 @@ impl Uv {
     }
 }
 
+/// Animated textures are a vertical strip of square frames (like Minecraft's water), top first.
+pub fn split_frames(img: &DynamicImage) -> Vec<DynamicImage> {
+    let size = img.width();
+    assert_eq!(img.height() % size, 0, "Animated textures must be a vertical strip of square frames.");
+    (0..img.height() / size).map(|i| img.crop_imm(0, i * size, size, size)).collect()
+}
+
+pub fn load_image(name: &str) -> DynamicImage {
+    let bytes = load_binary(name, "assets");  // TODO: reuse allocation
+    image::load_from_memory(&bytes).expect("Failed to decode image.")
+}
+
 pub fn load_binary(file_name: &str, dir: &str) -> Vec<u8> {
     let path = std::path::Path::new(dir)
         .join(file_name);
@@ -116,3 +219,41 @@ pub fn load_binary(file_name: &str, dir: &str) -> Vec<u8> {
         }
     }
 }
+
+#[test]
+fn mipmaps() {
+    // Half opaque red, half transparent. The transparent pixels shouldn't make it darker.
+    let mut rgba = vec![0u8; 4 * 4 * 4];
+    for i in 0..8 {
+        rgba[i * 4..(i + 1) * 4].copy_from_slice(&[255, 0, 0, 255]);
+    }
+    let levels = mip_chain(&rgba, 4);
+    assert_eq!(levels.len(), 2);
+    assert_eq!(levels[0], vec![255, 0, 0, 255, 255, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(levels[1], vec![255, 0, 0, 127]);
+}
+
+#[test]
+fn animation_frames() {
+    let mut strip = RgbaImage::new(2, 6);
+    for frame in 0..3 {
+        for pixel in 0..4 {
+            strip.put_pixel(pixel % 2, frame * 2 + pixel / 2, image::Rgba([frame as u8, 0, 0, 255]));
+        }
+    }
+    let strip = DynamicImage::from(strip);
+    let frames = split_frames(&strip);
+    assert_eq!(frames.len(), 3);
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!((frame.width(), frame.height()), (2, 2));
+        assert!(frame.to_rgba8().pixels().all(|p| p.0 == [i as u8, 0, 0, 255]));
+    }
+
+    // Frames end up side by side in the atlas and as consecutive layers in the array.
+    let mut atlas = AtlasBuilder::new(8, 8);
+    let uvs = atlas.load_frames(&strip);
+    assert_eq!(uvs.iter().map(|uv| uv.x * 8.0).collect::<Vec<_>>(), [0.0, 3.0, 6.0]);
+    let mut array = TextureArrayBuilder::new(2);
+    assert_eq!(array.load_frames(&strip), 1);
+    assert_eq!(array.layers.len(), 4);
+}
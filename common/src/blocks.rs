@@ -1,22 +1,58 @@
 use std::collections::HashMap;
-use crate::atlas::{AtlasBuilder, Uv};
+use image::DynamicImage;
+use crate::atlas::{AtlasBuilder, load_image, TextureArrayBuilder, Uv};
 use std::fmt::Write;
 use std::fs;
 use std::process::Command;
 use crate::pos::Tile;
+use crate::anim;
+use crate::input::Action;
+
+/// Which pipeline draws a tile. Chunks get a separate mesh for each layer.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RenderLayer {
+    /// No blending and hides the faces of neighbouring tiles.
+    Opaque = 0,
+    /// Pixels are either fully drawn or discarded by an alpha test (like leaves).
+    Cutout = 1,
+    /// Alpha blended and drawn last, back to front (like water or glass).
+    Translucent = 2,
+}
+
+impl RenderLayer {
+    pub const COUNT: usize = 3;
+    pub const ALL: [RenderLayer; Self::COUNT] = [RenderLayer::Opaque, RenderLayer::Cutout, RenderLayer::Translucent];
+}
 
 struct BlockInit {
     atlas: AtlasBuilder,
+    array: TextureArrayBuilder,
     uv_mod: String,
     tiles_mod: String,
-    all_uvs: Vec<String>,
+    all_uvs: Vec<Uv>,
     atlas_data: String,
     renderers: Vec<String>,
     uv_cache: HashMap<String, (Uv, usize)>,
     solid_tile_count: usize,
     custom_tile_count: usize,
+    solid_layers: String,
+    custom_layers: String,
+    /// Uv index and state of each custom tile for its renderer.
+    custom_uvs: String,
+    custom_states: String,
     tests: String,
     lua_tiles: String,
+    models: String,
+    model_names: Vec<String>,
+    animations: String,
+    animation_names: Vec<String>,
+    /// (first uv index, frame count, seconds per frame) of animated textures.
+    animated_uvs: Vec<(usize, usize, f32)>,
+    /// Uv indexes of textures that get multiplied by the biome colour.
+    tinted_uvs: Vec<usize>,
+    /// Lua sources from every animation file. Numbered by their index here so rust can ask lua to run one.
+    expressions: Vec<String>,
 }
 
 pub fn gen(out_dir: &str) -> String {
@@ -27,41 +63,81 @@ pub fn gen(out_dir: &str) -> String {
     Command::new("rustfmt").arg(&gen_path).status().unwrap();
     fs::write(format!("{}/gen.lua", out_dir), blocks.lua()).unwrap();
     blocks.atlas.save(&format!("{}/atlas.png", out_dir));
+    blocks.array.save(&format!("{}/texture_array.png", out_dir));
     blocks.lua()  // TODO: temp hack for build script. wither return more info or nothing
 }
 
+/// What gen() saves as atlas.png and texture_array.png, built again from the files in assets/ so a running game can
+/// pick up changes. Panics if a file is broken, same as the build script.
+pub struct Textures {
+    pub atlas: DynamicImage,
+    pub array: DynamicImage,
+    /// Same order as gen::uvs::ALL. Only matches it if no textures were added or removed since the build.
+    pub uvs: Vec<Uv>,
+}
+
+pub fn textures() -> Textures {
+    let mut blocks = BlockInit::new();
+    blocks.build();
+    Textures {
+        atlas: blocks.atlas.as_image(),
+        array: blocks.array.as_image(),
+        uvs: blocks.all_uvs,
+    }
+}
+
 impl BlockInit {
     fn new() -> Self {
         Self {
             atlas: AtlasBuilder::new(16 * 8, 16 * 8),
+            array: TextureArrayBuilder::new(16),
             uv_mod: String::new(),
             tiles_mod: "".to_string(),
-            all_uvs: vec!["Uv { x: 0.0, y: 0.0, size: 0.0 }".to_string()],
+            all_uvs: vec![Uv::default()],
             atlas_data: "".to_string(),
             renderers: vec![],
             uv_cache: Default::default(),
             // Index zero reserved for air.
             solid_tile_count: 1,
             custom_tile_count: 1,
+            solid_layers: "".to_string(),
+            custom_layers: "".to_string(),
+            custom_uvs: "".to_string(),
+            custom_states: "".to_string(),
             tests: "".to_string(),
-            lua_tiles: "".to_string()
+            lua_tiles: "".to_string(),
+            models: "".to_string(),
+            model_names: vec![],
+            animations: "".to_string(),
+            animation_names: vec![],
+            animated_uvs: vec![],
+            tinted_uvs: vec![],
+            expressions: vec![],
         }
     }
 
     // TODO: pull the actual definitions out to its own file that just calls the builder once it gets big
     fn build(&mut self) {
-        self.cube("stone.png");
-        self.cube("dirt.png");
+        use RenderLayer::*;
+        self.cube("stone.png", Opaque);
+        self.cube("dirt.png", Opaque);
         self.grass("grass", "grass.png", "dirt.png", "dirt.png");
-        self.cube("leaf.png");
+        self.cube("leaf.png", Cutout);
         self.pillar("log", "log_top.png", "log_side.png");
 
         self.simple_custom("sapling.png");
-        self.simple_custom("wheat.png");
-        self.simple_custom("wheat1.png");
-        self.simple_custom("wheat2.png");
-        self.simple_custom("wheat3.png");
-        // self.plant(["wheat.png", "wheat1.png"], 5);
+        self.plant(&["wheat1.png", "wheat2.png", "wheat3.png", "wheat.png"]);
+        self.custom("fence", "log_side.png", "fence", 0);
+
+        self.cube("glass.png", Translucent);
+        self.animated_cube("water.png", 0.15, Translucent);
+
+        self.model("falling_block", "stone.png");
+        self.model("critter", "models/critter.png");
+        self.animations("critter");
+
+        self.tinted("grass.png");
+        self.tinted("leaf.png");
     }
 
     fn code(&self) -> String {
@@ -69,26 +145,54 @@ impl BlockInit {
         // This file is @generated by a build script (blocks.rs). Do not edit manually!
 
         pub mod uvs {{
-            use common::atlas::Uv;
+            use common::atlas::{{Uv, UvIndex}};
             pub const ALL: [Uv; {}] = [{}];
             {}
             pub const SOLID_INDEXES: [u8; {}] = [
             0, 0, 0, 0, 0, 0,  // Placeholder
             {}
             ];
+            /// (frame count, seconds per frame) for each uv. The frames of an animated texture have consecutive
+            /// indexes but only the first has this set. Meshes always use the first and the shader picks the frame.
+            pub const FRAMES: [[f32; 2]; {}] = [{}];
+            /// For each uv, whether meshes multiply it by the biome colour (so the texture is what it looks like in plains).
+            pub const TINTED: [bool; {}] = [{}];
         }}
 
         pub mod tiles {{
             use common::pos::Tile;
+            use common::blocks::RenderLayer::{{self, *}};
             pub const SOLID_COUNT: usize = {};
             pub const CUSTOM_COUNT: usize = {};
             pub const empty: Tile = Tile::EMPTY;
             {}
+            pub const SOLID_LAYERS: [RenderLayer; {}] = [Opaque, {}];
+            pub const CUSTOM_LAYERS: [RenderLayer; {}] = [Opaque, {}];
         }}
 
         pub mod render {{
+            use common::atlas::UvIndex;
             use crate::chunk_mesh::renderers::*;
             pub const FUNCS: [CustomRenderFn; {}] = [&air, {}];
+            /// The texture of each custom tile.
+            pub const UVS: [UvIndex; {}] = [UvIndex(0), {}];
+            /// Lets tiles share a renderer (like the growth stage of a plant).
+            pub const STATES: [u8; {}] = [0, {}];
+        }}
+
+        pub mod models {{
+            use common::atlas::Uv;
+            use common::geo::ModelSource;
+            {}
+            pub const ALL: [ModelSource; {}] = [{}];
+        }}
+
+        pub mod animations {{
+            use common::anim::AnimationSource;
+            {}
+            pub const ALL: [AnimationSource; {}] = [{}];
+            /// Index is the argument to eval_expression in entities.lua.
+            pub const EXPRESSIONS: [&str; {}] = [{}];
         }}
 
         #[test]
@@ -96,30 +200,52 @@ impl BlockInit {
         {}
         }}
 
-        "##, self.all_uvs.len(), self.all_uvs.iter().cloned().collect::<String>(), self.uv_mod,
+        "##, self.all_uvs.len(), self.all_uvs.iter().map(|uv| format!("Uv {{ x: {}f32, y: {}f32, size: {}f32 }}, ", uv.x, uv.y, uv.size)).collect::<String>(), self.uv_mod,
                 self.solid_tile_count * 6, self.atlas_data,
+                self.all_uvs.len(), self.frames(),
+                self.all_uvs.len(), (0..self.all_uvs.len()).map(|i| format!("{}, ", self.tinted_uvs.contains(&i))).collect::<String>(),
                 self.solid_tile_count - 1, self.custom_tile_count - 1, self.tiles_mod,
+                self.solid_tile_count, self.solid_layers, self.custom_tile_count, self.custom_layers,
                 self.renderers.len() + 1, self.renderers.iter().map(|s| format!("&{},", s)).collect::<String>(),
+                self.custom_tile_count, self.custom_uvs, self.custom_tile_count, self.custom_states,
+                self.models, self.model_names.len(), self.model_names.join(", "),
+                self.animations, self.animation_names.len(), self.animation_names.join(", "),
+                self.expressions.len(), self.expressions.iter().map(|s| format!("{:?}, ", s)).collect::<String>(),
                 self.tests
         )
     }
 
     fn lua(&self) -> String {
+        let action_names: Vec<String> = Action::ALL.iter().map(|action| action.name().to_string()).collect();
         format!(r##"
         -- This file is @generated by a build script (blocks.rs). Do not edit manually!
 
         --- @type table<string, number>
         local gen = {{
-            tiles = {{ {} }}
+            tiles = {{ {} }},
+            models = {{ {} }},
+            animations = {{ {} }},
+            actions = {{ {} }},
+            expressions = {{ {} }}
         }}
-        "##, self.lua_tiles
+        "##, self.lua_tiles, lua_indexes(&self.model_names), lua_indexes(&self.animation_names), lua_indexes(&action_names), self.expressions.iter().enumerate()
+                .map(|(i, source)| format!("[{}] = function(query, q) return ({}) end,\n", i + 1, source))
+                .collect::<String>()
         )
     }
 
-    fn cube(&mut self, side: &str) {
+    fn cube(&mut self, side: &str, layer: RenderLayer) {
         let uv = self.load_uv(side);
         writeln!(self.atlas_data, "{0}, {0}, {0}, {0}, {0}, {0},   // cube: {1}",uv.1, side).unwrap();
-        self.tile(&side[0..side.len()-4], self.solid_tile_count, true);
+        self.tile(&side[0..side.len()-4], self.solid_tile_count, true, layer);
+        self.solid_tile_count += 1;
+    }
+
+    /// Like cube but the texture is a vertical strip of frames that loops, showing each for `frame_time` seconds.
+    fn animated_cube(&mut self, side: &str, frame_time: f32, layer: RenderLayer) {
+        let uv = self.load_frames(side, frame_time);
+        writeln!(self.atlas_data, "{0}, {0}, {0}, {0}, {0}, {0},   // animated cube: {1}", uv.1, side).unwrap();
+        self.tile(&side[0..side.len()-4], self.solid_tile_count, true, layer);
         self.solid_tile_count += 1;
     }
 
@@ -128,7 +254,7 @@ impl BlockInit {
         let side = self.load_uv(side);
         let bottom= self.load_uv(bottom);
         writeln!(self.atlas_data, "{0}, {1}, {2}, {2}, {2}, {2},  // grass: {3}", top.1, bottom.1, side.1, name).unwrap();
-        self.tile(name, self.solid_tile_count, true);
+        self.tile(name, self.solid_tile_count, true, RenderLayer::Opaque);
         self.solid_tile_count += 1;
     }
 
@@ -136,41 +262,137 @@ impl BlockInit {
         let top= self.load_uv(top);
         let side = self.load_uv(side);
         writeln!(self.atlas_data, "{0}, {0}, {1}, {1}, {1}, {1},  // pillar: {2}",top.1, side.1, name).unwrap();
-        self.tile(name, self.solid_tile_count, true);
+        self.tile(name, self.solid_tile_count, true, RenderLayer::Opaque);
         self.solid_tile_count += 1;
     }
 
-    // This is a little weird cause I don't use the Uv until later. It's just convenient to write it here.
-    fn simple_custom(&mut self, name: &str) {
-        let uv = self.load_uv(name);
-        let name = &name[0..name.len()-4];
-        self.tile(name, self.custom_tile_count, false);
+    /// A tile drawn by a function in chunk_mesh::renderers instead of as a cube. The renderer gets the texture and state
+    /// through its RenderContext.
+    fn custom(&mut self, name: &str, texture: &str, renderer: &str, state: u8) -> (Uv, usize) {
+        let uv = self.load_uv(texture);
+        self.tile(name, self.custom_tile_count, false, RenderLayer::Cutout);
         self.custom_tile_count += 1;
-        self.renderers.push(name.to_string());
-        writeln!(self.tests, "assert!(fn_eq(render::FUNCS[tiles::{0}.index()], &{0}));", name).unwrap();
+        self.renderers.push(renderer.to_string());
+        write!(self.custom_uvs, "UvIndex({}), ", uv.1).unwrap();
+        write!(self.custom_states, "{}, ", state).unwrap();
+        writeln!(self.tests, "assert!(fn_eq(render::FUNCS[tiles::{}.index()], &{}));", name, renderer).unwrap();
+        uv
+    }
+
+    /// Uses the renderer with the same name as the texture.
+    fn simple_custom(&mut self, texture: &str) {
+        let name = &texture[0..texture.len()-4];
+        let uv = self.custom(name, texture, name, 0);
+        self.custom_solid(name, uv);
+    }
 
+    /// One tile for each growth stage (youngest first), all drawn by renderers::plant. The state is the stage.
+    fn plant(&mut self, stages: &[&str]) {
+        for (stage, texture) in stages.iter().enumerate() {
+            let name = &texture[0..texture.len()-4];
+            let uv = self.custom(name, texture, "plant", stage as u8);
+            self.custom_solid(name, uv);
+        }
+    }
+
+    // This is a little weird cause I don't use the Uv until later. It's just convenient to write it here.
+    fn custom_solid(&mut self, name: &str, uv: (Uv, usize)) {
         writeln!(self.atlas_data, "{0}, {0}, {0}, {0}, {0}, {0},  // temp custom solid {1}", uv.1, name).unwrap();
-        self.tile(&format!("{}_solid", name), self.solid_tile_count, true);
+        self.tile(&format!("{}_solid", name), self.solid_tile_count, true, RenderLayer::Cutout);
         self.solid_tile_count += 1;
     }
 
-    fn tile(&mut self, name: &str, index: usize, solid: bool) {
+    /// An entity model from assets/models/{name}.geo.json. Its texture goes in the atlas but not the texture array
+    /// since it's not a block face (and usually isn't 16x16).
+    fn model(&mut self, name: &str, texture: &str) {
+        let path = format!("assets/models/{}.geo.json", name);
+        println!("cargo:rerun-if-changed={}", path);
+        let uv = match self.uv_cache.get(texture) {
+            Some((uv, _)) => *uv,
+            None => {
+                println!("cargo:rerun-if-changed=assets/{}", texture);
+                self.atlas.load(&load_image(texture))
+            }
+        };
+        writeln!(self.models,
+                 "pub const {0}: ModelSource = ModelSource {{ name: \"{0}\", json: include_str!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/{1}\")), texture: Uv {{ x: {2}f32, y: {3}f32, size: {4}f32 }} }};",
+                 name, path, uv.x, uv.y, uv.size
+        ).unwrap();
+        self.model_names.push(name.to_string());
+    }
+
+    /// Keyframe animations from assets/animations/{name}.animation.json. Parsed here so their expressions can go in gen.lua
+    /// (and a broken file fails the build instead of when an entity first uses it).
+    fn animations(&mut self, name: &str) {
+        let path = format!("assets/animations/{}.animation.json", name);
+        println!("cargo:rerun-if-changed={}", path);
+        let json = fs::read_to_string(&path).unwrap();
+        let animations = anim::parse(&json).unwrap_or_else(|e| panic!("Failed to load {}: {}", path, e));
+        for source in animations.iter().flat_map(|animation| animation.expressions()) {
+            if !self.expressions.iter().any(|s| s == source) {
+                self.expressions.push(source.to_string());
+            }
+        }
+        writeln!(self.animations,
+                 "pub const {0}: AnimationSource = AnimationSource {{ name: \"{0}\", json: include_str!(concat!(env!(\"CARGO_MANIFEST_DIR\"), \"/{1}\")) }};",
+                 name, path
+        ).unwrap();
+        self.animation_names.push(name.to_string());
+    }
+
+    fn tile(&mut self, name: &str, index: usize, solid: bool, layer: RenderLayer) {
         writeln!(self.tiles_mod, "pub const {}: Tile = Tile::new({}, {});", name, index, solid).unwrap();
+        let layers = if solid { &mut self.solid_layers } else { &mut self.custom_layers };
+        write!(layers, "{:?}, ", layer).unwrap();
         writeln!(self.lua_tiles, "{} = {},", name, Tile::new(index, solid).0).unwrap();
     }
 
+    /// Every frame gets a uv index but only the first is given a name in gen::uvs (and returned).
+    fn load_frames(&mut self, path: &str, frame_time: f32) -> (Uv, usize) {
+        assert!(path.ends_with(".png"));
+        assert!(!self.uv_cache.contains_key(path), "{} is already loaded without animation.", path);
+        println!("cargo:rerun-if-changed=assets/{}", path);
+        let img = load_image(path);
+        let uvs = self.atlas.load_frames(&img);
+        let index = self.all_uvs.len();
+        let layer = self.array.load_frames(&img);
+        debug_assert_eq!(index, layer);
+        self.all_uvs.extend_from_slice(&uvs);
+        self.animated_uvs.push((index, uvs.len(), frame_time));
+        self.uv_cache.insert(path.to_string(), (uvs[0], index));
+        writeln!(self.uv_mod, "pub const {}: UvIndex = UvIndex({});", &path[0..path.len()-4], index).unwrap();
+        (uvs[0], index)
+    }
+
+    /// Marks an already loaded texture as taking the biome colour (like grass and leaves).
+    fn tinted(&mut self, path: &str) {
+        let (_, index) = *self.uv_cache.get(path).unwrap_or_else(|| panic!("{} must be loaded before it can be tinted.", path));
+        self.tinted_uvs.push(index);
+    }
+
+    fn frames(&self) -> String {
+        let mut frames = vec![[1.0, 0.0]; self.all_uvs.len()];
+        for &(index, count, frame_time) in &self.animated_uvs {
+            frames[index] = [count as f32, frame_time];
+        }
+        frames.iter().map(|[count, time]| format!("[{:?}, {:?}], ", count, time)).collect()
+    }
+
     fn load_uv(&mut self, path: &str) -> (Uv, usize) {
         assert!(path.ends_with(".png"));
         match self.uv_cache.get(path) {
             None => {
                 println!("cargo:rerun-if-changed=assets/{}", path);
-                let uv = self.atlas.load_file(path);
-                let index = self.uv_cache.len() + 1;
+                let img = load_image(path);
+                let uv = self.atlas.load(&img);
+                let index = self.all_uvs.len();
+                let layer = self.array.load(&img);
+                debug_assert_eq!(index, layer);
                 let name = &path[0..path.len()-4];
-                self.all_uvs.push(format!(", Uv {{ x: {}f32, y: {}f32, size: {}f32 }}", uv.x, uv.y, uv.size));
+                self.all_uvs.push(uv);
                 self.uv_cache.insert(path.to_string(), (uv, index));
                 writeln!(self.uv_mod,
-                         "pub const {}: &'static Uv = &ALL[{}];",
+                         "pub const {}: UvIndex = UvIndex({});",
                          name, index
                 ).unwrap();
                 (uv, index)
@@ -179,3 +401,8 @@ impl BlockInit {
         }
     }
 }
+
+/// Names to their index in the matching gen.rs ALL array, so lua can tell rust which one it means.
+fn lua_indexes(names: &[String]) -> String {
+    names.iter().enumerate().map(|(i, name)| format!("{} = {},\n", name, i)).collect()
+}
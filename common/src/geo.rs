@@ -0,0 +1,517 @@
+//! Entity models in the Bedrock/geckolib geometry format (`.geo.json`, what Blockbench exports).
+//! Only format_version 1.12.0 and later. The older object style ("geometry.name": {...}) isn't supported.
+//! https://learn.microsoft.com/en-us/minecraft/creator/reference/content/schemasreference/schemas/minecraftschema_geometry_1.12.0
+
+use std::collections::HashMap;
+use std::fmt;
+use glam::{BVec3, EulerRot, Mat4, Vec2, Vec3};
+use serde::Deserialize;
+use crate::atlas::Uv;
+
+/// One model from a file.
+/// Positions are converted from pixels to blocks. Bedrock's x axis points the other way so x is flipped
+/// on load (like geckolib does), which means rotations around x and y change sign too.
+#[derive(Clone, Debug)]
+pub struct Geometry {
+    pub identifier: String,
+    /// The size uvs are measured against, in pixels.
+    pub texture_size: Vec2,
+    /// Parents always come before their children.
+    pub bones: Vec<Bone>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Bone {
+    pub name: String,
+    /// Index into Geometry::bones.
+    pub parent: Option<usize>,
+    pub pivot: Vec3,
+    /// Degrees, applied z then y then x.
+    pub rotation: Vec3,
+    pub cubes: Vec<Cube>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cube {
+    /// The corner with the smallest coordinates (before inflating).
+    pub origin: Vec3,
+    pub size: Vec3,
+    pub pivot: Vec3,
+    /// Degrees, same order as Bone::rotation.
+    pub rotation: Vec3,
+    /// Grows the cube on every side without changing the uvs.
+    pub inflate: f32,
+    /// In the same order as Face::ALL. None means that side isn't drawn.
+    pub faces: [Option<FaceUv>; 6],
+}
+
+/// A rectangle of the model's texture in pixels. A negative size flips it.
+#[derive(Copy, Clone, Debug, PartialEq, Deserialize)]
+pub struct FaceUv {
+    pub uv: [f32; 2],
+    pub uv_size: [f32; 2],
+}
+
+/// Sides of a cube with Bedrock's names. After the x flip, east is -x and west is +x.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Face {
+    North = 0,
+    South = 1,
+    East = 2,
+    West = 3,
+    Up = 4,
+    Down = 5,
+}
+
+impl Face {
+    pub const ALL: [Face; 6] = [Face::North, Face::South, Face::East, Face::West, Face::Up, Face::Down];
+
+    pub fn normal(self) -> Vec3 {
+        match self {
+            Face::North => Vec3::NEG_Z,
+            Face::South => Vec3::Z,
+            Face::East => Vec3::NEG_X,
+            Face::West => Vec3::X,
+            Face::Up => Vec3::Y,
+            Face::Down => Vec3::NEG_Y,
+        }
+    }
+
+    /// Which of the cube's corners (as min=false/max=true on each axis) are the top left, top right, bottom left
+    /// and bottom right of the texture when looking at that side from outside.
+    fn corners(self) -> [[bool; 3]; 4] {
+        const F: bool = false;
+        const T: bool = true;
+        match self {
+            Face::North => [[T, T, F], [F, T, F], [T, F, F], [F, F, F]],
+            Face::South => [[F, T, T], [T, T, T], [F, F, T], [T, F, T]],
+            Face::East => [[F, T, F], [F, T, T], [F, F, F], [F, F, T]],
+            Face::West => [[T, T, T], [T, T, F], [T, F, T], [T, F, F]],
+            Face::Up => [[F, T, F], [T, T, F], [F, T, T], [T, T, T]],
+            Face::Down => [[F, F, T], [T, F, T], [F, F, F], [T, F, F]],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GeoError {
+    Json(serde_json::Error),
+    UnknownParent { bone: String, parent: String },
+    /// Bones that are (maybe indirectly) their own parent.
+    ParentLoop(Vec<String>),
+}
+
+impl fmt::Display for GeoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoError::Json(e) => write!(f, "{}", e),
+            GeoError::UnknownParent { bone, parent } => write!(f, "Bone {} has parent {} which doesn't exist.", bone, parent),
+            GeoError::ParentLoop(bones) => write!(f, "Bones {:?} are their own parent.", bones),
+        }
+    }
+}
+
+/// A model the build script found in assets/models (see gen::models).
+#[derive(Copy, Clone, Debug)]
+pub struct ModelSource {
+    pub name: &'static str,
+    pub json: &'static str,
+    /// Where its texture ended up in the atlas.
+    pub texture: Uv,
+}
+
+impl ModelSource {
+    /// The first geometry in the file. These are checked by a test so it's fine to panic.
+    pub fn load(&self) -> Geometry {
+        match parse(self.json) {
+            Ok(mut models) if !models.is_empty() => models.swap_remove(0),
+            Ok(_) => panic!("{}.geo.json has no geometry.", self.name),
+            Err(e) => panic!("Failed to load {}.geo.json: {}", self.name, e),
+        }
+    }
+}
+
+/// Every geometry in a file.
+pub fn parse(json: &str) -> Result<Vec<Geometry>, GeoError> {
+    let file: FileJson = serde_json::from_str(json).map_err(GeoError::Json)?;
+    file.geometry.into_iter().map(Geometry::from_json).collect()
+}
+
+impl Geometry {
+    fn from_json(json: GeometryJson) -> Result<Geometry, GeoError> {
+        // Files usually list parents first but don't have to.
+        let mut remaining = json.bones;
+        let mut bones: Vec<Bone> = vec![];
+        let mut indexes: HashMap<String, usize> = HashMap::new();
+        while !remaining.is_empty() {
+            let before = remaining.len();
+            let mut i = 0;
+            while i < remaining.len() {
+                let parent = match &remaining[i].parent {
+                    None => None,
+                    Some(name) => match indexes.get(name) {
+                        Some(&index) => Some(index),
+                        None => {
+                            i += 1;
+                            continue;
+                        }
+                    }
+                };
+                let bone = remaining.remove(i);
+                indexes.insert(bone.name.clone(), bones.len());
+                bones.push(bone.convert(parent));
+            }
+
+            if remaining.len() == before {
+                let all: Vec<&String> = remaining.iter().map(|bone| &bone.name).collect();
+                for bone in &remaining {
+                    let parent = bone.parent.as_ref().unwrap();
+                    if !all.contains(&parent) {
+                        return Err(GeoError::UnknownParent { bone: bone.name.clone(), parent: parent.clone() });
+                    }
+                }
+                return Err(GeoError::ParentLoop(remaining.into_iter().map(|bone| bone.name).collect()));
+            }
+        }
+
+        Ok(Geometry {
+            identifier: json.description.identifier,
+            texture_size: Vec2::new(json.description.texture_width, json.description.texture_height),
+            bones,
+        })
+    }
+
+    pub fn bone(&self, name: &str) -> Option<usize> {
+        self.bones.iter().position(|bone| bone.name == name)
+    }
+
+    /// Model space transform of every bone. `local` gives each bone's transform relative to its parent.
+    pub fn pose(&self, mut local: impl FnMut(usize, &Bone) -> Mat4) -> Vec<Mat4> {
+        let mut result: Vec<Mat4> = Vec::with_capacity(self.bones.len());
+        for (i, bone) in self.bones.iter().enumerate() {
+            let transform = local(i, bone);
+            result.push(match bone.parent {
+                None => transform,
+                Some(parent) => result[parent] * transform,
+            });
+        }
+        result
+    }
+
+    /// How it looks with no animation.
+    pub fn rest_pose(&self) -> Vec<Mat4> {
+        self.pose(|_, bone| bone.transform(Vec3::ZERO, Vec3::ZERO, Vec3::ONE))
+    }
+
+    /// Every cube as it would be in the rest pose, without the bone transforms applied.
+    /// Each vertex remembers its bone so animations can move it with the matrices from pose() instead of remeshing.
+    /// `texture` is where the model's texture is in the atlas.
+    pub fn mesh(&self, texture: Uv) -> GeoMesh {
+        let mut mesh = GeoMesh::default();
+        let texel = texture.size / self.texture_size;
+        let atlas_uv = |pixels: Vec2| Vec2::new(texture.x, texture.y) + pixels * texel;
+
+        for (bone_index, bone) in self.bones.iter().enumerate() {
+            for cube in &bone.cubes {
+                let min = cube.origin - cube.inflate;
+                let max = cube.origin + cube.size + cube.inflate;
+                let rotation = Mat4::from_translation(cube.pivot) * rotation_matrix(cube.rotation) * Mat4::from_translation(-cube.pivot);
+
+                for face in Face::ALL {
+                    let Some(uv) = cube.faces[face as usize] else { continue };
+                    let start = Vec2::from(uv.uv);
+                    let size = Vec2::from(uv.uv_size);
+                    let uvs = [start, start + Vec2::new(size.x, 0.0), start + Vec2::new(0.0, size.y), start + size];
+                    let normal = rotation.transform_vector3(face.normal());
+
+                    let first = mesh.vertices.len() as u32;
+                    for (corner, uv) in face.corners().into_iter().zip(uvs) {
+                        let pos = Vec3::select(BVec3::new(corner[0], corner[1], corner[2]), max, min);
+                        mesh.vertices.push(GeoVertex {
+                            pos: rotation.transform_point3(pos),
+                            uv: atlas_uv(uv),
+                            normal,
+                            bone: bone_index as u32,
+                        });
+                    }
+                    // Same order as MeshBuilder::quad.
+                    mesh.indices.extend_from_slice(&[first, first + 1, first + 2, first + 1, first + 3, first + 2]);
+                }
+            }
+        }
+        mesh
+    }
+}
+
+impl Bone {
+    /// Relative to its parent. Animations add to the rotation and position and multiply the scale.
+    pub fn transform(&self, rotation: Vec3, position: Vec3, scale: Vec3) -> Mat4 {
+        Mat4::from_translation(self.pivot + position)
+            * rotation_matrix(self.rotation + rotation)
+            * Mat4::from_scale(scale)
+            * Mat4::from_translation(-self.pivot)
+    }
+}
+
+/// Degrees. Same order as geckolib (z, then y, then x).
+pub fn rotation_matrix(degrees: Vec3) -> Mat4 {
+    let radians = degrees * (std::f32::consts::PI / 180.0);
+    Mat4::from_euler(EulerRot::ZYX, radians.z, radians.y, radians.x)
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct GeoMesh {
+    pub vertices: Vec<GeoVertex>,
+    pub indices: Vec<u32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GeoVertex {
+    /// In blocks, relative to the model's origin (usually the middle of its feet).
+    pub pos: Vec3,
+    /// In the atlas.
+    pub uv: Vec2,
+    pub normal: Vec3,
+    /// Index into Geometry::bones.
+    pub bone: u32,
+}
+
+const PIXELS_PER_BLOCK: f32 = 16.0;
+
+fn flip_x(pixels: [f32; 3]) -> Vec3 {
+    Vec3::new(-pixels[0], pixels[1], pixels[2]) / PIXELS_PER_BLOCK
+}
+
+fn flip_rotation(degrees: [f32; 3]) -> Vec3 {
+    Vec3::new(-degrees[0], -degrees[1], degrees[2])
+}
+
+// The file format. Everything else gets ignored.
+
+#[derive(Deserialize)]
+struct FileJson {
+    #[serde(rename = "minecraft:geometry")]
+    geometry: Vec<GeometryJson>,
+}
+
+#[derive(Deserialize)]
+struct GeometryJson {
+    description: DescriptionJson,
+    #[serde(default)]
+    bones: Vec<BoneJson>,
+}
+
+#[derive(Deserialize)]
+struct DescriptionJson {
+    identifier: String,
+    texture_width: f32,
+    texture_height: f32,
+}
+
+#[derive(Deserialize)]
+struct BoneJson {
+    name: String,
+    parent: Option<String>,
+    #[serde(default)]
+    pivot: [f32; 3],
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default)]
+    cubes: Vec<CubeJson>,
+}
+
+#[derive(Deserialize)]
+struct CubeJson {
+    origin: [f32; 3],
+    size: [f32; 3],
+    /// Defaults to the middle of the cube.
+    pivot: Option<[f32; 3]>,
+    #[serde(default)]
+    rotation: [f32; 3],
+    #[serde(default)]
+    inflate: f32,
+    #[serde(default)]
+    mirror: bool,
+    #[serde(default)]
+    uv: UvJson,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum UvJson {
+    /// The corner of the standard unwrapped box layout.
+    Box([f32; 2]),
+    PerFace(PerFaceJson),
+}
+
+impl Default for UvJson {
+    fn default() -> Self {
+        UvJson::Box([0.0, 0.0])
+    }
+}
+
+#[derive(Deserialize)]
+struct PerFaceJson {
+    north: Option<FaceUv>,
+    south: Option<FaceUv>,
+    east: Option<FaceUv>,
+    west: Option<FaceUv>,
+    up: Option<FaceUv>,
+    down: Option<FaceUv>,
+}
+
+impl BoneJson {
+    fn convert(self, parent: Option<usize>) -> Bone {
+        Bone {
+            name: self.name,
+            parent,
+            pivot: flip_x(self.pivot),
+            rotation: flip_rotation(self.rotation),
+            cubes: self.cubes.into_iter().map(CubeJson::convert).collect(),
+        }
+    }
+}
+
+impl CubeJson {
+    fn convert(self) -> Cube {
+        let [x, y, z] = self.origin;
+        let size = Vec3::from(self.size);
+        let pivot = self.pivot.unwrap_or([x + size.x / 2.0, y + size.y / 2.0, z + size.z / 2.0]);
+        Cube {
+            // The flipped origin is the corner that used to have the largest x.
+            origin: flip_x([x + size.x, y, z]),
+            size: size / PIXELS_PER_BLOCK,
+            pivot: flip_x(pivot),
+            rotation: flip_rotation(self.rotation),
+            inflate: self.inflate / PIXELS_PER_BLOCK,
+            faces: self.uv.faces(size, self.mirror),
+        }
+    }
+}
+
+impl UvJson {
+    fn faces(self, size: Vec3, mirror: bool) -> [Option<FaceUv>; 6] {
+        match self {
+            UvJson::PerFace(faces) => [faces.north, faces.south, faces.east, faces.west, faces.up, faces.down],
+            UvJson::Box([u, v]) => {
+                // Sides in a row under the top and bottom:  [up][down] / [east][north][west][south]
+                let (w, h, d) = (size.x, size.y, size.z);
+                let face = |x: f32, y: f32, width: f32, height: f32| Some(FaceUv { uv: [u + x, v + y], uv_size: [width, height] });
+                let mut faces = [
+                    face(d, d, w, h),
+                    face(d * 2.0 + w, d, w, h),
+                    face(0.0, d, d, h),
+                    face(d + w, d, d, h),
+                    face(d, 0.0, w, d),
+                    face(d + w, 0.0, w, d),
+                ];
+                // Mirrored cubes swap east and west and flip everything sideways.
+                if mirror {
+                    faces.swap(Face::East as usize, Face::West as usize);
+                    for face in faces.iter_mut().flatten() {
+                        face.uv[0] += face.uv_size[0];
+                        face.uv_size[0] = -face.uv_size[0];
+                    }
+                }
+                faces
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_falling_block() {
+    let models = parse(include_str!("../../assets/models/falling_block.geo.json")).unwrap();
+    assert_eq!(models.len(), 1);
+    let model = &models[0];
+    assert_eq!(model.identifier, "geometry.falling_block");
+    assert_eq!(model.texture_size, Vec2::splat(16.0));
+    assert_eq!(model.bones.len(), 1);
+
+    // One block, centred on x/z, standing on the origin.
+    let cube = &model.bones[0].cubes[0];
+    assert_eq!(cube.origin, Vec3::new(-0.5, 0.0, -0.5));
+    assert_eq!(cube.size, Vec3::ONE);
+    let whole = Some(FaceUv { uv: [0.0, 0.0], uv_size: [16.0, 16.0] });
+    assert_eq!(cube.faces, [whole; 6]);
+
+    // The texture fills each face exactly.
+    let texture = Uv { x: 0.25, y: 0.5, size: 0.125 };
+    let mesh = model.mesh(texture);
+    assert_eq!(mesh.vertices.len(), 24);
+    assert_eq!(mesh.indices.len(), 36);
+    for vertex in &mesh.vertices {
+        assert!(vertex.pos.abs().cmple(Vec3::new(0.5, 1.0, 0.5)).all() && vertex.pos.y >= 0.0);
+        assert!([0.25, 0.375].contains(&vertex.uv.x) && [0.5, 0.625].contains(&vertex.uv.y), "{:?}", vertex.uv);
+    }
+}
+
+#[test]
+fn parse_critter() {
+    let model = &parse(include_str!("../../assets/models/critter.geo.json")).unwrap()[0];
+    assert_eq!(model.bones.len(), 7);
+
+    // The ear comes first in the file but gets moved after its parent.
+    let body = model.bone("body").unwrap();
+    let head = model.bone("head").unwrap();
+    let ear = model.bone("ear").unwrap();
+    assert_eq!(model.bones[body].parent, None);
+    assert_eq!(model.bones[head].parent, Some(body));
+    assert_eq!(model.bones[ear].parent, Some(head));
+    for (i, bone) in model.bones.iter().enumerate() {
+        assert!(bone.parent.is_none_or(|parent| parent < i));
+    }
+
+    // Rotations around x and y are flipped along with the x axis.
+    assert_eq!(model.bones[head].rotation, Vec3::new(-10.0, 0.0, 0.0));
+    let ear_cube = &model.bones[ear].cubes[0];
+    assert_eq!(ear_cube.rotation, Vec3::new(0.0, 0.0, -15.0));
+    assert_eq!(ear_cube.pivot, Vec3::new(1.5, 10.0, -6.5) / 16.0);
+    assert_eq!(ear_cube.origin, Vec3::new(1.0, 10.0, -7.0) / 16.0);
+    assert_eq!(ear_cube.faces[Face::Down as usize], None);
+
+    // Box uv for the 6x5x10 body at [0, 0].
+    let body_cube = &model.bones[body].cubes[0];
+    assert_eq!(body_cube.faces[Face::North as usize], Some(FaceUv { uv: [10.0, 10.0], uv_size: [6.0, 5.0] }));
+    assert_eq!(body_cube.faces[Face::South as usize], Some(FaceUv { uv: [26.0, 10.0], uv_size: [6.0, 5.0] }));
+    assert_eq!(body_cube.faces[Face::East as usize], Some(FaceUv { uv: [0.0, 10.0], uv_size: [10.0, 5.0] }));
+    assert_eq!(body_cube.faces[Face::West as usize], Some(FaceUv { uv: [16.0, 10.0], uv_size: [10.0, 5.0] }));
+    assert_eq!(body_cube.faces[Face::Up as usize], Some(FaceUv { uv: [10.0, 0.0], uv_size: [6.0, 10.0] }));
+    assert_eq!(body_cube.faces[Face::Down as usize], Some(FaceUv { uv: [16.0, 0.0], uv_size: [6.0, 10.0] }));
+
+    // Mirrored legs swap east/west and flip horizontally.
+    let left = &model.bones[model.bone("leg_front_left").unwrap()].cubes[0];
+    let right = &model.bones[model.bone("leg_front_right").unwrap()].cubes[0];
+    assert_eq!(right.faces[Face::East as usize], Some(FaceUv { uv: [26.0, 17.0], uv_size: [-2.0, 3.0] }));
+    assert_eq!(left.faces[Face::West as usize], Some(FaceUv { uv: [24.0, 17.0], uv_size: [2.0, 3.0] }));
+
+    // 6 cubes with every face and the ear without its bottom.
+    let mesh = model.mesh(Uv { x: 0.0, y: 0.0, size: 1.0 });
+    assert_eq!(mesh.vertices.len(), (6 * 6 + 5) * 4);
+    assert!(mesh.vertices.iter().all(|vertex| vertex.uv.cmpge(Vec2::ZERO).all() && vertex.uv.cmple(Vec2::ONE).all()));
+    assert!(mesh.vertices.iter().any(|vertex| vertex.bone == ear as u32));
+
+    // Children follow their parent's rotation.
+    let pose = model.rest_pose();
+    let tip = pose[ear].transform_point3(Vec3::new(0.0, 10.0, -7.0) / 16.0);
+    let expected = pose[head].transform_point3(Vec3::new(0.0, 10.0, -7.0) / 16.0);
+    assert!(tip.distance(expected) < 0.0001);
+    assert!(tip.distance(Vec3::new(0.0, 10.0, -7.0) / 16.0) > 0.01);
+}
+
+#[test]
+fn parse_errors() {
+    let missing = r#"{"format_version": "1.12.0", "minecraft:geometry": [{
+        "description": {"identifier": "geometry.test", "texture_width": 16, "texture_height": 16},
+        "bones": [{"name": "a", "parent": "nope"}]
+    }]}"#;
+    assert!(matches!(parse(missing), Err(GeoError::UnknownParent { .. })));
+
+    let looped = r#"{"format_version": "1.12.0", "minecraft:geometry": [{
+        "description": {"identifier": "geometry.test", "texture_width": 16, "texture_height": 16},
+        "bones": [{"name": "a", "parent": "b"}, {"name": "b", "parent": "a"}]
+    }]}"#;
+    assert!(matches!(parse(looped), Err(GeoError::ParentLoop(_))));
+
+    assert!(matches!(parse("{\"format_version\": \"1.8.0\", \"geometry.old\": {}}"), Err(GeoError::Json(_))));
+}
@@ -0,0 +1,82 @@
+/// Something the player can do by pressing a key. The game only looks at actions so keys can be rebound
+/// (see KeyBindings in the main crate). Lua refers to them by the index in gen.actions.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    MoveUp,
+    MoveDown,
+    /// Held to move faster.
+    Sprint,
+    /// Halves the movement speed.
+    SlowDown,
+    /// Doubles the movement speed.
+    SpeedUp,
+    ToggleCursor,
+    ToggleDebugHud,
+    ToggleVsync,
+    Screenshot,
+    CycleCamera,
+    RecordPath,
+    RenderDistanceDown,
+    RenderDistanceUp,
+    LogProfile,
+    Quit,
+}
+
+impl Action {
+    pub const COUNT: usize = 19;
+    pub const ALL: [Action; Self::COUNT] = [
+        Action::MoveForward, Action::MoveBackward, Action::MoveLeft, Action::MoveRight, Action::MoveUp, Action::MoveDown,
+        Action::Sprint, Action::SlowDown, Action::SpeedUp, Action::ToggleCursor, Action::ToggleDebugHud, Action::ToggleVsync,
+        Action::Screenshot, Action::CycleCamera, Action::RecordPath, Action::RenderDistanceDown, Action::RenderDistanceUp,
+        Action::LogProfile, Action::Quit,
+    ];
+
+    /// What it's called in the key bindings file and in lua.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveForward => "move_forward",
+            Action::MoveBackward => "move_backward",
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::Sprint => "sprint",
+            Action::SlowDown => "slow_down",
+            Action::SpeedUp => "speed_up",
+            Action::ToggleCursor => "toggle_cursor",
+            Action::ToggleDebugHud => "toggle_debug_hud",
+            Action::ToggleVsync => "toggle_vsync",
+            Action::Screenshot => "screenshot",
+            Action::CycleCamera => "cycle_camera",
+            Action::RecordPath => "record_path",
+            Action::RenderDistanceDown => "render_distance_down",
+            Action::RenderDistanceUp => "render_distance_up",
+            Action::LogProfile => "log_profile",
+            Action::Quit => "quit",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Action> {
+        Self::ALL.into_iter().find(|action| action.name() == name)
+    }
+
+    pub fn from_index(index: usize) -> Option<Action> {
+        Self::ALL.get(index).copied()
+    }
+}
+
+#[test]
+fn action_names() {
+    for (i, action) in Action::ALL.into_iter().enumerate() {
+        assert_eq!(action as usize, i);
+        assert_eq!(Action::from_name(action.name()), Some(action));
+        assert_eq!(Action::from_index(i), Some(action));
+    }
+    assert_eq!(Action::from_name("fly"), None);
+    assert_eq!(Action::from_index(Action::COUNT), None);
+}
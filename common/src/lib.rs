@@ -1,3 +1,6 @@
 pub mod blocks;
 pub mod pos;
 pub mod atlas;
+pub mod geo;
+pub mod anim;
+pub mod input;
@@ -1,9 +1,25 @@
 local next_id = 1
 
+-- How rust draws each Entity.ty. Indexes into gen.models and gen.animations (-1 for no animations).
+-- With animations, it plays the one ending in .walk while moving and .idle the rest of the time.
+entity_types = {
+    [1] = { model = gen.models.falling_block, animations = -1 },  -- FallingBlock
+    [2] = { model = gen.models.critter, animations = gen.animations.critter },  -- Critter
+}
+
+-- Called on the first tick since rust_state isn't set before that.
+function register_entity_types()
+    for ty, info in pairs(entity_types) do
+        ffi.C.register_entity_type(rust_state, ty, info.model, info.animations)
+    end
+end
+
 Entity = {
     x = 0, y = 0, z = 0,
     world = nil, ty = 0, id = 0,
     vel_x = 0, vel_y = 0, vel_z = 0,
+    -- Added to x and z when telling rust where to draw it. Models are centred but some positions are a block's corner.
+    render_offset = 0,
 
     init = function(self, world, x, y, z, ty)
         self.world = world
@@ -19,12 +35,13 @@ Entity = {
         self.x = self.x + self.vel_x
         self.y = self.y + self.vel_y
         self.z = self.z + self.vel_z
-        ffi.C.render_entity(rust_state, self.id, self.ty, self.x, self.y, self.z)
+        ffi.C.render_entity(rust_state, self.id, self.ty, self.x + self.render_offset, self.y, self.z + self.render_offset)
     end,
 }
 
 FallingBlock = {
     tile = 0,
+    render_offset = 0.5,
     
     init = function(world, x, y, z, tile)
         local self = new(FallingBlock)
@@ -46,3 +63,52 @@ FallingBlock = {
 }
 
 setmetatable(FallingBlock, { __index = Entity })
+
+-- Wanders around on the ground. Rust plays its walk animation while it's moving (see EntityRender).
+Critter = {
+    timer = 0,
+
+    init = function(world, x, y, z)
+        local self = new(Critter)
+        Entity.init(self, world, x, y, z, 2)
+        return self
+    end,
+
+    tick = function(self)
+        local below = self.world:get_block(math.floor(self.x), math.floor(self.y - 0.1), math.floor(self.z))
+        if below == 0 then
+            self.vel_x = 0
+            self.vel_y = -0.25
+            self.vel_z = 0
+        else
+            self.vel_y = 0
+            self.y = math.floor(self.y)
+            self.timer = self.timer - 1
+            if self.timer <= 0 then
+                -- Pick a new direction or stand still for a bit.
+                self.timer = math.random(20, 60)
+                if math.random() < 0.5 then
+                    local angle = math.random() * math.pi * 2
+                    self.vel_x = math.cos(angle) * 0.08
+                    self.vel_z = math.sin(angle) * 0.08
+                else
+                    self.vel_x = 0
+                    self.vel_z = 0
+                end
+            end
+            if self.world:get_block(math.floor(self.x + self.vel_x * 4), math.floor(self.y), math.floor(self.z + self.vel_z * 4)) ~= 0 then
+                self.vel_x = -self.vel_x
+                self.vel_z = -self.vel_z
+            end
+        end
+        Entity.tick(self)
+    end,
+}
+
+setmetatable(Critter, { __index = Entity })
+
+-- Rust calls this to evaluate the lua expressions in animation files (see anim.rs). index is into gen.expressions, from 0.
+function eval_expression(index, anim_time, life_time)
+    local query = { anim_time = anim_time, life_time = life_time }
+    return gen.expressions[index + 1](query, query)
+end
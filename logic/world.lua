@@ -24,8 +24,13 @@ void unload_chunk(void* state, int x, int y, int z);
 void lua_drop(void* ptr);
 Chunk* random_chunk(void* state);
 void gc_chunks(void* state, int x, int y, int z);
+int render_distance(void* state);
+int unload_distance(void* state);
+void take_screenshot(void* state);
 void render_entity(void* state, int id, int ty, float x, float y, float z);
 void forget_entity(void* state, int id);
+void register_entity_type(void* state, int ty, int model, int animations);
+int action_held(void* state, int action);
 ]]
 
 function new<T>(cls: T): T
@@ -57,8 +62,8 @@ end
 local block_random_tick_handlers: { [number]: (World, Chunk, number, number, number) -> () } = {}
 --local gen: { tiles: { [string]: number} } = gen
 
-local load_radius = 5
-local unload_radius = 8
+local load_radius = 5  -- set from ffi.C.render_distance every tick
+local unload_radius = 8  -- set from ffi.C.unload_distance along with load_radius
 local prev_chunk = nil  -- never read through this! it might have been dropped. just compare the pointer. that's also wrong if it decided to reuse but unlikely 
 local ticks_in_chunk = 0
 
@@ -216,12 +221,29 @@ function unload_square(cx, cy, cz, rad)
     end
 end
 
+-- Saves the next frame to a png (only on native).
+function take_screenshot()
+    ffi.C.take_screenshot(rust_state)
+end
+
+-- True while a key bound to the action is down. action is a constant from gen.actions.
+function action_held(action)
+    return ffi.C.action_held(rust_state, action) ~= 0
+end
+
 local extra_time = 0
 local tick_interval_secs = 1/20
 local spawn_x = 0
+local registered_entity_types = false
+local critter_count = 0
+local max_critters = 5
 
 function run_tick(state, player_bx, player_by, player_bz, dt_sec)
     rust_state = state
+    if not registered_entity_types then
+        register_entity_types()
+        registered_entity_types = true
+    end
 
     extra_time = math.min(extra_time + dt_sec, 1)
     if extra_time < tick_interval_secs then
@@ -229,6 +251,13 @@ function run_tick(state, player_bx, player_by, player_bz, dt_sec)
     end
     extra_time = extra_time - tick_interval_secs
 
+    local distance = ffi.C.render_distance(rust_state)
+    if distance ~= load_radius then
+        load_radius = distance
+        unload_radius = ffi.C.unload_distance(rust_state)
+        prev_chunk = nil  -- forces loading every ring at the new radius
+    end
+
     local changed_chunks = load_around_player(player_bx, player_by, player_bz)
     
     -- Each chunk ticks every x so one of n chunks ticks every x/n
@@ -247,6 +276,11 @@ function run_tick(state, player_bx, player_by, player_bz, dt_sec)
         end
     end
 
+    if critter_count < max_critters and math.random() < 0.01 then
+        critter_count = critter_count + 1
+        the_world:add_entity(new(Critter.init(the_world, math.random(-8, 8), 20, math.random(-8, 8))))
+    end
+
     for id,entity in pairs(the_world.entities) do
         entity:tick()
     end
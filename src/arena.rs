@@ -0,0 +1,462 @@
+use std::mem::size_of;
+use std::num::NonZeroU64;
+use std::ops::Range;
+use std::rc::Rc;
+use glam::Mat4;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, BufferUsages, RenderPass};
+use crate::window::{MeshUniform, ModelVertex, ref_to_bytes, slice_to_bytes, WindowContext};
+
+/// Tracks which parts of a buffer are in use. Doesn't know anything about the gpu.
+/// First fit, and neighbouring free ranges get merged when released.
+#[derive(Debug)]
+pub struct FreeList {
+    free: Vec<Range<u32>>,  // Sorted by start and never touching.
+    capacity: u32,
+}
+
+impl FreeList {
+    pub fn new(capacity: u32) -> Self {
+        let mut list = FreeList {
+            free: vec![],
+            capacity: 0,
+        };
+        list.grow(capacity);
+        list
+    }
+
+    pub fn alloc(&mut self, len: u32) -> Option<u32> {
+        debug_assert!(len > 0);
+        let i = self.free.iter().position(|r| r.end - r.start >= len)?;
+        let start = self.free[i].start;
+        self.free[i].start += len;
+        if self.free[i].is_empty() {
+            self.free.remove(i);
+        }
+        Some(start)
+    }
+
+    pub fn release(&mut self, start: u32, len: u32) {
+        let end = start + len;
+        let i = self.free.partition_point(|r| r.start < start);
+        debug_assert!(end <= self.capacity, "release out of bounds");
+        debug_assert!(i == 0 || self.free[i - 1].end <= start, "double free");
+        debug_assert!(i == self.free.len() || self.free[i].start >= end, "double free");
+
+        let joins_prev = i > 0 && self.free[i - 1].end == start;
+        let joins_next = i < self.free.len() && self.free[i].start == end;
+        match (joins_prev, joins_next) {
+            (true, true) => {
+                self.free[i - 1].end = self.free[i].end;
+                self.free.remove(i);
+            }
+            (true, false) => self.free[i - 1].end = end,
+            (false, true) => self.free[i].start = start,
+            (false, false) => self.free.insert(i, start..end),
+        }
+    }
+
+    /// The new space at the end becomes available.
+    pub fn grow(&mut self, new_capacity: u32) {
+        debug_assert!(new_capacity >= self.capacity);
+        let old = self.capacity;
+        self.capacity = new_capacity;
+        if new_capacity > old {
+            self.release(old, new_capacity - old);
+        }
+    }
+
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+
+    pub fn used(&self) -> u32 {
+        self.capacity - self.free.iter().map(|r| r.end - r.start).sum::<u32>()
+    }
+}
+
+/// A gpu buffer divided up by a FreeList. Grows (by copying into a bigger buffer) when it runs out of space.
+struct ArenaBuffer {
+    buffer: Buffer,
+    list: FreeList,
+    stride: u64,
+    label: &'static str,
+    usage: BufferUsages,
+    /// Growing stops doubling at this many elements (but still grows enough to fit).
+    limit: u32,
+    /// The most elements a buffer can hold on this gpu. Never grows past this.
+    max: u32,
+    /// Times the buffer has been replaced with a bigger one.
+    grows: u64,
+}
+
+impl ArenaBuffer {
+    fn new(ctx: &WindowContext, label: &'static str, usage: BufferUsages, stride: u64, capacity: u32) -> Self {
+        let usage = usage | BufferUsages::COPY_DST | BufferUsages::COPY_SRC;
+        ArenaBuffer {
+            buffer: ctx.buffer_empty(label, stride * capacity as u64, usage),
+            list: FreeList::new(capacity),
+            stride,
+            label,
+            usage,
+            limit: Self::device_limit(ctx, stride),
+            max: Self::device_limit(ctx, stride),
+            grows: 0,
+        }
+    }
+
+    /// The most elements a buffer can hold on this gpu.
+    fn device_limit(ctx: &WindowContext, stride: u64) -> u32 {
+        (ctx.device.limits().max_buffer_size / stride).min(u32::MAX as u64) as u32
+    }
+
+    /// Returns true if the buffer had to be replaced (so any bind groups using it are stale).
+    /// None if it would have to be bigger than the gpu allows.
+    fn alloc(&mut self, ctx: &WindowContext, len: u32) -> Option<(u32, bool)> {
+        if let Some(start) = self.list.alloc(len) {
+            return Some((start, false));
+        }
+        self.resize(ctx, grown_capacity(self.list.capacity(), len, self.limit, self.max)?);
+        Some((self.list.alloc(len).unwrap(), true))
+    }
+
+    // Copies everything over so existing meshes don't move.
+    fn resize(&mut self, ctx: &WindowContext, new_capacity: u32) {
+        let buffer = ctx.buffer_empty(self.label, self.stride * new_capacity as u64, self.usage);
+        let mut encoder = ctx.command_encoder("arena_grow");
+        encoder.copy_buffer_to_buffer(&self.buffer, 0, &buffer, 0, self.buffer.size());
+        ctx.queue.submit([encoder.finish()]);
+        self.buffer = buffer;
+        self.list.grow(new_capacity);
+        self.grows += 1;
+    }
+
+    /// Returns true if the buffer had to be replaced.
+    fn reserve(&mut self, ctx: &WindowContext, capacity: u32) -> bool {
+        let capacity = capacity.min(self.limit);
+        if capacity > self.list.capacity() {
+            self.resize(ctx, capacity);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.list.used() as u64 * self.stride
+    }
+
+    fn write(&self, ctx: &WindowContext, start: u32, data: &[u8]) {
+        ctx.queue.write_buffer(&self.buffer, start as u64 * self.stride, data);
+    }
+}
+
+/// Double so the number of copies amortizes, but not past the limit unless that's needed to fit.
+/// Never past max though since the gpu can't make a buffer that big. None if it won't fit under it.
+fn grown_capacity(old: u32, len: u32, limit: u32, max: u32) -> Option<u32> {
+    let needed = old.checked_add(len).filter(|needed| *needed <= max)?;
+    Some(old.saturating_mul(2).min(limit).min(max).max(needed))
+}
+
+/// Meshes get a bit more space than they need so small edits can be written in place instead of moving.
+fn rounded_capacity(len: u32) -> u32 {
+    (len + len / 8).next_multiple_of(MeshArena::ROUNDING)
+}
+
+/// How the arena has been used since it was made (for log_profile).
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ArenaCounters {
+    /// New meshes.
+    pub inits: u64,
+    /// Updates that fit in the space the mesh already had.
+    pub reuses: u64,
+    /// Updates that outgrew their space and moved somewhere bigger.
+    pub resizes: u64,
+    /// Times the vertex or index buffer was replaced with a bigger one.
+    pub grows: u64,
+}
+
+/// Where a mesh lives in the MeshArena. Must be given back with MeshArena::free or the space leaks.
+#[derive(Debug)]
+pub struct ArenaMesh {
+    first_vertex: u32,
+    vertex_capacity: u32,
+    first_index: u32,
+    index_capacity: u32,
+    num_elements: u32,
+    slot: u32,
+}
+
+/// Every chunk mesh shares the same few big buffers instead of each having their own.
+/// Transforms are all in one uniform buffer and each draw picks its slot with a dynamic offset.
+pub struct MeshArena {
+    ctx: Rc<WindowContext>,
+    vertices: ArenaBuffer,
+    indices: ArenaBuffer,
+    info: ArenaBuffer,
+    info_bind_group: BindGroup,
+    pub layout: BindGroupLayout,
+    // WebGL can't draw with a base vertex so the offset gets added to the indices before upload instead.
+    base_vertex: bool,
+    offset_indices: Vec<u32>,
+    inits: u64,
+    reuses: u64,
+    resizes: u64,
+}
+
+impl MeshArena {
+    const START_VERTICES: u32 = 1 << 18;
+    const START_INDICES: u32 = 1 << 19;
+    const START_SLOTS: u32 = 1024;
+    /// Mesh space is handed out in multiples of this many vertices or indices.
+    const ROUNDING: u32 = 64;
+
+    pub fn new(ctx: Rc<WindowContext>) -> Self {
+        let uniform_size = size_of::<MeshUniform>() as u64;
+        let alignment = ctx.device.limits().min_uniform_buffer_offset_alignment as u64;
+        let slot_stride = uniform_size.div_ceil(alignment) * alignment;
+
+        let vertices = ArenaBuffer::new(&ctx, "arena_vertex", BufferUsages::VERTEX, size_of::<ModelVertex>() as u64, Self::START_VERTICES);
+        let indices = ArenaBuffer::new(&ctx, "arena_index", BufferUsages::INDEX, size_of::<u32>() as u64, Self::START_INDICES);
+        let info = ArenaBuffer::new(&ctx, "arena_mesh_info", BufferUsages::UNIFORM, slot_stride, Self::START_SLOTS);
+        let layout = ctx.bind_group_layout_dynamic("mesh_info", wgpu::ShaderStages::VERTEX_FRAGMENT, uniform_size);
+        let info_bind_group = Self::info_bind_group(&ctx, &layout, &info.buffer);
+
+        MeshArena {
+            base_vertex: ctx.downlevel.contains(wgpu::DownlevelFlags::BASE_VERTEX),
+            ctx,
+            vertices,
+            indices,
+            info,
+            info_bind_group,
+            layout,
+            offset_indices: vec![],
+            inits: 0,
+            reuses: 0,
+            resizes: 0,
+        }
+    }
+
+    /// Grows the buffers up front so loading that many doesn't have to copy them over and over.
+    pub fn reserve(&mut self, vertices: u32, indices: u32, slots: u32) {
+        self.vertices.reserve(&self.ctx, vertices);
+        self.indices.reserve(&self.ctx, indices);
+        if self.info.reserve(&self.ctx, slots) {
+            self.info_bind_group = Self::info_bind_group(&self.ctx, &self.layout, &self.info.buffer);
+        }
+    }
+
+    /// The vertex and index buffers stop doubling once they'd be bigger than this between them.
+    /// Two thirds goes to vertices since there's about one and a half indices per vertex and they're smaller.
+    pub fn set_limit(&mut self, bytes: u64) {
+        for (buffer, bytes) in [(&mut self.vertices, bytes * 2 / 3), (&mut self.indices, bytes / 3)] {
+            buffer.limit = ((bytes / buffer.stride).min(u32::MAX as u64) as u32).min(buffer.max);
+        }
+    }
+
+    /// The biggest limit worth setting. Past this one of the buffers would need to be bigger than the gpu allows.
+    pub fn max_bytes(&self) -> u64 {
+        let max = |buffer: &ArenaBuffer| buffer.max as u64 * buffer.stride;
+        (max(&self.vertices) * 3 / 2).min(max(&self.indices) * 3)
+    }
+
+    /// Vertices and indices actually holding meshes (including the rounding).
+    pub fn used_bytes(&self) -> u64 {
+        self.vertices.used_bytes() + self.indices.used_bytes()
+    }
+
+    /// How many bytes a mesh of that size takes up.
+    pub fn mesh_bytes(vertices: usize, indices: usize) -> u64 {
+        let vertices = rounded_capacity(vertices as u32) as u64 * size_of::<ModelVertex>() as u64;
+        let indices = rounded_capacity(indices as u32) as u64 * size_of::<u32>() as u64;
+        vertices + indices
+    }
+
+    pub fn counters(&self) -> ArenaCounters {
+        ArenaCounters {
+            inits: self.inits,
+            reuses: self.reuses,
+            resizes: self.resizes,
+            grows: self.vertices.grows + self.indices.grows,
+        }
+    }
+
+    fn info_bind_group(ctx: &WindowContext, layout: &BindGroupLayout, buffer: &Buffer) -> BindGroup {
+        ctx.bind_group("mesh_info", layout, &[
+            wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                buffer,
+                offset: 0,
+                size: NonZeroU64::new(size_of::<MeshUniform>() as u64),
+            })
+        ])
+    }
+
+    /// None if the buffers are already as big as the gpu allows and there's no space left in them.
+    pub fn alloc(&mut self, vert: &[ModelVertex], indi: &[u32], transform: Mat4) -> Option<ArenaMesh> {
+        let (slot, stale) = self.info.alloc(&self.ctx, 1)?;
+        if stale {
+            self.info_bind_group = Self::info_bind_group(&self.ctx, &self.layout, &self.info.buffer);
+        }
+        let mut mesh = ArenaMesh {
+            first_vertex: 0,
+            vertex_capacity: 0,
+            first_index: 0,
+            index_capacity: 0,
+            num_elements: 0,
+            slot,
+        };
+        if !self.write(&mut mesh, vert, indi, transform) {
+            self.free(mesh);
+            return None;
+        }
+        self.inits += 1;
+        Some(mesh)
+    }
+
+    /// Writes in place if the new data fits in the old space, otherwise moves it somewhere bigger.
+    /// Returns false if there's no room for it (like alloc). The mesh still has to be freed.
+    pub fn update(&mut self, mesh: &mut ArenaMesh, vert: &[ModelVertex], indi: &[u32], transform: Mat4) -> bool {
+        if vert.len() as u32 > mesh.vertex_capacity || indi.len() as u32 > mesh.index_capacity {
+            self.resizes += 1;
+        } else {
+            self.reuses += 1;
+        }
+        self.write(mesh, vert, indi, transform)
+    }
+
+    fn write(&mut self, mesh: &mut ArenaMesh, vert: &[ModelVertex], indi: &[u32], transform: Mat4) -> bool {
+        debug_assert!(!vert.is_empty() && !indi.is_empty());
+        let vert_len = vert.len() as u32;
+        let indi_len = indi.len() as u32;
+
+        if vert_len > mesh.vertex_capacity {
+            if mesh.vertex_capacity > 0 {
+                self.vertices.list.release(mesh.first_vertex, mesh.vertex_capacity);
+                mesh.vertex_capacity = 0;
+            }
+            let capacity = rounded_capacity(vert_len);
+            let Some((start, _)) = self.vertices.alloc(&self.ctx, capacity) else {
+                return false;
+            };
+            mesh.first_vertex = start;
+            mesh.vertex_capacity = capacity;
+        }
+
+        if indi_len > mesh.index_capacity {
+            if mesh.index_capacity > 0 {
+                self.indices.list.release(mesh.first_index, mesh.index_capacity);
+                mesh.index_capacity = 0;
+            }
+            let capacity = rounded_capacity(indi_len);
+            let Some((start, _)) = self.indices.alloc(&self.ctx, capacity) else {
+                return false;
+            };
+            mesh.first_index = start;
+            mesh.index_capacity = capacity;
+        }
+
+        self.vertices.write(&self.ctx, mesh.first_vertex, slice_to_bytes(vert));
+        if self.base_vertex {
+            self.indices.write(&self.ctx, mesh.first_index, slice_to_bytes(indi));
+        } else {
+            self.offset_indices.clear();
+            self.offset_indices.extend(indi.iter().map(|i| i + mesh.first_vertex));
+            self.indices.write(&self.ctx, mesh.first_index, slice_to_bytes(&self.offset_indices));
+        }
+        mesh.num_elements = indi_len;
+        self.set_transform(mesh, transform);
+        true
+    }
+
+    pub fn set_transform(&self, mesh: &ArenaMesh, transform: Mat4) {
+        let transform = MeshUniform {
+            transform: transform.to_cols_array_2d(),
+        };
+        self.info.write(&self.ctx, mesh.slot, ref_to_bytes(&transform));
+    }
+
+    pub fn free(&mut self, mesh: ArenaMesh) {
+        // A failed write can leave them without space.
+        if mesh.vertex_capacity > 0 {
+            self.vertices.list.release(mesh.first_vertex, mesh.vertex_capacity);
+        }
+        if mesh.index_capacity > 0 {
+            self.indices.list.release(mesh.first_index, mesh.index_capacity);
+        }
+        self.info.list.release(mesh.slot, 1);
+    }
+
+    /// Call once before any draw calls in the render pass.
+    pub fn bind<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertices.buffer.slice(..));
+        render_pass.set_index_buffer(self.indices.buffer.slice(..), wgpu::IndexFormat::Uint32);
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut RenderPass<'a>, mesh: &ArenaMesh) {
+        let offset = mesh.slot as u64 * self.info.stride;
+        render_pass.set_bind_group(1, &self.info_bind_group, &[offset as u32]);
+        let indices = mesh.first_index..(mesh.first_index + mesh.num_elements);
+        let base_vertex = if self.base_vertex { mesh.first_vertex as i32 } else { 0 };
+        render_pass.draw_indexed(indices, base_vertex, 0..1);
+    }
+
+    /// (used, capacity) of vertices, indices and transform slots.
+    pub fn usage(&self) -> [(u32, u32); 3] {
+        [&self.vertices, &self.indices, &self.info].map(|b| (b.list.used(), b.list.capacity()))
+    }
+
+    #[cfg(feature = "profiling")]
+    pub fn log_profile(&self) {
+        let mb = |b: &ArenaBuffer| b.buffer.size() / 1024 / 1024;
+        let used = |b: &ArenaBuffer| b.list.used() as u64 * 100 / b.list.capacity() as u64;
+        let counters = self.counters();
+        println!("MeshArena:\n  - vertex MB: {} ({}% used)\n  - index MB: {} ({}% used)\n  - slots: {} ({}% used)\n  - meshes: {} init, {} reuse, {} resize\n  - buffer grows: {}",
+                 mb(&self.vertices), used(&self.vertices), mb(&self.indices), used(&self.indices), self.info.list.capacity(), used(&self.info),
+                 counters.inits, counters.reuses, counters.resizes, counters.grows);
+    }
+}
+
+#[test]
+fn free_list() {
+    let mut list = FreeList::new(10);
+    assert_eq!(list.alloc(4), Some(0));
+    assert_eq!(list.alloc(4), Some(4));
+    assert_eq!(list.alloc(4), None);
+    assert_eq!(list.used(), 8);
+
+    // Freeing both neighbours of a gap merges them back into one range.
+    list.release(0, 4);
+    list.release(4, 4);
+    assert_eq!(list.free, vec![0..10]);
+
+    // First fit takes the earliest hole that's big enough.
+    assert_eq!(list.alloc(3), Some(0));
+    assert_eq!(list.alloc(3), Some(3));
+    list.release(0, 3);
+    assert_eq!(list.alloc(4), Some(6));
+    assert_eq!(list.alloc(2), Some(0));
+
+    list.grow(20);
+    assert_eq!(list.alloc(10), Some(10));
+    assert_eq!(list.used(), 19);
+}
+
+#[test]
+fn arena_sizing() {
+    // Doubles until the limit then only grows by what's needed.
+    assert_eq!(grown_capacity(100, 10, u32::MAX, u32::MAX), Some(200));
+    assert_eq!(grown_capacity(100, 10, 150, u32::MAX), Some(150));
+    assert_eq!(grown_capacity(100, 80, 150, u32::MAX), Some(180));
+    assert_eq!(grown_capacity(100, 10, 50, u32::MAX), Some(110));
+
+    // But never past what the gpu allows, even to fit.
+    assert_eq!(grown_capacity(100, 10, u32::MAX, 150), Some(150));
+    assert_eq!(grown_capacity(100, 80, 150, 160), None);
+    assert_eq!(grown_capacity(100, 80, 150, 180), Some(180));
+    assert_eq!(grown_capacity(u32::MAX - 5, 10, u32::MAX, u32::MAX), None);
+
+    // A little extra then rounded up so a few more faces still fit.
+    assert_eq!(rounded_capacity(1), 64);
+    assert_eq!(rounded_capacity(64), 128);
+    assert_eq!(rounded_capacity(1000), 1152);
+    assert!((1..5000).all(|len| rounded_capacity(len) >= len && rounded_capacity(len).is_multiple_of(MeshArena::ROUNDING)));
+}
@@ -0,0 +1,70 @@
+use common::pos::Tile;
+use crate::gen;
+
+/// What a column of the world is like. Worldgen picks one for every column and the Chunk remembers it
+/// so meshing can colour grass and leaves to match.
+#[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Default, Debug)]
+pub enum Biome {
+    #[default]
+    Plains = 0,
+    Forest = 1,
+    Desert = 2,
+    Tundra = 3,
+}
+
+impl Biome {
+    pub const COUNT: usize = 4;
+    pub const ALL: [Biome; Self::COUNT] = [Biome::Plains, Biome::Forest, Biome::Desert, Biome::Tundra];
+
+    /// Temperature and humidity are from 0 to 1.
+    pub fn pick(temperature: f32, humidity: f32) -> Biome {
+        if temperature < 0.3 {
+            Biome::Tundra
+        } else if temperature > 0.65 && humidity < 0.45 {
+            Biome::Desert
+        } else if humidity > 0.55 {
+            Biome::Forest
+        } else {
+            Biome::Plains
+        }
+    }
+
+    /// The top block of the ground.
+    pub fn surface(self) -> Tile {
+        match self {
+            Biome::Desert => gen::tiles::dirt,
+            _ => gen::tiles::grass,
+        }
+    }
+
+    /// Chance that a column grows a tree.
+    pub fn tree_chance(self) -> f32 {
+        match self {
+            Biome::Plains => 0.005,
+            Biome::Forest => 0.04,
+            Biome::Desert => 0.0,
+            Biome::Tundra => 0.01,
+        }
+    }
+
+    /// Chance that a column without a tree gets a sapling.
+    pub fn plant_chance(self) -> f32 {
+        match self {
+            Biome::Plains => 0.03,
+            Biome::Forest => 0.01,
+            Biome::Desert => 0.0,
+            Biome::Tundra => 0.0,
+        }
+    }
+
+    /// Multiplied with the textures in gen::uvs::TINTED. Those are drawn how they look in plains so it's white.
+    pub fn tint(self) -> [u8; 4] {
+        match self {
+            Biome::Plains => [255, 255, 255, 255],
+            Biome::Forest => [170, 215, 150, 255],
+            Biome::Desert => [240, 210, 130, 255],
+            Biome::Tundra => [180, 215, 205, 255],
+        }
+    }
+}
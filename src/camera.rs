@@ -2,18 +2,41 @@ use std::f32::consts::FRAC_PI_2;
 use instant::Instant;
 use glam::{Mat4, Vec3, Vec4};
 use winit::dpi::{PhysicalPosition, PhysicalSize};
-use winit::event::{DeviceEvent, ElementState, KeyboardInput, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
-use crate::window::{ref_to_bytes, WindowContext};
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, WindowEvent};
+use crate::window::{ref_to_bytes, SKY_COLOUR, WindowContext};
+use common::input::Action;
 
 pub struct CameraHandle {
+    /// The player's eyes. Controllers move this and the world loads around it.
     pub camera: CameraPerspective,
+    /// Where frames are drawn from. Worked out from the camera by the mode every frame (see update_view).
+    pub view: CameraPerspective,
+    pub mode: CameraMode,
+}
+
+/// How the view is placed relative to the player. Switched at runtime with CameraHandle::cycle_mode.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraMode {
+    /// From the player's eyes.
+    FirstPerson,
+    /// Behind the player, pulled in when a block is in the way.
+    ThirdPerson { distance: f32 },
+    /// Circles a point, always looking at it. Turning the player moves around the orbit.
+    Orbit { target: Vec3, distance: f32 },
+    /// First person but the view eases towards where the player is, taking about `smoothing` seconds. For recording demos.
+    Cinematic { smoothing: f32 },
+}
+
+/// The gpu side of the camera. Owned by the renderer and rewritten from a CameraPerspective every frame.
+pub struct CameraBuffer {
+    start: Instant,
     pub camera_buffer: wgpu::Buffer,
     pub camera_bind_group: wgpu::BindGroup,
     pub camera_bind_group_layout: wgpu::BindGroupLayout,
 }
 
 /// A projection that can be used when rendering the world.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CameraPerspective {
     pub pos: Vec3,
     pub yaw: f32,
@@ -22,7 +45,10 @@ pub struct CameraPerspective {
     pub fovy: f32,
     pub znear: f32,
     pub zfar: f32,
-    pub scale: f32
+    pub scale: f32,
+    /// Distance (in blocks) where fog starts and where it completely hides everything.
+    pub fog_start: f32,
+    pub fog_end: f32,
 }
 
 /// Raw form of a CameraPerspective to be sent to the GPU.
@@ -30,14 +56,18 @@ pub struct CameraPerspective {
 #[derive(Copy, Clone, Debug, Default)]
 pub struct RawCamera {
     view_proj: [[f32; 4]; 4],
-    view_pos: [f32; 4]
+    view_pos: [f32; 4],
+    fog: [f32; 4],  // start, end, unused, unused
+    fog_colour: [f32; 4],
+    time: [f32; 4],  // seconds, unused, unused, unused
 }
 
 /// A strategy for moving a CameraPerspective based on user input.
 pub trait CameraController {
     fn process_scroll(&mut self, delta: &MouseScrollDelta);
     fn process_mouse(&mut self, mouse_dx: f64, mouse_dy: f64);
-    fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool;
+    /// Movement actions. Returns true if it used the action.
+    fn process_action(&mut self, action: Action, pressed: bool) -> bool;
     fn set_mouse_pressed(&mut self, pressed: bool);
 
     /// Adjusts the CameraPerspective based on any user input received this frame.
@@ -45,17 +75,6 @@ pub trait CameraController {
 
     fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
         match event {
-            WindowEvent::KeyboardInput {
-                input:
-                KeyboardInput {
-                    virtual_keycode: Some(key),
-                    state,
-                    ..
-                },
-                ..
-            } => {
-                self.process_keyboard(*key, *state);
-            },
             WindowEvent::MouseWheel { delta, .. } => {
                 self.process_scroll(delta);
             }
@@ -77,25 +96,76 @@ pub trait CameraController {
         }
     }
 
-    fn update(&mut self, ctx: &WindowContext, camera: &mut CameraHandle) {
+    fn update(&mut self, camera: &mut CameraHandle) {
         self.update_camera(&mut camera.camera);
-        ctx.write_buffer(&camera.camera_buffer, ref_to_bytes(&camera.camera.as_raw()));
     }
 
     fn resize(&mut self, camera: &mut CameraHandle, new_size: &PhysicalSize<u32>) {
-        camera.camera.resize(new_size.width, new_size.height);
+        camera.resize(new_size.width, new_size.height);
     }
 }
 
 impl CameraHandle {
-    pub fn new(ctx: &WindowContext) -> CameraHandle {
+    pub fn new(size: PhysicalSize<u32>) -> CameraHandle {
+        let mut camera = CameraPerspective::new();
+        camera.resize(size.width, size.height);
+        CameraHandle {
+            view: camera.clone(),
+            camera,
+            mode: CameraMode::FirstPerson,
+        }
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.camera.resize(width, height);
+        self.view.resize(width, height);
+    }
+
+    /// First person, third person, orbit, cinematic, then back to first person.
+    /// Orbit goes around `target` (like the block or entity you're looking at).
+    pub fn cycle_mode(&mut self, target: Vec3) {
+        self.mode = match self.mode {
+            CameraMode::FirstPerson => CameraMode::ThirdPerson { distance: 4.0 },
+            CameraMode::ThirdPerson { .. } => CameraMode::Orbit { target, distance: target.distance(self.camera.pos).max(2.0) },
+            CameraMode::Orbit { .. } => CameraMode::Cinematic { smoothing: 0.3 },
+            CameraMode::Cinematic { .. } => CameraMode::FirstPerson,
+        };
+    }
+
+    /// Moves the view to match the camera. `raycast` says how far you can go from a point in a direction before
+    /// hitting a block (None if there's nothing within the max distance).
+    pub fn update_view(&mut self, dt: f32, raycast: impl Fn(Vec3, Vec3, f32) -> Option<f32>) {
+        let eye = &self.camera;
+        let mut view = eye.clone();
+        match self.mode {
+            CameraMode::FirstPerson => {}
+            CameraMode::ThirdPerson { distance } => {
+                let back = -eye.facing();
+                // Stop a little in front of the block so the near plane doesn't cut into it.
+                let distance = raycast(eye.pos, back, distance).map_or(distance, |hit| (hit - 0.2).max(0.0));
+                view.pos = eye.pos + back * distance;
+            }
+            CameraMode::Orbit { target, distance } => {
+                view.pos = target - eye.facing() * distance;
+            }
+            CameraMode::Cinematic { smoothing } => {
+                // Frame rate independent easing.
+                let t = 1.0 - (-dt / smoothing).exp();
+                view.pos = self.view.pos.lerp(eye.pos, t);
+                view.yaw = self.view.yaw + (eye.yaw - self.view.yaw) * t;
+                view.pitch = self.view.pitch + (eye.pitch - self.view.pitch) * t;
+            }
+        }
+        self.view = view;
+    }
+}
+
+impl CameraBuffer {
+    pub fn new(ctx: &WindowContext, camera: &CameraPerspective) -> CameraBuffer {
         let camera_bind_group_layout = ctx.bind_group_layout_buffer("Camera", &[
             (wgpu::ShaderStages::VERTEX_FRAGMENT, wgpu::BufferBindingType::Uniform)
         ]);
 
-        let mut camera = CameraPerspective::new();
-        camera.resize(ctx.size.borrow().width, ctx.size.borrow().height);
-
         let camera_buffer = ctx.buffer_init(
             "Camera", ref_to_bytes(&camera.as_raw()),
             wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST
@@ -105,20 +175,30 @@ impl CameraHandle {
             camera_buffer.as_entire_binding()
         ]);
 
-        CameraHandle {
-            camera,
+        CameraBuffer {
+            start: Instant::now(),
             camera_buffer,
             camera_bind_group,
             camera_bind_group_layout,
         }
     }
+
+    /// Also updates the time that animated textures use.
+    pub fn write(&self, ctx: &WindowContext, camera: &CameraPerspective) {
+        let mut raw = camera.as_raw();
+        raw.time[0] = (Instant::now() - self.start).as_secs_f32();
+        ctx.write_buffer(&self.camera_buffer, ref_to_bytes(&raw));
+    }
 }
 
 impl CameraPerspective {
     pub(crate) fn as_raw(&self) -> RawCamera {
         RawCamera {
             view_pos: [self.pos.x, self.pos.y, self.pos.z, 1.0],
-            view_proj: self.calc_matrix().to_cols_array_2d()
+            view_proj: self.calc_matrix().to_cols_array_2d(),
+            fog: [self.fog_start, self.fog_end, 0.0, 0.0],
+            fog_colour: [SKY_COLOUR.r as f32, SKY_COLOUR.g as f32, SKY_COLOUR.b as f32, SKY_COLOUR.a as f32],
+            time: [0.0; 4],
         }
     }
 
@@ -132,9 +212,18 @@ impl CameraPerspective {
             znear: 0.1,
             zfar: 100.0,
             scale: 1.0,
+            fog_start: 50.0,
+            fog_end: 80.0,
         }
     }
 
+    /// Fog hides everything past the render distance so you can't see chunks popping in at the edge.
+    pub fn set_render_distance(&mut self, blocks: f32) {
+        self.fog_end = blocks;
+        self.fog_start = blocks * 0.6;
+        self.zfar = blocks * 2.0;  // Corners of the loaded area are further away than the sides.
+    }
+
     pub fn calc_matrix(&self) -> Mat4 {
         let player = Mat4::look_at_rh(
             self.pos,
@@ -182,7 +271,7 @@ pub struct SpectatorCameraController {
     scroll: f32,
     pub speed: f32,
     sensitivity: f32,
-    control_held: bool,
+    sprint_held: bool,
     mouse_pressed: bool,
     pub last_update: Instant,
     pub frozen: bool
@@ -202,7 +291,7 @@ impl SpectatorCameraController {
             scroll: 0.0,
             speed,
             sensitivity,
-            control_held: false,
+            sprint_held: false,
             mouse_pressed: false,
             last_update: Instant::now(),
             frozen: false,
@@ -226,40 +315,40 @@ impl CameraController for SpectatorCameraController {
         self.rotate_vertical = mouse_dy as f32;
     }
 
-    fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+    fn process_action(&mut self, action: Action, pressed: bool) -> bool {
         if self.frozen { return false; }
-        let amount = if state == ElementState::Pressed {
+        let amount = if pressed {
             1.0
         } else {
             0.0
         };
-        match key {
-            VirtualKeyCode::W | VirtualKeyCode::Up => {
+        match action {
+            Action::MoveForward => {
                 self.amount_forward = amount;
                 true
             }
-            VirtualKeyCode::S | VirtualKeyCode::Down => {
+            Action::MoveBackward => {
                 self.amount_backward = amount;
                 true
             }
-            VirtualKeyCode::A | VirtualKeyCode::Left => {
+            Action::MoveLeft => {
                 self.amount_left = amount;
                 true
             }
-            VirtualKeyCode::D | VirtualKeyCode::Right => {
+            Action::MoveRight => {
                 self.amount_right = amount;
                 true
             }
-            VirtualKeyCode::Space => {
+            Action::MoveUp => {
                 self.amount_up = amount;
                 true
             }
-            VirtualKeyCode::LShift => {
+            Action::MoveDown => {
                 self.amount_down = amount;
                 true
             },
-            VirtualKeyCode::LControl => {
-                self.control_held = state == ElementState::Pressed;
+            Action::Sprint => {
+                self.sprint_held = pressed;
                 true
             }
             _ => false,
@@ -276,7 +365,7 @@ impl CameraController for SpectatorCameraController {
         self.last_update = Instant::now();
 
         let dt = dt.as_secs_f32();
-        let move_speed = self.speed * if self.control_held { 5.0 } else { 1.0 };
+        let move_speed = self.speed * if self.sprint_held { 5.0 } else { 1.0 };
 
         // Move forward/backward and left/right
         let (yaw_sin, yaw_cos) = camera.yaw.sin_cos();
@@ -306,3 +395,40 @@ impl CameraController for SpectatorCameraController {
         camera.pitch = camera.pitch.clamp(-SAFE_FRAC_PI_2, SAFE_FRAC_PI_2);
     }
 }
+
+#[test]
+fn camera_modes() {
+    let mut handle = CameraHandle::new(PhysicalSize::new(100, 100));
+    handle.camera.pos = Vec3::ZERO;
+    handle.camera.yaw = 0.0;
+    handle.camera.pitch = 0.0;
+    let nothing = |_, _, _| None;
+
+    handle.update_view(0.1, nothing);
+    assert_eq!(handle.view.pos, Vec3::ZERO);
+
+    // Behind the player, closer if something's in the way.
+    handle.cycle_mode(Vec3::ZERO);
+    handle.update_view(0.1, nothing);
+    assert_eq!(handle.view.pos, Vec3::new(-4.0, 0.0, 0.0));
+    handle.update_view(0.1, |_, _, _| Some(1.2));
+    assert_eq!(handle.view.pos, Vec3::new(-1.0, 0.0, 0.0));
+
+    // Looks at the target from the direction the player is facing.
+    handle.cycle_mode(Vec3::new(0.0, 0.0, 5.0));
+    handle.update_view(0.1, nothing);
+    assert_eq!(handle.view.pos, Vec3::new(-5.0, 0.0, 5.0));
+    assert_eq!(handle.view.facing(), handle.camera.facing());
+
+    // Starts from the previous view and catches up over time.
+    handle.cycle_mode(Vec3::ZERO);
+    handle.update_view(0.1, nothing);
+    assert!(handle.view.pos.x > -5.0 && handle.view.pos.x < 0.0);
+    for _ in 0..100 {
+        handle.update_view(0.1, nothing);
+    }
+    assert!(handle.view.pos.distance(Vec3::ZERO) < 0.01);
+
+    handle.cycle_mode(Vec3::ZERO);
+    assert_eq!(handle.mode, CameraMode::FirstPerson);
+}
@@ -1,242 +1,333 @@
-use std::cell::Cell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
-use glam::{Mat4, Vec3, Vec4};
-use wgpu::{BindGroup, BindGroupLayout, RenderPass};
-use crate::pos::{ChunkPos, Chunk, CHUNK_SIZE, LocalPos, Direction};
+use glam::{Mat4, Vec3};
+use image::DynamicImage;
+use wgpu::{BindGroup, BindGroupLayout, Buffer, RenderPass, TextureViewDimension};
 use common::atlas::Uv;
+use crate::pos::{BlockPos, ChunkPos, Chunk, CHUNK_SIZE, LocalPos, Direction};
+use common::blocks::RenderLayer;
+use common::atlas::UvIndex;
 use common::pos::Tile;
 use crate::gen;
-use crate::window::{Mesh, MeshUniform, ModelVertex, ref_to_bytes, slice_to_bytes, Texture, WindowContext};
+use crate::arena::{ArenaMesh, MeshArena};
+use crate::window::{ModelVertex, slice_to_bytes, Texture, WindowContext};
+
+/// One mesh for each RenderLayer since they're drawn by different pipelines.
+type ChunkMeshes = [Option<ArenaMesh>; RenderLayer::COUNT];
 
 pub struct ChunkList {
-    chunks: HashMap<ChunkPos, Mesh>,
-    layout: BindGroupLayout,
-    ctx: Rc<WindowContext>,
-    pub builder: MeshBuilder,
-
-    // Old meshes not currently in use. When loading a new chunk, check if there's one here,
-    // since I assume it's cheaper to update a buffer than create a new one.
-    pub mesh_pool: Vec<Mesh>,
-    #[cfg(feature = "profiling")]
-    init_count: Cell<usize>,
-    #[cfg(feature = "profiling")]
-    memory: Cell<u64>
+    chunks: HashMap<ChunkPos, ChunkMeshes>,
+    pub arena: MeshArena,
+    /// Chunks further than this (axis distance) from the camera aren't drawn. Lua uses it as the radius to load.
+    pub render_distance: u32,
+    /// How many times a chunk has been meshed in total (for benchmarks).
+    pub meshed: u64,
+    /// Bytes of vertices and indices all the chunk meshes can use. The farthest ones get dropped to stay under it.
+    budget: u64,
+    /// The camera's chunk as of the last frame. Eviction starts with whatever is furthest from here.
+    pub centre: ChunkPos,
+    /// Chunks that lost their mesh to the budget. They get remeshed once they're back in view and there's room.
+    evicted: HashSet<ChunkPos>,
+    evictions: u64,
 }
 
 impl ChunkList {
-    pub fn new(ctx: Rc<WindowContext>, atlas: Rc<TextureAtlas>, layout: BindGroupLayout) -> Self {
-        ChunkList {
+    pub fn new(ctx: Rc<WindowContext>) -> Self {
+        let mut list = ChunkList {
             chunks: Default::default(),
-            layout,
-            ctx,
-            builder: MeshBuilder {
-                atlas,
-                vert: Vec::with_capacity(10000),
-                indi: Vec::with_capacity(10000),
-            },
-            mesh_pool: Vec::with_capacity(Self::MAX_ALLOC_POOL),
-            #[cfg(feature = "profiling")]
-            init_count: Cell::new(0),
-            #[cfg(feature = "profiling")]
-            memory: Cell::new(0),
-        }
+            arena: MeshArena::new(ctx),
+            render_distance: 5,
+            meshed: 0,
+            budget: Self::DEFAULT_BUDGET,
+            centre: ChunkPos::new(0, 0, 0),
+            evicted: Default::default(),
+            evictions: 0,
+        };
+        list.set_budget(Self::DEFAULT_BUDGET);
+        list
     }
 
-    pub fn remove(&mut self, pos: ChunkPos) {
-        let old = self.chunks.remove(&pos);
-        self.recycle(old);
+    pub const DEFAULT_BUDGET: u64 = 512 * 1024 * 1024;
+    /// Lua loads a couple of chunks above and below the surface, so roughly this many have faces in each column.
+    const MESHES_PER_COLUMN: u32 = 2;
+    /// A guess for a chunk at the surface with some hills and trees.
+    const AVERAGE_VERTICES: u32 = 2048;
+
+    /// Reserves enough of the arena for everything in view so loading doesn't keep growing it.
+    pub fn set_render_distance(&mut self, distance: u32) {
+        self.render_distance = distance;
+        let side = distance * 2 + 1;
+        let meshes = side * side * Self::MESHES_PER_COLUMN;
+        let vertices = meshes * Self::AVERAGE_VERTICES;
+        self.arena.reserve(vertices, vertices / 2 * 3, meshes * RenderLayer::COUNT as u32);
     }
 
-    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>, player: ChunkPos) {
-        // TODO: easy culling based on ChunkPos and camera direction.
-        for (pos, mesh) in self.chunks.iter() {
-            if player.axis_distance(pos) > 5 {
-                continue;
-            }
-            mesh.render(render_pass);
+    /// Capped at what the gpu's max buffer size allows.
+    pub fn set_budget(&mut self, bytes: u64) {
+        self.budget = bytes.min(self.arena.max_bytes());
+        self.arena.set_limit(self.budget);
+    }
+
+    /// Chunks that were evicted and are in view again. Waits until there's a bit of room so it doesn't keep
+    /// evicting and remeshing the same ones at the edge.
+    pub fn take_evicted(&mut self) -> Vec<ChunkPos> {
+        if self.arena.used_bytes() > self.budget / 10 * 9 {
+            return vec![];
         }
+        let back: Vec<_> = self.evicted.iter().filter(|pos| self.centre.axis_distance(pos) <= self.render_distance).copied().collect();
+        for pos in &back {
+            self.evicted.remove(pos);
+        }
+        back
     }
 
-    const CHUNK_SCALE: f32 = CHUNK_SIZE as f32;
-    const MAX_ALLOC_POOL: usize = 1000;  // TODO: need to set this dynamically based on render distance.
+    /// Chunks with at least one non-empty layer.
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
 
-    pub fn update_mesh(&mut self, pos: ChunkPos, chunk: &Chunk) {
-        // println!("Meshes: {} + {}", self.chunks.len(), self.mesh_pool.len());
-        let old = if let Some(mesh) = self.build_mesh(pos, chunk) {
-            self.chunks.insert(pos, mesh)
-        } else {
-            self.chunks.remove(&pos)
-        };
-        self.recycle(old);
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
     }
 
-    pub fn recycle(&mut self, mesh: Option<Mesh>) {
-        if let Some(old) = mesh {
-            if self.mesh_pool.len() < Self::MAX_ALLOC_POOL {
-                self.mesh_pool.push(old);
-            } else {
-                print!("full mesh pool");
+    pub fn remove(&mut self, pos: ChunkPos) {
+        self.evicted.remove(&pos);
+        if let Some(old) = self.chunks.remove(&pos) {
+            for mesh in old.into_iter().flatten() {
+                self.arena.free(mesh);
             }
         }
     }
 
-    fn build_mesh(&mut self, pos: ChunkPos, chunk: &Chunk) -> Option<Mesh> {
-        self.builder.clear();
+    /// The pipeline for the layer must already be set.
+    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>, camera: Vec3, layer: RenderLayer) {
+        let player = BlockPos::vec(camera).chunk();
+        self.arena.bind(render_pass);
 
-        // TODO: could use wrapping and stay unsigned since negative becomes really high positive
-        // TODO: you already know in the loop which are the edge so maybe treat those differently and the don't need the branching here.
-        let empty = |x: isize, y: isize, z: isize| {
-            let is = CHUNK_SIZE as isize;
-            if x >= is || y >= is || z >= is || x < 0 || y < 0 || z < 0 {
-                return true;
+        // TODO: easy culling based on ChunkPos and camera direction.
+        let visible = self.chunks.iter().filter_map(|(pos, meshes)| {
+            match &meshes[layer as usize] {
+                Some(mesh) if player.axis_distance(pos) <= self.render_distance => Some((pos, mesh)),
+                _ => None,
             }
-            let pos = LocalPos::new(x as usize, y as usize, z as usize);
-            let tile = chunk.get(pos);
-            !tile.solid()
-        };
+        });
 
-        let mut count = 0;
-        for x in (0..(CHUNK_SIZE as isize))  {
-            for y in 0..(CHUNK_SIZE as isize) {
-                for z in 0..(CHUNK_SIZE as isize)  {
-                    let pos = LocalPos::new(x as usize, y as usize, z as usize);
-                    let tile = chunk.get(pos);
-                    if tile.solid() {
-                        debug_assert!(tile.index() <= gen::tiles::SOLID_COUNT, "Invalid tile {:?} at {:?} {:?}. Damn you lua!", tile, chunk.pos, pos);
-                        // TODO: use DirectionSet?
-                        let top = empty(x, y + 1, z);
-                        let right = empty(x, y, z + 1);
-                        let far = empty(x + 1, y, z);
-                        let bottom = empty(x, y - 1, z);
-                        let left = empty(x, y, z - 1);
-                        let close = empty(x - 1, y, z);
-                        self.builder.add_cube(tile, pos.normalized() * Self::CHUNK_SCALE, top, bottom, left, right, close, far);
-                        count += 1;
-                    } else if tile.custom_render() {
-                        debug_assert!(tile.index() <= gen::tiles::CUSTOM_COUNT, "Invalid tile {:?} at {:?} {:?}. Damn you lua!", tile, chunk.pos, pos);
-                        let func = gen::render::FUNCS[tile.index()];
-                        func(&mut self.builder, pos.normalized() * Self::CHUNK_SCALE);
-                    }
-                }
+        if layer == RenderLayer::Translucent {
+            // Blending only works if the far away things are already drawn.
+            // TODO: this only sorts whole chunks, faces within a chunk can still be in the wrong order.
+            let mut sorted: Vec<_> = visible.map(|(pos, mesh)| (Self::centre(*pos).distance_squared(camera), mesh)).collect();
+            sorted.sort_by(|a, b| b.0.total_cmp(&a.0));
+            for (_, mesh) in sorted {
+                self.arena.draw(render_pass, mesh);
+            }
+        } else {
+            for (_, mesh) in visible {
+                self.arena.draw(render_pass, mesh);
             }
         }
+    }
 
-        if self.builder.indi.is_empty() {
-            return None;
+    /// Layers with no geometry don't get a mesh.
+    pub fn update_mesh(&mut self, pos: ChunkPos, layers: &ChunkGeometry) {
+        self.meshed += 1;
+        self.evicted.remove(&pos);
+        let bytes = layers.iter().map(|geometry| MeshArena::mesh_bytes(geometry.vert.len(), geometry.indi.len())).sum();
+        self.make_room(pos, bytes);
+
+        // The buffers can't grow any more so something further away has to go, or this one does.
+        while !self.write_meshes(pos, layers) {
+            if !self.evict_further(pos) {
+                self.remove(pos);
+                self.evicted.insert(pos);
+                return;
+            }
         }
 
-        // println!("Mesh({}, {}, {}): {} vertices, {} indices, {} cubes.", pos.x, pos.y, pos.z, self.builder.vert.len(), self.builder.indi.len(), count);
+        if self.chunks.get(&pos).is_some_and(|meshes| meshes.iter().all(Option::is_none)) {
+            self.chunks.remove(&pos);
+        }
+    }
 
-        Some(match self.mesh_pool.pop() {
-            None => self.init_mesh(&self.builder.vert, &self.builder.indi, Self::translate(pos)),
-            Some(mut mesh) => {
-                self.reuse_mesh(&mut mesh, &self.builder.vert, &self.builder.indi, Self::translate(pos));
-                mesh
+    // Returns false if one of the layers didn't fit in the arena. That layer is left without a mesh.
+    fn write_meshes(&mut self, pos: ChunkPos, layers: &ChunkGeometry) -> bool {
+        let meshes = self.chunks.entry(pos).or_default();
+        for layer in RenderLayer::ALL {
+            let geometry = &layers[layer as usize];
+            let slot = &mut meshes[layer as usize];
+            let fits = match slot {
+                Some(_) if geometry.indi.is_empty() => {
+                    self.arena.free(slot.take().unwrap());
+                    true
+                }
+                Some(mesh) => self.arena.update(mesh, &geometry.vert, &geometry.indi, Self::translate(pos)),
+                None if geometry.indi.is_empty() => true,
+                None => {
+                    *slot = self.arena.alloc(&geometry.vert, &geometry.indi, Self::translate(pos));
+                    slot.is_some()
+                }
+            };
+            if !fits {
+                if let Some(mesh) = slot.take() {
+                    self.arena.free(mesh);
+                }
+                return false;
             }
-        })
+        }
+        true
     }
 
-    fn translate(pos: ChunkPos) -> Mat4 {
-        let offset = Vec3::new(pos.x as f32 * Self::CHUNK_SCALE, pos.y as f32 * Self::CHUNK_SCALE, pos.z as f32 * Self::CHUNK_SCALE);
-        Mat4::from_translation(offset)
+    // Drops the furthest meshes until the new one fits.
+    fn make_room(&mut self, pos: ChunkPos, bytes: u64) {
+        // Goes over budget rather than leaving a hole near the camera.
+        while self.arena.used_bytes() + bytes > self.budget && self.evict_further(pos) {}
     }
 
-    // This checks that there's enough space in the buffer before writing. Resizing will amortize.
-    pub(crate) fn reuse_mesh(&self, mesh: &mut Mesh, vert: &[ModelVertex], indi: &[u32], transform: Mat4)  {
-        let vert_b = slice_to_bytes(vert);
-        let indi_b = slice_to_bytes(indi);
-
-        if vert_b.len() <= mesh.vertex_buffer.size() as usize {
-            self.ctx.write_buffer(&mesh.vertex_buffer, vert_b);
-        } else {
-            #[cfg(feature = "profiling")]
-            self.memory.set(self.memory.get() - mesh.vertex_buffer.size());
-            mesh.vertex_buffer = self.ctx.buffer_init(
-                "tri", vert_b, wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
-            );
-            #[cfg(feature = "profiling")]
-            self.memory.set(self.memory.get() + mesh.vertex_buffer.size());
-        }
-
-        if indi_b.len() <= mesh.index_buffer.size() as usize {
-            self.ctx.write_buffer(&mesh.index_buffer, indi_b);
-        } else {
-            #[cfg(feature = "profiling")]
-            self.memory.set(self.memory.get() - mesh.index_buffer.size());
-            mesh.index_buffer = self.ctx.buffer_init(
-                "tri", indi_b, wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST
-            );
-            #[cfg(feature = "profiling")]
-            self.memory.set(self.memory.get() + mesh.index_buffer.size());
+    // Drops the mesh furthest from the camera, but never one closer than pos. Returns false if there wasn't one.
+    fn evict_further(&mut self, pos: ChunkPos) -> bool {
+        let distance = |other: &ChunkPos| Self::centre(*other).distance_squared(Self::centre(self.centre));
+        let furthest = self.chunks.keys().filter(|other| **other != pos).max_by(|a, b| distance(a).total_cmp(&distance(b))).copied();
+        match furthest {
+            Some(furthest) if distance(&furthest) > distance(&pos) => {
+                self.remove(furthest);
+                self.evicted.insert(furthest);
+                self.evictions += 1;
+                true
+            }
+            _ => false,
         }
-
-        let transform = MeshUniform {
-            transform: transform.to_cols_array_2d(),
-        };
-
-        // Never need to resize, info length is fixed.
-        self.ctx.write_buffer(&mesh.info_buffer, ref_to_bytes(&transform));
-
-        mesh.num_elements = indi.len() as u32;
     }
 
-    // TODO: move this method since entity wants it too
-    pub fn init_mesh(&self, vert: &[ModelVertex], indi: &[u32], transform: Mat4) -> Mesh {
-        #[cfg(feature = "profiling")]
-        self.init_count.set(self.init_count.get() + 1);
-
-        let vertex_buffer = self.ctx.buffer_init(
-            "tri", slice_to_bytes(vert), wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST
-        );
-        let index_buffer = self.ctx.buffer_init(
-            "tri", slice_to_bytes(indi), wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST
-        );
-
-        let transform = MeshUniform {
-            transform: transform.to_cols_array_2d(),
-        };
+    pub fn translate(pos: ChunkPos) -> Mat4 {
+        let offset = Vec3::new(pos.x as f32 * CHUNK_SCALE, pos.y as f32 * CHUNK_SCALE, pos.z as f32 * CHUNK_SCALE);
+        Mat4::from_translation(offset)
+    }
 
-        let info_buffer = self.ctx.buffer_init(
-            "mesh_info", ref_to_bytes(&transform),
-            wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST
-        );
-
-        #[cfg(feature = "profiling")]
-        self.memory.set(self.memory.get() + index_buffer.size() + vertex_buffer.size() + info_buffer.size());
-
-        let info_bind_group = self.ctx.bind_group("mesh_info", &self.layout, &[
-            info_buffer.as_entire_binding()
-        ]);
-
-        Mesh {
-            vertex_buffer,
-            index_buffer,
-            num_elements: indi.len() as u32,
-            transform,
-            info_buffer,
-            info_bind_group,
-        }
+    fn centre(pos: ChunkPos) -> Vec3 {
+        Self::translate(pos).transform_point3(Vec3::splat(CHUNK_SCALE / 2.0))
     }
 
     #[cfg(feature = "profiling")]
     pub fn log_profile(&self) {
-        // Note: currently MB includes entities since they use the same chunk pool
-        //       planning to just use one mesh for all entities and move resizing to mesh struct.
-        println!("ChunkRender:\n  - loaded: {}\n  - pool: {}\n  - gpu MB: {}", self.chunks.len(), self.mesh_pool.len(), self.memory.get() / 1024 / 1024);
+        println!("ChunkRender:\n  - loaded: {}\n  - budget MB: {} ({}% used)\n  - evicted: {} ({} waiting)",
+                 self.chunks.len(), self.budget / 1024 / 1024, self.arena.used_bytes() * 100 / self.budget.max(1), self.evictions, self.evicted.len());
+        self.arena.log_profile();
     }
 }
 
-pub struct MeshBuilder {
-    pub atlas: Rc<TextureAtlas>,
+const CHUNK_SCALE: f32 = CHUNK_SIZE as f32;
+
+pub fn render_layer(tile: Tile) -> RenderLayer {
+    if tile.solid() {
+        gen::tiles::SOLID_LAYERS[tile.index()]
+    } else {
+        gen::tiles::CUSTOM_LAYERS[tile.index()]
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Geometry {
     pub vert: Vec<ModelVertex>,
     pub indi: Vec<u32>,
 }
 
+/// A whole chunk's mesh, indexed by RenderLayer.
+pub type ChunkGeometry = [Geometry; RenderLayer::COUNT];
+
+/// Copies of the chunks next to the one being meshed, indexed by Direction. None if it isn't loaded.
+pub type Neighbours = [Option<Box<Chunk>>; 6];
+
+/// Builds geometry on the cpu. Doesn't need a gpu so the headless renderers can use it too.
+pub struct MeshBuilder {
+    /// Which layer new faces are added to.
+    pub layer: RenderLayer,
+    /// Biome colour for new faces with a texture in gen::uvs::TINTED.
+    pub tint: [u8; 4],
+    layers: [Geometry; RenderLayer::COUNT],
+}
+
+impl Default for MeshBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl MeshBuilder {
+    pub fn new() -> Self {
+        MeshBuilder {
+            layer: RenderLayer::Opaque,
+            tint: ModelVertex::NO_TINT,
+            layers: RenderLayer::ALL.map(|_| Geometry {
+                vert: Vec::with_capacity(10000),
+                indi: Vec::with_capacity(10000),
+            }),
+        }
+    }
+
     pub fn clear(&mut self) {
-        self.vert.clear();
-        self.indi.clear();
+        for geometry in self.layers.iter_mut() {
+            geometry.vert.clear();
+            geometry.indi.clear();
+        }
+        self.layer = RenderLayer::Opaque;
+        self.tint = ModelVertex::NO_TINT;
+    }
+
+    pub fn geometry(&self, layer: RenderLayer) -> &Geometry {
+        &self.layers[layer as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.iter().all(|geometry| geometry.indi.is_empty())
+    }
+
+    /// Copies out what's been built. The builder keeps its buffers for next time.
+    pub fn take(&self) -> ChunkGeometry {
+        self.layers.clone()
+    }
+
+    /// Replaces the builder's contents with the chunk's geometry.
+    pub fn build_chunk(&mut self, chunk: &Chunk, neighbours: &Neighbours) {
+        self.clear();
+
+        // A face is hidden if the neighbour covers it completely. Touching tiles of the same type
+        // (like two glass blocks) also skip the faces between them.
+        // Faces on the edge look in the neighbouring chunk. They're always drawn if it isn't loaded.
+        // TODO: you already know in the loop which are the edge so maybe treat those differently and the don't need the branching here.
+        let empty = |tile: Tile, x: isize, y: isize, z: isize| match tile_at(chunk, neighbours, x, y, z) {
+            Some(other) => !other.solid() || (other != tile && render_layer(other) != RenderLayer::Opaque),
+            None => true,
+        };
+
+        let mut count = 0;
+        for x in (0..(CHUNK_SIZE as isize))  {
+            for y in 0..(CHUNK_SIZE as isize) {
+                for z in 0..(CHUNK_SIZE as isize)  {
+                    let pos = LocalPos::new(x as usize, y as usize, z as usize);
+                    let tile = chunk.get(pos);
+                    self.tint = chunk.biome(x as usize, z as usize).tint();
+                    if tile.solid() {
+                        debug_assert!(tile.index() <= gen::tiles::SOLID_COUNT, "Invalid tile {:?} at {:?} {:?}. Damn you lua!", tile, chunk.pos, pos);
+                        // TODO: use DirectionSet?
+                        let top = empty(tile, x, y + 1, z);
+                        let right = empty(tile, x, y, z + 1);
+                        let far = empty(tile, x + 1, y, z);
+                        let bottom = empty(tile, x, y - 1, z);
+                        let left = empty(tile, x, y, z - 1);
+                        let close = empty(tile, x - 1, y, z);
+                        self.layer = render_layer(tile);
+                        self.add_cube(tile, pos.normalized() * CHUNK_SCALE, top, bottom, left, right, close, far);
+                        count += 1;
+                    } else if tile.custom_render() {
+                        debug_assert!(tile.index() <= gen::tiles::CUSTOM_COUNT, "Invalid tile {:?} at {:?} {:?}. Damn you lua!", tile, chunk.pos, pos);
+                        let func = gen::render::FUNCS[tile.index()];
+                        self.layer = render_layer(tile);
+                        func(self, &RenderContext { tile, pos: pos.normalized() * CHUNK_SCALE, x, y, z, chunk, neighbours });
+                    }
+                }
+            }
+        }
+
+        // println!("Mesh({}, {}, {}): {} opaque vertices, {} opaque indices, {} cubes.", chunk.pos.x, chunk.pos.y, chunk.pos.z, self.geometry(RenderLayer::Opaque).vert.len(), self.geometry(RenderLayer::Opaque).indi.len(), count);
     }
 
     pub fn add_cube(&mut self, tile: Tile, pos: Vec3, top: bool, bottom: bool, left: bool, right: bool, close: bool, far: bool) {
@@ -251,132 +342,313 @@ impl MeshBuilder {
         let up_far_right = [1.0, 1.0, 1.0];
 
         if bottom {
-            let uv = *self.atlas.get(tile, Direction::Down);
-            self.add_quad(&uv, pos, down_close_left, down_close_right, down_far_left, down_far_right);
+            self.add_face(tile, Direction::Down, pos, [down_close_left, down_close_right, down_far_left, down_far_right]);
         }
 
         if far {
-            let uv = *self.atlas.get(tile, Direction::North);
-            self.add_quad(&uv, pos, up_far_left, up_far_right, down_far_left, down_far_right);
+            self.add_face(tile, Direction::North, pos, [up_far_left, up_far_right, down_far_left, down_far_right]);
         }
 
         if close {
-            let uv = *self.atlas.get(tile, Direction::South);
-            self.add_quad(&uv, pos, up_close_left, up_close_right, down_close_left, down_close_right);
+            self.add_face(tile, Direction::South, pos, [up_close_left, up_close_right, down_close_left, down_close_right]);
         }
 
         if left {
-            let uv = *self.atlas.get(tile, Direction::West);
-            self.add_quad(&uv, pos, up_close_left, up_far_left, down_close_left, down_far_left);
+            self.add_face(tile, Direction::West, pos, [up_close_left, up_far_left, down_close_left, down_far_left]);
         }
 
         if right {
-            let uv = *self.atlas.get(tile, Direction::East);
-            self.add_quad(&uv, pos, up_close_right, up_far_right, down_close_right, down_far_right);
+            self.add_face(tile, Direction::East, pos, [up_close_right, up_far_right, down_close_right, down_far_right]);
         }
 
         if top {
-            let uv = *self.atlas.get(tile, Direction::Up);
-            self.add_quad(&uv, pos, up_close_left, up_close_right, up_far_left, up_far_right);
+            self.add_face(tile, Direction::Up, pos, [up_close_left, up_close_right, up_far_left, up_far_right]);
         }
     }
 
-    fn vertex(&mut self, uv: [f32; 2], pos: Vec3, a: impl Into<Vec3>) -> u32 {
-        self.vert.push(ModelVertex {
-            position: Vec4::from(((a.into() + pos), 1.0)).to_array(),
-            uv,
-        });
-        (self.vert.len() - 1) as u32
+    /// A cuboid from min to max (in blocks, relative to pos) with the whole texture on every side.
+    pub fn add_box(&mut self, uv: UvIndex, pos: Vec3, min: [f32; 3], max: [f32; 3]) {
+        let corner = |far: bool, up: bool, right: bool| [
+            if far { max[0] } else { min[0] },
+            if up { max[1] } else { min[1] },
+            if right { max[2] } else { min[2] },
+        ];
+        // Same winding as add_cube.
+        self.quad(uv, Direction::Down as u8, pos, [corner(false, false, false), corner(false, false, true), corner(true, false, false), corner(true, false, true)]);
+        self.quad(uv, Direction::North as u8, pos, [corner(true, true, false), corner(true, true, true), corner(true, false, false), corner(true, false, true)]);
+        self.quad(uv, Direction::South as u8, pos, [corner(false, true, false), corner(false, true, true), corner(false, false, false), corner(false, false, true)]);
+        self.quad(uv, Direction::West as u8, pos, [corner(false, true, false), corner(true, true, false), corner(false, false, false), corner(true, false, false)]);
+        self.quad(uv, Direction::East as u8, pos, [corner(false, true, true), corner(true, true, true), corner(false, false, true), corner(true, false, true)]);
+        self.quad(uv, Direction::Up as u8, pos, [corner(false, true, false), corner(false, true, true), corner(true, true, false), corner(true, true, true)]);
+    }
+
+    fn add_face(&mut self, tile: Tile, face: Direction, pos: Vec3, corners: [[f32; 3]; 4]) {
+        let uv = TextureAtlas::get(tile, face);
+        self.quad(uv, face as u8, pos, corners);
+    }
+
+    fn vertex(&mut self, uv: UvIndex, corner: u8, face: u8, pos: Vec3, a: impl Into<Vec3>) -> u32 {
+        let vert = &mut self.layers[self.layer as usize].vert;
+        let tint = if gen::uvs::TINTED[uv.0 as usize] { self.tint } else { ModelVertex::NO_TINT };
+        vert.push(ModelVertex::pack(a.into() + pos, corner, uv, face, ModelVertex::FULL_LIGHT, tint));
+        (vert.len() - 1) as u32
     }
 
     // top left, top right, bottom left, bottom right
-    fn add_quad(&mut self, uv: &Uv, pos: Vec3, a: [f32; 3], b: [f32; 3], c: [f32; 3], d: [f32; 3]) {
-        let a = self.vertex(uv.top_left(), pos, a);
-        let b = self.vertex(uv.top_right(), pos, b);
-        let c = self.vertex(uv.bottom_left(), pos, c);
-        let d = self.vertex(uv.bottom_right(), pos, d);
+    fn add_quad(&mut self, uv: UvIndex, pos: Vec3, corners: [[f32; 3]; 4]) {
+        self.quad(uv, ModelVertex::NO_FACE, pos, corners);
+    }
+
+    // Corners in the same order as add_quad.
+    fn quad(&mut self, uv: UvIndex, face: u8, pos: Vec3, corners: [[f32; 3]; 4]) {
+        let [a, b, c, d] = corners;
+        let a = self.vertex(uv, 0, face, pos, a);
+        let b = self.vertex(uv, 1, face, pos, b);
+        let c = self.vertex(uv, 2, face, pos, c);
+        let d = self.vertex(uv, 3, face, pos, d);
         self.add_triangle(a, b, c);
         self.add_triangle(b, d, c);
     }
 
     fn add_triangle(&mut self, a: u32, b: u32, c: u32) {
-        self.indi.push(a);
-        self.indi.push(b);
-        self.indi.push(c);
+        let indi = &mut self.layers[self.layer as usize].indi;
+        indi.push(a);
+        indi.push(b);
+        indi.push(c);
+    }
+}
+
+/// The tile at a position relative to the chunk, looking in the neighbours past the edge. None if that one isn't loaded.
+fn tile_at(chunk: &Chunk, neighbours: &Neighbours, x: isize, y: isize, z: isize) -> Option<Tile> {
+    let is = CHUNK_SIZE as isize;
+    let chunk = if x >= is || y >= is || z >= is || x < 0 || y < 0 || z < 0 {
+        let dir = if y >= is {
+            Direction::Up
+        } else if y < 0 {
+            Direction::Down
+        } else if x >= is {
+            Direction::North
+        } else if x < 0 {
+            Direction::South
+        } else if z >= is {
+            Direction::East
+        } else {
+            Direction::West
+        };
+        &**neighbours[dir as usize].as_ref()?
+    } else {
+        chunk
+    };
+    let pos = LocalPos::new(x.rem_euclid(is) as usize, y.rem_euclid(is) as usize, z.rem_euclid(is) as usize);
+    Some(chunk.get(pos))
+}
+
+/// What a custom renderer gets to know about the tile it's drawing.
+pub struct RenderContext<'a> {
+    pub tile: Tile,
+    /// Where the vertices go, relative to the chunk.
+    pub pos: Vec3,
+    x: isize,
+    y: isize,
+    z: isize,
+    chunk: &'a Chunk,
+    neighbours: &'a Neighbours,
+}
+
+impl<'a> RenderContext<'a> {
+    /// The texture blocks.rs gave the tile.
+    pub fn uv(&self) -> UvIndex {
+        gen::render::UVS[self.tile.index()]
+    }
+
+    /// Set in blocks.rs so tiles can share a renderer (like the growth stage of a plant).
+    pub fn state(&self) -> u8 {
+        gen::render::STATES[self.tile.index()]
+    }
+
+    /// The tile next to this one. Empty if it's in a chunk that isn't loaded.
+    pub fn neighbour(&self, dir: Direction) -> Tile {
+        let offset = dir.offset().as_vec();
+        tile_at(self.chunk, self.neighbours, self.x + offset.x as isize, self.y + offset.y as isize, self.z + offset.z as isize).unwrap_or(Tile::EMPTY)
     }
 }
 
 pub struct TextureAtlas {
     _tex: Texture,  // Never need to use this, but it needs to stay alive and not call drop.
+    _uv_table: Buffer,
+    /// Whether it's using the texture array or the atlas.
+    pub array: bool,
     pub bind_group: BindGroup,
     pub layout: BindGroupLayout,
+    /// Declares the texture bindings and sampling functions used by shader.wgsl. Append it to the shader source.
+    pub shader: &'static str,
 }
 
 impl TextureAtlas {
+    /// Must match the array lengths of UvTable in texture_atlas.wgsl and texture_array.wgsl.
+    const UV_TABLE_SIZE: usize = 256;
+
     pub fn new(ctx: &WindowContext) -> Self {
-        let tex = Self::bake(ctx);
-        let layout = ctx.bind_group_layout_texture();
+        let array_img = image::load_from_memory(gen::TEXTURE_ARRAY_PNG).unwrap();
+        let array = Self::supports_array(ctx, &array_img);
+        let (tex, dimension, shader) = if array {
+            (Self::bake_array(ctx, &array_img), TextureViewDimension::D2Array, include_str!("texture_array.wgsl"))
+        } else {
+            (Self::bake(ctx, &image::load_from_memory(gen::ATLAS_PNG).unwrap()), TextureViewDimension::D2, include_str!("texture_atlas.wgsl"))
+        };
+        // The array doesn't need the rects but animated textures still need the frames.
+        let uv_table = Self::uv_table(ctx, &gen::uvs::ALL);
+        let layout = ctx.bind_group_layout_texture(dimension, true);
         TextureAtlas {
-            bind_group: ctx.bind_group_texture(&layout, &tex),
+            bind_group: ctx.bind_group_texture(&layout, &tex, Some(&uv_table)),
             _tex: tex,
-            layout
+            _uv_table: uv_table,
+            array,
+            layout,
+            shader,
         }
     }
 
-    fn bake(ctx: &WindowContext) -> Texture {
-        let img = image::load_from_memory(gen::ATLAS_PNG).unwrap();
-        Texture::from_image(&ctx.device, &ctx.queue, &img, Some("atlas"))
+    /// Swaps in textures rebuilt from assets/ (see common::blocks::textures).
+    /// The layout doesn't change so pipelines made with it still work.
+    #[cfg(feature = "hot_reload")]
+    pub fn reload(&mut self, ctx: &WindowContext, textures: &common::blocks::Textures) {
+        let tex = if self.array {
+            Self::bake_array(ctx, &textures.array)
+        } else {
+            Self::bake(ctx, &textures.atlas)
+        };
+        self._uv_table = Self::uv_table(ctx, &textures.uvs);
+        self.bind_group = ctx.bind_group_texture(&self.layout, &tex, Some(&self._uv_table));
+        self._tex = tex;
+    }
+
+    // The array needs a layer for every texture. Fall back to the atlas on devices that can't make one that big.
+    fn supports_array(ctx: &WindowContext, img: &DynamicImage) -> bool {
+        let limits = ctx.device.limits();
+        let layers = img.height() / img.width();
+        layers <= limits.max_texture_array_layers && img.width() <= limits.max_texture_dimension_2d
     }
 
-    pub fn get(&self, block: Tile, face: Direction) -> &Uv {
+    // Padded to a fixed length because the shader can't have a dynamically sized uniform array.
+    // Each is a vec4 because uniform arrays need 16 byte alignment anyway.
+    // All the rects then all the frames (see gen::uvs::FRAMES).
+    fn uv_table(ctx: &WindowContext, uvs: &[Uv]) -> Buffer {
+        let mut table = [[0f32; 4]; Self::UV_TABLE_SIZE * 2];
+        for (i, uv) in uvs.iter().enumerate() {
+            table[i] = [uv.x, uv.y, uv.size, 0.0];
+        }
+        for (i, [count, time]) in gen::uvs::FRAMES.iter().enumerate() {
+            table[Self::UV_TABLE_SIZE + i] = [*count, *time, 0.0, 0.0];
+        }
+        ctx.buffer_init("uv_table", slice_to_bytes(&table), wgpu::BufferUsages::UNIFORM)
+    }
+
+    pub fn bake(ctx: &WindowContext, img: &DynamicImage) -> Texture {
+        Texture::from_image(&ctx.device, &ctx.queue, img, Some("atlas"))
+    }
+
+    fn bake_array(ctx: &WindowContext, img: &DynamicImage) -> Texture {
+        Texture::array_from_image(&ctx.device, &ctx.queue, img, Some("texture_array"))
+    }
+
+    pub fn get(block: Tile, face: Direction) -> UvIndex {
         debug_assert!(block.solid());
         let index = (block.index() * 6) + face as usize;
-        &gen::uvs::ALL[gen::uvs::SOLID_INDEXES[index] as usize]
+        UvIndex(gen::uvs::SOLID_INDEXES[index] as u16)
     }
 }
 
+// uv_table would index past the end and the shaders' arrays would be too short.
+const _: () = assert!(gen::uvs::ALL.len() <= TextureAtlas::UV_TABLE_SIZE, "Too many textures for the uv table.");
+
 pub mod renderers {
-    use glam::Vec3;
-    use common::atlas::Uv;
-    use crate::chunk_mesh::{MeshBuilder};
-    use crate::gen::uvs;
+    use common::blocks::RenderLayer;
+    use crate::chunk_mesh::{MeshBuilder, render_layer, RenderContext};
+    use crate::pos::Direction;
 
-    pub type CustomRenderFn = &'static dyn Fn(&mut MeshBuilder, Vec3);
+    pub type CustomRenderFn = &'static dyn Fn(&mut MeshBuilder, &RenderContext);
 
-    pub fn air(_: &mut MeshBuilder, _: Vec3) {
+    pub fn air(_: &mut MeshBuilder, _: &RenderContext) {
         unreachable!()
     }
 
-    pub fn sapling(mesh: &mut MeshBuilder, pos: Vec3) {
-        let uv = uvs::sapling;
+    pub fn sapling(mesh: &mut MeshBuilder, ctx: &RenderContext) {
+        let (uv, pos) = (ctx.uv(), ctx.pos);
         // These have x/z swapped so it makes a little cross.
-        mesh.add_quad(uv, pos, [0.0, 1.0, 0.5], [1.0, 1.0, 0.5], [0.0, 0.0, 0.5], [1.0, 0.0, 0.5]);
-        mesh.add_quad(uv, pos, [0.5, 1.0, 0.0], [0.5, 1.0, 1.0], [0.5, 0.0, 0.0], [0.5, 0.0, 1.0]);
-    }
-
-    // TODO: no copy paste! render funcs need to get their tile?
-    pub fn wheat(mesh: &mut MeshBuilder, pos: Vec3) {
-        plant(mesh, pos,uvs::wheat);
-    }
-    pub fn wheat1(mesh: &mut MeshBuilder, pos: Vec3) {
-        plant(mesh, pos,uvs::wheat1);
-    }
-    pub fn wheat2(mesh: &mut MeshBuilder, pos: Vec3) {
-        plant(mesh, pos,uvs::wheat2);
-    }
-    pub fn wheat3(mesh: &mut MeshBuilder, pos: Vec3) {
-        plant(mesh, pos,uvs::wheat3);
+        mesh.add_quad(uv, pos, [[0.0, 1.0, 0.5], [1.0, 1.0, 0.5], [0.0, 0.0, 0.5], [1.0, 0.0, 0.5]]);
+        mesh.add_quad(uv, pos, [[0.5, 1.0, 0.0], [0.5, 1.0, 1.0], [0.5, 0.0, 0.0], [0.5, 0.0, 1.0]]);
     }
 
-    fn plant(mesh: &mut MeshBuilder, pos: Vec3, uv: &Uv) {
+    /// Every growth stage shares this, they just have different textures.
+    pub fn plant(mesh: &mut MeshBuilder, ctx: &RenderContext) {
+        let (uv, pos) = (ctx.uv(), ctx.pos);
         // This time two quads going across.
         let a = [0.2, 0.8];
         for a in a {
-            mesh.add_quad(uv, pos, [0.0, 1.0, a], [1.0, 1.0, a], [0.0, 0.0, a], [1.0, 0.0, a]);
+            mesh.add_quad(uv, pos, [[0.0, 1.0, a], [1.0, 1.0, a], [0.0, 0.0, a], [1.0, 0.0, a]]);
         }
         // Then swap x/z so its like a tick-tac-toe board.
         for a in a {
-            mesh.add_quad(uv, pos, [a, 1.0, 0.0], [a, 1.0, 1.0], [a, 0.0, 0.0], [a, 0.0, 1.0]);
+            mesh.add_quad(uv, pos, [[a, 1.0, 0.0], [a, 1.0, 1.0], [a, 0.0, 0.0], [a, 0.0, 1.0]]);
+        }
+    }
+
+    /// A post with two rails out to each side that has another fence or a full block.
+    pub fn fence(mesh: &mut MeshBuilder, ctx: &RenderContext) {
+        let (uv, pos) = (ctx.uv(), ctx.pos);
+        mesh.add_box(uv, pos, [0.375, 0.0, 0.375], [0.625, 1.0, 0.625]);
+        for dir in [Direction::North, Direction::South, Direction::East, Direction::West] {
+            let other = ctx.neighbour(dir);
+            if other != ctx.tile && !(other.solid() && render_layer(other) == RenderLayer::Opaque) {
+                continue;
+            }
+            let (x, z) = match dir {
+                Direction::North => ([0.625, 1.0], [0.4375, 0.5625]),
+                Direction::South => ([0.0, 0.375], [0.4375, 0.5625]),
+                Direction::East => ([0.4375, 0.5625], [0.625, 1.0]),
+                _ => ([0.4375, 0.5625], [0.0, 0.375]),
+            };
+            for y in [[0.375, 0.5625], [0.75, 0.9375]] {
+                mesh.add_box(uv, pos, [x[0], y[0], z[0]], [x[1], y[1], z[1]]);
+            }
         }
     }
 }
+
+#[test]
+fn custom_renderers() {
+    use gen::tiles::*;
+
+    // One renderer for every stage, each with its own texture.
+    let mut chunk = Chunk::full(empty, ChunkPos::new(0, 0, 0));
+    chunk.set(LocalPos::new(1, 1, 1), wheat1);
+    chunk.set(LocalPos::new(3, 1, 1), wheat);
+    let mut mesh = MeshBuilder::new();
+    mesh.build_chunk(&chunk, &Default::default());
+    let vert = &mesh.geometry(RenderLayer::Cutout).vert;
+    assert_eq!(vert.len(), 2 * 4 * 4);
+    assert!(vert[..16].iter().all(|v| v.uv() == gen::uvs::wheat1));
+    assert!(vert[16..].iter().all(|v| v.uv() == gen::uvs::wheat));
+    assert_eq!((gen::render::STATES[wheat1.index()], gen::render::STATES[wheat.index()]), (0, 3));
+
+    // A fence on its own is just the post, then it grows two rails towards each fence or full block next to it.
+    let boxes = |chunk: &Chunk, neighbours: &Neighbours| {
+        let mut mesh = MeshBuilder::new();
+        mesh.build_chunk(chunk, neighbours);
+        mesh.geometry(RenderLayer::Cutout).indi.len() / 36
+    };
+    let mut chunk = Chunk::full(empty, ChunkPos::new(0, 0, 0));
+    chunk.set(LocalPos::new(15, 1, 1), fence);
+    assert_eq!(boxes(&chunk, &Default::default()), 1);
+    chunk.set(LocalPos::new(15, 1, 2), fence);
+    chunk.set(LocalPos::new(14, 1, 1), sapling);
+    assert_eq!(boxes(&chunk, &Default::default()), 2 * 3);
+    chunk.set(LocalPos::new(14, 1, 1), stone);
+    assert_eq!(boxes(&chunk, &Default::default()), 5 + 3);
+
+    // Neighbours in the next chunk count too.
+    let mut neighbours: Neighbours = Default::default();
+    neighbours[Direction::North as usize] = Some(Box::new(Chunk::full(stone, ChunkPos::new(1, 0, 0))));
+    assert_eq!(boxes(&chunk, &neighbours), 7 + 5);
+}
@@ -0,0 +1,73 @@
+// Entity models. Every bone of every entity is an instance. See EntityRender in entity_render.rs.
+
+struct CameraUniform {
+    view_proj: mat4x4<f32>,
+    view_pos: vec4<f32>,
+    fog: vec4<f32>,  // start, end
+    fog_colour: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> camera: CameraUniform;
+
+// The atlas, even if chunks are using the texture array.
+@group(1) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(1) @binding(1)
+var s_diffuse: sampler;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+};
+
+// See EntityInstance. Matrices can't be vertex attributes so it's split into columns.
+struct InstanceInput {
+    @location(3) transform_0: vec4<f32>,
+    @location(4) transform_1: vec4<f32>,
+    @location(5) transform_2: vec4<f32>,
+    @location(6) transform_3: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) uv: vec2<f32>,
+    @location(2) light: f32,
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let transform = mat4x4<f32>(instance.transform_0, instance.transform_1, instance.transform_2, instance.transform_3);
+    var out: VertexOutput;
+    out.world_position = transform * vec4<f32>(model.position, 1.0);
+    out.clip_position = camera.view_proj * out.world_position;
+    out.uv = model.uv;
+    out.light = shade(normalize((transform * vec4<f32>(model.normal, 0.0)).xyz));
+    return out;
+}
+
+// Same numbers as face_shade in shader.wgsl, blended by how much the normal points along each axis.
+fn shade(normal: vec3<f32>) -> f32 {
+    let squared = normal * normal;
+    let up_down = select(0.5, 1.0, normal.y > 0.0);
+    return squared.y * up_down + squared.z * 0.8 + squared.x * 0.65;
+}
+
+// Same as shader.wgsl
+fn apply_fog(colour: vec3<f32>, world_position: vec4<f32>) -> vec3<f32> {
+    let dist = distance(world_position.xyz, camera.view_pos.xyz);
+    let amount = clamp((dist - camera.fog.x) / (camera.fog.y - camera.fog.x), 0.0, 1.0);
+    return mix(colour, camera.fog_colour.rgb, amount);
+}
+
+// Models are cutout so the texture can leave holes.
+@fragment
+fn fs_cutout(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour = textureSample(t_diffuse, s_diffuse, in.uv);
+    if object_colour.a < 0.5 {
+        discard;
+    }
+    return vec4<f32>(apply_fog(object_colour.rgb * in.light, in.world_position), 1.0);
+}
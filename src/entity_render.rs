@@ -1,62 +1,341 @@
 use std::collections::HashMap;
+use std::mem::size_of;
+use std::ops::Range;
 use std::rc::Rc;
-use wgpu::RenderPass;
-use crate::chunk_mesh::ChunkList;
-use crate::window::{Mesh, WindowContext};
+use glam::{Mat4, Vec3};
+use instant::{Duration, Instant};
+use wgpu::{BindGroup, BindGroupLayout, Buffer, BufferUsages, RenderPass, RenderPipeline, TextureViewDimension};
+use common::anim::{Animation, Query};
+use common::geo::{Geometry, ModelSource};
+use crate::chunk_mesh::TextureAtlas;
+use crate::gen;
+use crate::lua_api::lua::GameLogic;
+use crate::window::{EntityInstance, EntityVertex, PipelineOptions, slice_to_bytes, Texture, WindowContext};
 
-// TODO: this needs to be a trait
-pub enum EntityInfo {
-    None,
-    SingleMesh(Mesh)
+/// How often lua moves entities. Same as tick_interval_secs in world.lua.
+pub const TICK: Duration = Duration::from_millis(50);
+
+/// How one type of entity is drawn. Lua registers a type id with a model (and maybe animations) and
+/// EntityRender picks the implementation.
+pub trait EntityRenderer {
+    fn model(&self) -> &Model;
+    /// Model space transform of every bone this frame (see Geometry::pose).
+    fn pose(&self, entity: &mut Entity, now: Instant, logic: &GameLogic) -> Vec<Mat4>;
+}
+
+/// Always in the rest pose (like falling blocks).
+pub struct StaticModel {
+    model: Model,
+}
+
+impl EntityRenderer for StaticModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn pose(&self, _: &mut Entity, _: Instant, _: &GameLogic) -> Vec<Mat4> {
+        self.model.geometry.rest_pose()
+    }
+}
+
+/// Plays the animation ending in .walk while it's moving and the one ending in .idle the rest of the time
+/// (like geckolib's usual controller). Either can be missing and it just stays still.
+pub struct WalkingModel {
+    model: Model,
+    animations: Vec<Animation>,
+    idle: Option<usize>,
+    walk: Option<usize>,
+}
+
+impl WalkingModel {
+    fn new(model: Model, animations: Vec<Animation>) -> Self {
+        let find = |suffix: &str| animations.iter().position(|animation| animation.name.ends_with(suffix));
+        WalkingModel {
+            idle: find(".idle"),
+            walk: find(".walk"),
+            model,
+            animations,
+        }
+    }
+}
+
+impl EntityRenderer for WalkingModel {
+    fn model(&self) -> &Model {
+        &self.model
+    }
+
+    fn pose(&self, entity: &mut Entity, now: Instant, logic: &GameLogic) -> Vec<Mat4> {
+        let moving = entity.pos.x != entity.prev_pos.x || entity.pos.z != entity.prev_pos.z;
+        let wanted = if moving { self.walk } else { self.idle };
+        // Restart when it switches.
+        if entity.animation.map(|(index, _)| index) != wanted {
+            entity.animation = wanted.map(|index| (index, now));
+        }
+
+        match entity.animation {
+            None => self.model.geometry.rest_pose(),
+            Some((index, started)) => {
+                let life_time = (now - entity.created).as_secs_f32();
+                self.animations[index].pose(&self.model.geometry, (now - started).as_secs_f32(), &mut |source, anim_time| {
+                    let index = gen::animations::EXPRESSIONS.iter().position(|s| *s == source).unwrap();
+                    logic.eval_expression(index, Query { anim_time, life_time })
+                })
+            }
+        }
+    }
+}
+
+pub struct Entity {
+    ty: i32,
+    /// Where it was at the last two ticks. It's drawn part way between them depending on how long ago the last tick was.
+    prev_pos: Vec3,
+    pos: Vec3,
+    last_tick: Instant,
+    created: Instant,
+    /// Radians around y. Turns to face the way it's moving.
+    yaw: f32,
+    /// For the renderer to remember what animation is playing and when it started.
+    animation: Option<(usize, Instant)>,
+}
+
+/// A model uploaded to the gpu in its rest pose.
+pub struct Model {
+    geometry: Geometry,
+    vertices: Buffer,
+    indices: Buffer,
+    /// The range of indices for each bone's cubes. Geometry::mesh adds them one bone at a time.
+    bones: Vec<Range<u32>>,
+}
+
+/// A registered entity type.
+struct EntityType {
+    renderer: Box<dyn EntityRenderer>,
+    /// Set by prepare. Where this type's instances start in the instance buffer and how many entities there are.
+    /// Each bone is a separate run of instances so they can share the vertices without the shader needing every pose.
+    first_instance: u32,
+    count: u32,
 }
 
+/// Entities of the same type are drawn together. Each bone is one instanced draw (so just one for models
+/// with a single bone like falling blocks), with every entity's pose for that bone in the instance buffer.
 pub struct EntityRender {
-    entities: HashMap<i32, EntityInfo>,
+    entities: HashMap<i32, Entity>,
+    types: HashMap<i32, EntityType>,
     ctx: Rc<WindowContext>,
+    pipeline: RenderPipeline,
+    /// Model textures are only in the atlas (not the texture array) so this always has the atlas.
+    _texture: Texture,
+    #[cfg(feature = "hot_reload")]
+    texture_layout: BindGroupLayout,
+    texture_bind_group: BindGroup,
+    /// Rebuilt every frame.
+    instances: Buffer,
+    instance_data: Vec<EntityInstance>,
+    poses: Vec<Mat4>,
 }
 
 impl EntityRender {
-    pub fn new(ctx: Rc<WindowContext>) -> Self {
+    const START_INSTANCES: u64 = 1 << 10;
+
+    pub fn new(ctx: Rc<WindowContext>, camera_layout: &BindGroupLayout) -> Self {
+        let texture = TextureAtlas::bake(&ctx, &image::load_from_memory(gen::ATLAS_PNG).unwrap());
+        let texture_layout = ctx.bind_group_layout_texture(TextureViewDimension::D2, false);
+        let texture_bind_group = ctx.bind_group_texture(&texture_layout, &texture, None);
+        let pipeline = ctx.render_pipeline(
+            "Entity", &ctx.pipeline_layout(&[camera_layout, &texture_layout]), &[
+                wgpu::VertexBufferLayout {
+                    array_stride: size_of::<EntityVertex>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Vertex,
+                    attributes: EntityVertex::ATTRIBS
+                },
+                wgpu::VertexBufferLayout {
+                    array_stride: size_of::<EntityInstance>() as wgpu::BufferAddress,
+                    step_mode: wgpu::VertexStepMode::Instance,
+                    attributes: EntityInstance::ATTRIBS
+                },
+            ], include_str!("entity.wgsl"), PipelineOptions::CUTOUT
+        );
+
         Self {
             entities: Default::default(),
+            types: Default::default(),
+            instances: Self::instance_buffer(&ctx, Self::START_INSTANCES),
             ctx,
+            pipeline,
+            _texture: texture,
+            #[cfg(feature = "hot_reload")]
+            texture_layout,
+            texture_bind_group,
+            instance_data: vec![],
+            poses: vec![],
         }
     }
 
-    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
-        for info in self.entities.values() {
-            match info {
-                EntityInfo::None => {}
-                EntityInfo::SingleMesh(mesh) => mesh.render(render_pass),
-            }
+    /// Replaces the atlas (when it gets rebuilt at runtime).
+    #[cfg(feature = "hot_reload")]
+    pub fn set_texture(&mut self, texture: Texture) {
+        self.texture_bind_group = self.ctx.bind_group_texture(&self.texture_layout, &texture, None);
+        self._texture = texture;
+    }
+
+    fn instance_buffer(ctx: &WindowContext, count: u64) -> Buffer {
+        ctx.buffer_empty("entity_instance", count * size_of::<EntityInstance>() as u64, BufferUsages::VERTEX | BufferUsages::COPY_DST)
+    }
+
+    /// Indexes into gen::models::ALL and gen::animations::ALL. Registering the same type again replaces it.
+    pub fn register(&mut self, ty: i32, model: usize, animations: Option<usize>) {
+        let model = Model::load(&self.ctx, gen::models::ALL[model]);
+        let renderer: Box<dyn EntityRenderer> = match animations {
+            None => Box::new(StaticModel { model }),
+            Some(animations) => Box::new(WalkingModel::new(model, gen::animations::ALL[animations].load())),
+        };
+        self.types.insert(ty, EntityType {
+            renderer,
+            first_instance: 0,
+            count: 0,
+        });
+    }
+
+    /// Called once per tick with its new position.
+    pub fn update(&mut self, id: i32, ty: i32, pos: Vec3) {
+        if !self.types.contains_key(&ty) {
+            debug_assert!(false, "Entity type {} was never registered, id={}", ty, id);
+            return;
+        }
+
+        let now = Instant::now();
+        let entity = self.entities.entry(id).or_insert_with(|| Entity {
+            ty,
+            prev_pos: pos,
+            pos,
+            last_tick: now,
+            created: now,
+            yaw: 0.0,
+            animation: None,
+        });
+        entity.prev_pos = entity.pos;
+        entity.pos = pos;
+        entity.last_tick = now;
+        let step = pos - entity.prev_pos;
+        if step.x != 0.0 || step.z != 0.0 {
+            entity.yaw = step.x.atan2(step.z);
         }
     }
 
-    pub fn update(&mut self, id: i32, callback: impl FnOnce(&WindowContext, &mut EntityInfo)) {
-        match self.entities.get_mut(&id) {
-            None => {
-                self.entities.insert(id, EntityInfo::None);
-                callback(&self.ctx, self.entities.get_mut(&id).unwrap());
+    /// Poses every entity where it should be between ticks and uploads the instance buffer. Call before the render pass.
+    pub fn prepare(&mut self, logic: &GameLogic) {
+        let now = Instant::now();
+        self.instance_data.clear();
+        for (ty, info) in self.types.iter_mut() {
+            // Entity major so each pose can be pushed at once, then copied out bone major.
+            self.poses.clear();
+            let mut count = 0;
+            for entity in self.entities.values_mut().filter(|entity| entity.ty == *ty) {
+                let transform = entity.transform(now);
+                self.poses.extend(info.renderer.pose(entity, now, logic).into_iter().map(|bone| transform * bone));
+                count += 1;
             }
-            Some(info) => {
-                callback(&self.ctx, info);
+
+            info.first_instance = self.instance_data.len() as u32;
+            info.count = count;
+            let bone_count = info.renderer.model().bones.len();
+            for bone in 0..bone_count {
+                self.instance_data.extend((0..count as usize).map(|i| EntityInstance {
+                    transform: self.poses[i * bone_count + bone].to_cols_array_2d(),
+                }));
             }
         }
+
+        if self.instance_data.is_empty() {
+            return;
+        }
+        if (self.instance_data.len() * size_of::<EntityInstance>()) as u64 > self.instances.size() {
+            self.instances = Self::instance_buffer(&self.ctx, (self.instance_data.len() as u64).next_power_of_two());
+        }
+        self.ctx.queue.write_buffer(&self.instances, 0, slice_to_bytes(&self.instance_data));
     }
 
-    pub fn remove(&mut self, chunks: &mut ChunkList, id: i32) {
-        if let Some(old) = self.entities.remove(&id) {
-            match old {
-                EntityInfo::None => {}
-                EntityInfo::SingleMesh(mesh) => {
-                    chunks.recycle(Some(mesh));
+    /// Expects the camera bind group to already be set. Changes the pipeline.
+    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        if self.instance_data.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, &self.texture_bind_group, &[]);
+        let stride = size_of::<EntityInstance>() as u64;
+        for info in self.types.values() {
+            if info.count == 0 {
+                continue;
+            }
+            let model = info.renderer.model();
+            render_pass.set_vertex_buffer(0, model.vertices.slice(..));
+            render_pass.set_index_buffer(model.indices.slice(..), wgpu::IndexFormat::Uint32);
+            for (bone, indices) in model.bones.iter().enumerate() {
+                if indices.is_empty() {
+                    continue;
                 }
+                // WebGL can't draw with a first instance so the buffer offset does it instead.
+                let first = (info.first_instance + bone as u32 * info.count) as u64;
+                render_pass.set_vertex_buffer(1, self.instances.slice(first * stride..(first + info.count as u64) * stride));
+                render_pass.draw_indexed(indices.clone(), 0, 0..info.count);
             }
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    pub fn remove(&mut self, id: i32) {
+        self.entities.remove(&id);
+    }
+
     #[cfg(feature = "profiling")]
     pub fn log_profile(&self) {
-        println!("EntityRender:\n  - loaded: {}", self.entities.len());
+        let draws: usize = self.types.values()
+            .filter(|info| info.count > 0)
+            .map(|info| info.renderer.model().bones.iter().filter(|indices| !indices.is_empty()).count())
+            .sum();
+        println!("EntityRender:\n  - loaded: {}\n  - types: {}\n  - instances: {}\n  - draw calls: {}",
+                 self.entities.len(), self.types.len(), self.instance_data.len(), draws);
+    }
+}
+
+impl Model {
+    fn load(ctx: &WindowContext, source: ModelSource) -> Model {
+        let geometry = source.load();
+        let mesh = geometry.mesh(source.texture);
+
+        let mut bones = vec![0..0; geometry.bones.len()];
+        for (i, index) in mesh.indices.iter().enumerate() {
+            let bone = &mut bones[mesh.vertices[*index as usize].bone as usize];
+            if bone.start == bone.end {
+                *bone = i as u32..i as u32;
+            }
+            debug_assert_eq!(bone.end, i as u32, "Bone indices aren't contiguous.");
+            bone.end += 1;
+        }
+
+        let vertices: Vec<EntityVertex> = mesh.vertices.iter().map(|vertex| EntityVertex {
+            position: vertex.pos.to_array(),
+            uv: vertex.uv.to_array(),
+            normal: vertex.normal.to_array(),
+        }).collect();
+
+        Model {
+            vertices: ctx.buffer_init(source.name, slice_to_bytes(&vertices), BufferUsages::VERTEX),
+            indices: ctx.buffer_init(source.name, slice_to_bytes(&mesh.indices), BufferUsages::INDEX),
+            geometry,
+            bones,
+        }
+    }
+}
+
+impl Entity {
+    /// Model space to world space, part way between the last two ticks.
+    fn transform(&self, now: Instant) -> Mat4 {
+        let partial_tick = ((now - self.last_tick).as_secs_f32() / TICK.as_secs_f32()).min(1.0);
+        let pos = self.prev_pos.lerp(self.pos, partial_tick);
+        Mat4::from_translation(pos) * Mat4::from_rotation_y(self.yaw)
     }
 }
@@ -0,0 +1,242 @@
+use std::fmt::Write;
+use std::fs;
+use glam::Vec3;
+use instant::Instant;
+use crate::camera::CameraPerspective;
+
+/// Where the camera was at one moment of a recording.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    /// Seconds since the recording started.
+    pub time: f32,
+    pub pos: Vec3,
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+/// A recorded flight. Saved as text with one keyframe per line: `time x y z yaw pitch`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CameraPath {
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl CameraPath {
+    pub fn load(path: &str) -> Result<CameraPath, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        CameraPath::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<CameraPath, String> {
+        let mut keyframes = vec![];
+        for (i, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let numbers: Result<Vec<f32>, _> = line.split_whitespace().map(str::parse).collect();
+            let numbers = numbers.map_err(|e| format!("line {}: {}", i + 1, e))?;
+            let &[time, x, y, z, yaw, pitch] = numbers.as_slice() else {
+                return Err(format!("line {}: expected 6 numbers but found {}", i + 1, numbers.len()));
+            };
+            keyframes.push(Keyframe { time, pos: Vec3::new(x, y, z), yaw, pitch });
+        }
+        if keyframes.is_empty() {
+            return Err("no keyframes".to_string());
+        }
+        if keyframes.windows(2).any(|pair| pair[1].time < pair[0].time) {
+            return Err("keyframes must be in order".to_string());
+        }
+        Ok(CameraPath { keyframes })
+    }
+
+    pub fn save(&self, path: &str) -> std::io::Result<()> {
+        let mut text = String::new();
+        for k in &self.keyframes {
+            writeln!(text, "{} {} {} {} {} {}", k.time, k.pos.x, k.pos.y, k.pos.z, k.yaw, k.pitch).unwrap();
+        }
+        fs::write(path, text)
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.keyframes.last().map_or(0.0, |k| k.time)
+    }
+
+    /// Linear between the keyframes on either side. Holds the first/last keyframe outside the path.
+    pub fn sample(&self, time: f32) -> Keyframe {
+        let after = self.keyframes.partition_point(|k| k.time <= time);
+        if after == 0 {
+            return self.keyframes[0];
+        }
+        if after == self.keyframes.len() {
+            return self.keyframes[after - 1];
+        }
+        let (a, b) = (self.keyframes[after - 1], self.keyframes[after]);
+        let t = (time - a.time) / (b.time - a.time);
+        Keyframe {
+            time,
+            pos: a.pos.lerp(b.pos, t),
+            yaw: a.yaw + (b.yaw - a.yaw) * t,
+            pitch: a.pitch + (b.pitch - a.pitch) * t,
+        }
+    }
+}
+
+/// Writes down where the camera is a few times a second.
+pub struct PathRecorder {
+    start: Instant,
+    path: CameraPath,
+}
+
+impl PathRecorder {
+    /// Seconds between keyframes. Replays interpolate so this only needs to be small enough to keep the curves.
+    const INTERVAL: f32 = 0.1;
+    /// Where State saves recordings.
+    pub const FILE: &'static str = "camera_path.txt";
+
+    pub fn new() -> Self {
+        PathRecorder {
+            start: Instant::now(),
+            path: CameraPath::default(),
+        }
+    }
+
+    pub fn record(&mut self, camera: &CameraPerspective) {
+        let time = self.start.elapsed().as_secs_f32();
+        if self.path.keyframes.last().is_some_and(|k| time - k.time < Self::INTERVAL) {
+            return;
+        }
+        self.path.keyframes.push(Keyframe { time, pos: camera.pos, yaw: camera.yaw, pitch: camera.pitch });
+    }
+
+    pub fn finish(self) -> CameraPath {
+        self.path
+    }
+}
+
+impl Default for PathRecorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Flies the camera along a recorded path and measures every frame. The world and lua's math.random use
+/// Benchmark::SEED so each run generates and meshes the same chunks.
+pub struct Benchmark {
+    path: CameraPath,
+    start: Instant,
+    last_frame: Instant,
+    frame_times_ms: Vec<f32>,
+    /// Counters when it started so the summary only has this run.
+    start_generated: u64,
+    start_meshed: u64,
+}
+
+/// The results of a Benchmark. Saved as json so runs can be compared by scripts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BenchmarkSummary {
+    pub frames: usize,
+    pub min_ms: f32,
+    pub avg_ms: f32,
+    pub p99_ms: f32,
+    pub max_ms: f32,
+    pub chunks_generated: u64,
+    pub chunks_meshed: u64,
+}
+
+impl Benchmark {
+    pub const SEED: u32 = 1;
+    /// Where the summary is saved.
+    pub const OUTPUT: &'static str = "benchmark.json";
+
+    /// Counters are how many chunks have been generated and meshed so far.
+    pub fn new(path: CameraPath, generated: u64, meshed: u64) -> Self {
+        Benchmark {
+            path,
+            start: Instant::now(),
+            last_frame: Instant::now(),
+            frame_times_ms: vec![],
+            start_generated: generated,
+            start_meshed: meshed,
+        }
+    }
+
+    /// Call once a frame. Moves the camera to where the path is now. Returns false once the path is over.
+    pub fn frame(&mut self, camera: &mut CameraPerspective) -> bool {
+        let now = Instant::now();
+        self.frame_times_ms.push((now - self.last_frame).as_secs_f32() * 1000.0);
+        self.last_frame = now;
+
+        let time = (now - self.start).as_secs_f32();
+        let key = self.path.sample(time);
+        camera.pos = key.pos;
+        camera.yaw = key.yaw;
+        camera.pitch = key.pitch;
+        time <= self.path.duration()
+    }
+
+    pub fn finish(self, generated: u64, meshed: u64) -> BenchmarkSummary {
+        BenchmarkSummary::new(self.frame_times_ms, generated - self.start_generated, meshed - self.start_meshed)
+    }
+}
+
+impl BenchmarkSummary {
+    /// The first frame time is dropped since it includes loading.
+    pub fn new(mut frame_times_ms: Vec<f32>, chunks_generated: u64, chunks_meshed: u64) -> Self {
+        if frame_times_ms.len() > 1 {
+            frame_times_ms.remove(0);
+        }
+        frame_times_ms.sort_by(f32::total_cmp);
+        let frames = frame_times_ms.len();
+        let percentile = |p: f32| frame_times_ms.get(((frames as f32 * p).ceil() as usize).saturating_sub(1)).copied().unwrap_or(0.0);
+        BenchmarkSummary {
+            frames,
+            min_ms: percentile(0.0),
+            avg_ms: frame_times_ms.iter().sum::<f32>() / frames.max(1) as f32,
+            p99_ms: percentile(0.99),
+            max_ms: percentile(1.0),
+            chunks_generated,
+            chunks_meshed,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"frames\": {}, \"min_ms\": {:.3}, \"avg_ms\": {:.3}, \"p99_ms\": {:.3}, \"max_ms\": {:.3}, \"chunks_generated\": {}, \"chunks_meshed\": {}}}",
+            self.frames, self.min_ms, self.avg_ms, self.p99_ms, self.max_ms, self.chunks_generated, self.chunks_meshed
+        )
+    }
+}
+
+#[test]
+fn camera_path() {
+    let text = "0 0 0 0 0 0\n1 10 0 -4 1 0.5\n\n3 10 2 -4 1 0.5\n";
+    let path = CameraPath::parse(text).unwrap();
+    assert_eq!(path.keyframes.len(), 3);
+    assert_eq!(path.duration(), 3.0);
+
+    let half = path.sample(0.5);
+    assert_eq!(half.pos, Vec3::new(5.0, 0.0, -2.0));
+    assert_eq!((half.yaw, half.pitch), (0.5, 0.25));
+    assert_eq!(path.sample(2.0).pos, Vec3::new(10.0, 1.0, -4.0));
+    assert_eq!(path.sample(-1.0), path.keyframes[0]);
+    assert_eq!(path.sample(10.0), path.keyframes[2]);
+
+    // Saving then loading gives back the same path.
+    let file = std::env::temp_dir().join(format!("blockgame_path_{}.txt", std::process::id()));
+    let file = file.to_str().unwrap();
+    path.save(file).unwrap();
+    assert_eq!(CameraPath::load(file).unwrap(), path);
+    fs::remove_file(file).unwrap();
+
+    assert!(CameraPath::parse("").is_err());
+    assert!(CameraPath::parse("0 1 2").is_err());
+    assert!(CameraPath::parse("1 0 0 0 0 0\n0 0 0 0 0 0").is_err());
+}
+
+#[test]
+fn benchmark_summary() {
+    // 100 frames of 1..=100ms, after the slow first frame gets dropped.
+    let mut times: Vec<f32> = (1..=100).rev().map(|ms| ms as f32).collect();
+    times.insert(0, 5000.0);
+    let summary = BenchmarkSummary::new(times, 12, 30);
+    assert_eq!(summary.frames, 100);
+    assert_eq!((summary.min_ms, summary.p99_ms, summary.max_ms), (1.0, 99.0, 100.0));
+    assert_eq!(summary.avg_ms, 50.5);
+    assert_eq!(summary.to_json(), r#"{"frames": 100, "min_ms": 1.000, "avg_ms": 50.500, "p99_ms": 99.000, "max_ms": 100.000, "chunks_generated": 12, "chunks_meshed": 30}"#);
+}
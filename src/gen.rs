@@ -7,6 +7,8 @@ include!(concat!(env!("OUT_DIR"), "/gen.rs"));
 // TODO: I like the idea of not depending on Image at runtime but then the binary would be bigger.
 /// The atlas texture generated by the build script.
 pub const ATLAS_PNG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/atlas.png"));
+/// Every block texture as one layer of a texture array (stacked vertically). Layer index is the same as the uv index.
+pub const TEXTURE_ARRAY_PNG: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/texture_array.png"));
 
 #[test]
 fn codegen_sanity_check(){
@@ -23,6 +25,10 @@ fn codegen_sanity_check(){
     assert_eq!(tiles::SOLID_COUNT, solid_count);
 
     assert_eq!(tiles::CUSTOM_COUNT, render::FUNCS.len() - 1);
+    assert_eq!(tiles::SOLID_LAYERS.len(), tiles::SOLID_COUNT + 1);
+    assert_eq!(tiles::CUSTOM_LAYERS.len(), tiles::CUSTOM_COUNT + 1);
+    assert_eq!(render::UVS.len(), tiles::CUSTOM_COUNT + 1);
+    assert_eq!(render::STATES.len(), tiles::CUSTOM_COUNT + 1);
 
     // All uv coords are on the unit square
     for uv in uvs::ALL {
@@ -35,6 +41,31 @@ fn codegen_sanity_check(){
     }
 
     assert!(uvs::ALL.len() < 256, "SOLID_INDEXES assumes indexes can be u8.");
+
+    // The shader steps through an animation's frames by adding to its first uv index.
+    assert_eq!(uvs::FRAMES.len(), uvs::ALL.len());
+    assert_eq!(uvs::TINTED.len(), uvs::ALL.len());
+    for (i, [count, time]) in uvs::FRAMES.iter().enumerate() {
+        if *count > 1.0 {
+            assert!(*time > 0.0);
+            assert!(i + *count as usize <= uvs::ALL.len());
+        }
+    }
+
+    // Model files are only read when an entity first needs them so check they're all valid here.
+    for model in models::ALL {
+        let geometry = model.load();
+        assert!(!geometry.bones.is_empty(), "{}", model.name);
+    }
+
+    // EntityRender looks expressions up by their source so they all need a number.
+    for source in animations::ALL {
+        for animation in source.load() {
+            for expression in animation.expressions() {
+                assert!(animations::EXPRESSIONS.contains(&expression), "{} {}", animation.name, expression);
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+use winit::event::VirtualKeyCode;
+use common::input::Action;
+
+/// Which key does which Action. Starts with the defaults and a key_bindings.txt in the working directory
+/// can change them. Each line is an action and the keys for it: `move_forward = W, Up`.
+/// An action in the file loses its default keys. `#` starts a comment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyBindings {
+    keys: HashMap<VirtualKeyCode, Action>,
+}
+
+impl KeyBindings {
+    pub const FILE: &'static str = "key_bindings.txt";
+
+    /// Uses the defaults if there's no file. Lines that don't make sense are skipped with a warning.
+    pub fn load() -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Ok(text) = std::fs::read_to_string(Self::FILE) {
+            let (bindings, warnings) = KeyBindings::parse(&text);
+            for warning in warnings {
+                eprintln!("{}: {}", Self::FILE, warning);
+            }
+            return bindings;
+        }
+        KeyBindings::default()
+    }
+
+    /// Returns the bindings with every line that could be understood and a message for each one that couldn't.
+    pub fn parse(text: &str) -> (Self, Vec<String>) {
+        let mut bindings = KeyBindings::default();
+        let mut warnings = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let Some((name, keys)) = line.split_once('=') else {
+                warnings.push(format!("line {}: expected 'action = Key, Key'", i + 1));
+                continue;
+            };
+            let Some(action) = Action::from_name(name.trim()) else {
+                warnings.push(format!("line {}: unknown action '{}'", i + 1, name.trim()));
+                continue;
+            };
+            bindings.keys.retain(|_, bound| *bound != action);
+            for key in keys.split(',').map(str::trim).filter(|key| !key.is_empty()) {
+                match key_from_name(key) {
+                    Some(key) => { bindings.keys.insert(key, action); }
+                    None => warnings.push(format!("line {}: unknown key '{}'", i + 1, key)),
+                }
+            }
+        }
+        (bindings, warnings)
+    }
+
+    pub fn action(&self, key: VirtualKeyCode) -> Option<Action> {
+        self.keys.get(&key).copied()
+    }
+
+    /// Sorted by name so messages about them don't change order.
+    pub fn keys(&self, action: Action) -> Vec<VirtualKeyCode> {
+        let mut keys: Vec<_> = self.keys.iter().filter(|(_, bound)| **bound == action).map(|(key, _)| *key).collect();
+        keys.sort_by_key(|key| key_name(*key));
+        keys
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+        let defaults = [
+            (W, Action::MoveForward), (Up, Action::MoveForward),
+            (S, Action::MoveBackward), (Down, Action::MoveBackward),
+            (A, Action::MoveLeft), (Left, Action::MoveLeft),
+            (D, Action::MoveRight), (Right, Action::MoveRight),
+            (Space, Action::MoveUp),
+            (LShift, Action::MoveDown),
+            (LControl, Action::Sprint),
+            (Key1, Action::SlowDown),
+            (Key2, Action::SpeedUp),
+            (Tab, Action::ToggleCursor),
+            (F3, Action::ToggleDebugHud),
+            (V, Action::ToggleVsync),
+            (F2, Action::Screenshot),
+            (F5, Action::CycleCamera),
+            (F6, Action::RecordPath),
+            (Minus, Action::RenderDistanceDown),
+            (Equals, Action::RenderDistanceUp),
+            (P, Action::LogProfile),
+            (Escape, Action::Quit),
+        ];
+        KeyBindings { keys: defaults.into_iter().collect() }
+    }
+}
+
+/// The names are the same as the VirtualKeyCode variants.
+const KEY_NAMES: &[(&str, VirtualKeyCode)] = {
+    use VirtualKeyCode::*;
+    &[
+        ("A", A), ("B", B), ("C", C), ("D", D), ("E", E), ("F", F), ("G", G), ("H", H), ("I", I), ("J", J),
+        ("K", K), ("L", L), ("M", M), ("N", N), ("O", O), ("P", P), ("Q", Q), ("R", R), ("S", S), ("T", T),
+        ("U", U), ("V", V), ("W", W), ("X", X), ("Y", Y), ("Z", Z),
+        ("Key0", Key0), ("Key1", Key1), ("Key2", Key2), ("Key3", Key3), ("Key4", Key4),
+        ("Key5", Key5), ("Key6", Key6), ("Key7", Key7), ("Key8", Key8), ("Key9", Key9),
+        ("F1", F1), ("F2", F2), ("F3", F3), ("F4", F4), ("F5", F5), ("F6", F6),
+        ("F7", F7), ("F8", F8), ("F9", F9), ("F10", F10), ("F11", F11), ("F12", F12),
+        ("Up", Up), ("Down", Down), ("Left", Left), ("Right", Right),
+        ("Space", Space), ("Tab", Tab), ("Escape", Escape), ("Return", Return), ("Back", Back),
+        ("Insert", Insert), ("Delete", Delete), ("Home", Home), ("End", End), ("PageUp", PageUp), ("PageDown", PageDown),
+        ("LShift", LShift), ("RShift", RShift), ("LControl", LControl), ("RControl", RControl), ("LAlt", LAlt), ("RAlt", RAlt),
+        ("Minus", Minus), ("Equals", Equals), ("Comma", Comma), ("Period", Period), ("Slash", Slash), ("Backslash", Backslash),
+        ("Semicolon", Semicolon), ("Apostrophe", Apostrophe), ("Grave", Grave), ("LBracket", LBracket), ("RBracket", RBracket),
+        ("Numpad0", Numpad0), ("Numpad1", Numpad1), ("Numpad2", Numpad2), ("Numpad3", Numpad3), ("Numpad4", Numpad4),
+        ("Numpad5", Numpad5), ("Numpad6", Numpad6), ("Numpad7", Numpad7), ("Numpad8", Numpad8), ("Numpad9", Numpad9),
+    ]
+};
+
+pub fn key_from_name(name: &str) -> Option<VirtualKeyCode> {
+    KEY_NAMES.iter().find(|(key_name, _)| key_name.eq_ignore_ascii_case(name)).map(|(_, key)| *key)
+}
+
+/// Keys that can't be written in the file are named by their Debug output.
+pub fn key_name(key: VirtualKeyCode) -> String {
+    KEY_NAMES.iter().find(|(_, k)| *k == key).map_or_else(|| format!("{:?}", key), |(name, _)| name.to_string())
+}
+
+#[test]
+fn key_bindings() {
+    use VirtualKeyCode::*;
+    let defaults = KeyBindings::default();
+    for action in Action::ALL {
+        assert!(!defaults.keys(action).is_empty(), "{:?} has no default key", action);
+    }
+
+    let text = "
+        # Arrow keys only
+        move_forward = Up
+        move_backward = Down  # trailing comments too
+        quit = q, F10
+        toggle_vsync =
+        fly = F
+        screenshot = F2, Banana
+        nonsense
+    ";
+    let (bindings, warnings) = KeyBindings::parse(text);
+    assert_eq!(warnings.len(), 3, "{:?}", warnings);
+    assert_eq!(bindings.action(W), None);
+    assert_eq!(bindings.keys(Action::MoveForward), vec![Up]);
+    assert_eq!(bindings.action(Down), Some(Action::MoveBackward));
+    assert_eq!(bindings.keys(Action::Quit), vec![F10, Q]);
+    assert_eq!(bindings.action(Escape), None);
+    assert!(bindings.keys(Action::ToggleVsync).is_empty());
+    assert_eq!(bindings.keys(Action::Screenshot), vec![F2]);
+    // Not in the file so still the default.
+    assert_eq!(bindings.keys(Action::MoveLeft), vec![A, Left]);
+
+    // Binding a key to something else takes it away from its default.
+    let (bindings, _) = KeyBindings::parse("cycle_camera = F6");
+    assert_eq!(bindings.action(F6), Some(Action::CycleCamera));
+    assert!(bindings.keys(Action::RecordPath).is_empty());
+
+    for (name, key) in KEY_NAMES {
+        assert_eq!(key_from_name(name), Some(*key));
+        assert_eq!(&key_name(*key), name);
+    }
+}
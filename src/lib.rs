@@ -4,28 +4,45 @@ pub mod chunk_mesh;
 pub mod gen;
 pub mod lua_api;
 pub mod pos;
+pub mod arena;
+pub mod renderer;
+pub mod raster;
+mod screenshot;
+mod selection;
+pub mod overlay;
 mod worldgen;
+mod meshing;
+pub mod biome;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod watch;
 mod entity_render;
+pub mod input;
+pub mod flythrough;
+pub mod profiler;
 
-use std::mem::size_of;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::rc::Rc;
 use instant::Instant;
-use wgpu::RenderPipeline;
-use winit::event::{DeviceEvent, KeyboardInput, VirtualKeyCode, WindowEvent};
+use glam::Vec3;
+use winit::dpi::PhysicalSize;
+use winit::event::{DeviceEvent, KeyboardInput, WindowEvent};
 use winit::event::ElementState::Pressed;
-use winit::window::CursorGrabMode;
 use crate::camera::{CameraController, CameraHandle, SpectatorCameraController};
-use crate::chunk_mesh::{ChunkList, TextureAtlas};
-use crate::pos::{BlockPos, Chunk, ChunkPos, LocalPos};
-use crate::window::{App, ModelVertex, Texture, WindowContext};
+use crate::flythrough::{Benchmark, CameraPath, PathRecorder};
+use crate::meshing::MeshWorkers;
+use crate::pos::{BlockPos, Chunk, CHUNK_SIZE, ChunkPos, LocalPos};
+use crate::renderer::{GpuRenderer, RecordingRenderer, RenderEvent, Renderer};
+use crate::window::{App, WindowContext};
 use common;
 use common::pos::Tile;
+use common::input::Action;
+use crate::input::KeyBindings;
 
 #[cfg(target_arch="wasm32")]
 use wasm_bindgen::prelude::*;
-use crate::entity_render::EntityRender;
 use crate::lua_api::lua::GameLogic;
-use crate::worldgen::LogicChunks;
+use crate::worldgen::{LogicChunks, RayHit};
 
 #[cfg(target_arch="wasm32")]
 #[cfg_attr(target_arch="wasm32", wasm_bindgen(start))]
@@ -37,69 +54,238 @@ pub async fn run(){
 }
 
 pub struct State {
-    ctx: Rc<WindowContext>,
-    depth_texture: Texture,
+    render: Box<dyn Renderer>,
     camera: CameraHandle,
-    render_pipeline: RenderPipeline,
-    chunks: ChunkList,
     controller: SpectatorCameraController,
-    atlas: Rc<TextureAtlas>,
     cursor_lock: bool,
+    debug_hud: bool,
+    /// The block in the middle of the screen, updated every frame.
+    target: Option<RayHit>,
     world: LogicChunks,
     logic: &'static GameLogic,
-    entities: EntityRender
+    bindings: KeyBindings,
+    /// Actions with a key down right now. Lua can ask about these.
+    held_actions: HashSet<Action>,
+    /// Set while Action::RecordPath is recording the camera's path.
+    recorder: Option<PathRecorder>,
+    /// Set while flying along a path from --benchmark.
+    benchmark: Option<Benchmark>,
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    assets: watch::FileWatcher,
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    shaders: watch::FileWatcher,
 }
 
 
-#[no_mangle]
-pub extern "C" fn add(a: i32, b: i32) -> i32 {
-    a + b
-}
-
-
-impl App for State {
-    fn new(ctx: Rc<WindowContext>) -> Self {
-        let atlas = Rc::new(TextureAtlas::new(&ctx));
-        let depth_texture = Texture::create_depth_texture(&ctx.device, &ctx.config.borrow(), "depth_texture");
-        let camera = CameraHandle::new(&ctx);
-
-        let info_bind_group_layout = ctx.bind_group_layout_buffer("mesh_info", &[
-            (wgpu::ShaderStages::VERTEX_FRAGMENT, wgpu::BufferBindingType::Uniform)
-        ]);
-
-        let render_pipeline_layout = ctx.pipeline_layout(&[
-            &camera.camera_bind_group_layout,
-            &info_bind_group_layout,
-            &atlas.layout
-        ]);
-
-        let render_pipeline = ctx.render_pipeline(
-            "main", &render_pipeline_layout, &[wgpu::VertexBufferLayout {
-                array_stride: size_of::<ModelVertex>() as wgpu::BufferAddress,
-                step_mode: wgpu::VertexStepMode::Vertex,
-                attributes: ModelVertex::ATTRIBS
-            }], include_str!("shader.wgsl")
-        );
-
-        let chunks = ChunkList::new(ctx.clone(), atlas.clone(), info_bind_group_layout);
+impl State {
+    /// How far away (in blocks) you can select things.
+    pub const REACH: f32 = 8.0;
 
+    pub fn with_renderer(render: Box<dyn Renderer>, camera: CameraHandle) -> Self {
         let logic = Box::new(GameLogic::new());
 
-        State {
-            entities: EntityRender::new(ctx.clone()),
-            ctx,
-            depth_texture,
+        let mut state = State {
+            render,
             camera,
-            render_pipeline,
-            chunks,
             controller: SpectatorCameraController::new(30.0, 0.4),
-            atlas,
             cursor_lock: true,
+            debug_hud: false,
+            target: None,
             world: LogicChunks::new(),
             logic: Box::leak(logic),  // Leaking this means you can pass the &mut self to function in lua since we're borrowing from the universe instead of ourself
+            bindings: KeyBindings::default(),
+            held_actions: HashSet::new(),
+            recorder: None,
+            benchmark: None,
+            #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+            assets: watch::FileWatcher::new("assets", "png"),
+            #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+            shaders: watch::FileWatcher::new(concat!(env!("CARGO_MANIFEST_DIR"), "/src"), "wgsl").only(GpuRenderer::CHUNK_SHADERS),
+        };
+        state.set_render_distance(state.render.render_distance());
+        state
+    }
+
+    /// No window or gpu. The world and lua still run but meshes are only recorded in the event log.
+    pub fn headless(events: Rc<RefCell<Vec<RenderEvent>>>) -> Self {
+        let camera = CameraHandle::new(PhysicalSize::new(1, 1));
+        let mut state = State::with_renderer(Box::new(RecordingRenderer::new(events)), camera);
+        // Meshed as soon as they're asked for so tests see the events without waiting.
+        state.world.meshing = MeshWorkers::new(0);
+        state
+    }
+
+    fn debug_text(&self) -> Vec<String> {
+        let stats = self.render.stats();
+        let pos = self.camera.camera.pos;
+        let chunk = BlockPos::vec(pos).chunk();
+        let [(vertices, vertex_capacity), (indices, index_capacity), (slots, slot_capacity)] = stats.arena;
+        // The smoothed frame time is 0 until the first frame has been timed.
+        let fps = if stats.frame_time_ms > 0.0 { 1000.0 / stats.frame_time_ms } else { 0.0 };
+        let mut lines = vec![
+            format!("{:.0} fps ({:.2} ms)", fps, stats.frame_time_ms),
+            format!("xyz: {:.2} / {:.2} / {:.2}", pos.x, pos.y, pos.z),
+            format!("chunk: {} {} {}", chunk.x, chunk.y, chunk.z),
+            format!("chunks: {} loaded, {} meshes, {} meshing, render distance {}", self.world.chunks.len(), stats.chunk_meshes, self.world.meshing.pending, self.render.render_distance()),
+            format!("pool: {}K/{}K vertices, {}K/{}K indices, {}/{} slots", vertices / 1024, vertex_capacity / 1024, indices / 1024, index_capacity / 1024, slots, slot_capacity),
+            format!("entities: {}", stats.entities),
+            format!("camera: {:?}", self.camera.mode),
+            match self.target {
+                Some(hit) => {
+                    let block = hit.block.as_vec();
+                    format!("target: {} {} {}, tile {}, {:?}", block.x, block.y, block.z, hit.tile.0, hit.face)
+                }
+                None => "target: none".to_string(),
+            },
+        ];
+        lines.extend(profiler::summary());
+        lines
+    }
+
+    /// In chunks. The camera's fog and far plane follow it and lua picks it up next tick.
+    pub fn set_render_distance(&mut self, distance: u32) {
+        let distance = distance.clamp(2, 32);
+        self.render.set_render_distance(distance);
+        self.camera.camera.set_render_distance((distance * CHUNK_SIZE as u32) as f32);
+    }
+
+    /// Keys are turned into actions by the KeyBindings. Holding a key repeats the press so toggles only react to the first one.
+    fn handle_action(&mut self, action: Action, pressed: bool) {
+        let first_press = if pressed { self.held_actions.insert(action) } else { self.held_actions.remove(&action); false };
+        self.controller.process_action(action, pressed);
+        if !first_press {
+            return;
+        }
+        match action {
+            Action::SlowDown => self.controller.speed /= 2.0,
+            Action::SpeedUp => self.controller.speed *= 2.0,
+            Action::ToggleCursor => {
+                self.cursor_lock = !self.cursor_lock;
+                self.render.set_cursor_lock(self.cursor_lock);
+                self.controller.frozen = !self.controller.frozen;
+            }
+            Action::ToggleDebugHud => {
+                self.debug_hud = !self.debug_hud;
+                if !self.debug_hud {
+                    self.render.set_debug_text(vec![]);
+                }
+            }
+            Action::Screenshot => self.render.screenshot(),
+            Action::CycleCamera => {
+                // Orbit around what you're looking at, or a point in front if that's nothing.
+                let camera = &self.camera.camera;
+                let target = match self.target {
+                    Some(hit) => hit.block.as_vec() + Vec3::splat(0.5),
+                    None => camera.pos + camera.facing() * State::REACH,
+                };
+                self.camera.cycle_mode(target);
+            }
+            Action::RecordPath => self.toggle_recording(),
+            Action::RenderDistanceDown => self.set_render_distance(self.render.render_distance() - 1),
+            Action::RenderDistanceUp => self.set_render_distance(self.render.render_distance() + 1),
+            #[cfg(feature = "profiling")]
+            Action::LogProfile => {
+                self.render.log_profile();
+                self.world.log_profile();
+                #[cfg(not(target_arch = "wasm32"))]
+                match profiler::save_trace(profiler::Profiler::TRACE_FILE) {
+                    Ok(_) => println!("Saved the last few seconds of frames to {}", profiler::Profiler::TRACE_FILE),
+                    Err(e) => println!("Failed to save {}: {}", profiler::Profiler::TRACE_FILE, e),
+                }
+            }
+            // The rest are movement for the controller or handled by the WindowContext.
+            _ => {}
+        }
+    }
+
+    /// RecordPath (F6) starts recording and pressing it again saves it to PathRecorder::FILE.
+    fn toggle_recording(&mut self) {
+        match self.recorder.take() {
+            None => {
+                self.recorder = Some(PathRecorder::new());
+                println!("Recording camera path.");
+            }
+            Some(recorder) => match recorder.finish().save(PathRecorder::FILE) {
+                Ok(_) => println!("Saved camera path to {}", PathRecorder::FILE),
+                Err(e) => println!("Failed to save camera path: {}", e),
+            }
+        }
+    }
+
+    /// Flies along a recorded path (ignoring input) then saves the frame times to Benchmark::OUTPUT and exits.
+    /// Must happen before lua loads any chunks so they all use the benchmark's seed.
+    pub fn start_benchmark(&mut self, file: &str) {
+        let path = match CameraPath::load(file) {
+            Ok(path) => path,
+            Err(e) => {
+                println!("Can't run benchmark: {}", e);
+                return;
+            }
+        };
+        self.world.seed = Benchmark::SEED;
+        // Random ticks and critter spawns would change what gets meshed.
+        self.logic.seed_random(Benchmark::SEED);
+        self.controller.frozen = true;
+        self.benchmark = Some(Benchmark::new(path, self.world.generated, self.render.stats().chunks_meshed));
+        println!("Running benchmark {}", file);
+    }
+
+    fn finish_benchmark(&mut self) {
+        let Some(benchmark) = self.benchmark.take() else { return };
+        let summary = benchmark.finish(self.world.generated, self.render.stats().chunks_meshed).to_json();
+        println!("{}", summary);
+        if let Err(e) = std::fs::write(Benchmark::OUTPUT, &summary) {
+            println!("Failed to save {}: {}", Benchmark::OUTPUT, e);
         }
     }
 
+    /// Rebuilds the atlas from the files in assets/ and remeshes every chunk. A broken file keeps the old textures.
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    fn reload_textures(&mut self) {
+        let start = Instant::now();
+        // The panic message was already printed by the hook.
+        let Ok(textures) = std::panic::catch_unwind(common::blocks::textures) else {
+            println!("Failed to reload textures.");
+            return;
+        };
+        // Tiles point at uvs by index so they'd all be wrong.
+        if textures.uvs.len() != gen::uvs::ALL.len() {
+            println!("Textures were added or removed. Rebuild to see them.");
+            return;
+        }
+        self.render.reload_textures(&textures);
+        self.world.remesh_all(self.render.as_mut());
+        println!("Reloaded textures in {:?}", Instant::now() - start);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+
+impl App for State {
+    fn new(ctx: Rc<WindowContext>) -> Self {
+        let camera = CameraHandle::new(*ctx.size.borrow());
+        let bindings = ctx.bindings.clone();
+        let render = GpuRenderer::new(ctx, &camera.camera);
+        let mut state = State::with_renderer(Box::new(render), camera);
+        state.bindings = bindings;
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(file) = std::env::args().skip_while(|arg| arg != "--benchmark").nth(1) {
+            state.start_benchmark(&file);
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(mb) = std::env::args().skip_while(|arg| arg != "--mesh-budget").nth(1) {
+            match mb.parse::<u64>() {
+                Ok(mb) => state.render.set_mesh_budget(mb * 1024 * 1024),
+                Err(_) => println!("--mesh-budget wants a number of MB, not {:?}", mb),
+            }
+        }
+        state
+    }
+
     fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
         self.controller.handle_window_event(event);
 
@@ -113,27 +299,8 @@ impl App for State {
 
             ..
         } = event {
-            match key {
-                VirtualKeyCode::Key1 => self.controller.speed /= 2.0,
-                VirtualKeyCode::Key2 => self.controller.speed *= 2.0,
-                VirtualKeyCode::Tab => if *state == Pressed {
-                    self.cursor_lock = !self.cursor_lock;
-                    if self.cursor_lock {
-                        let _ = self.ctx.window.set_cursor_grab(CursorGrabMode::Locked);
-                        self.ctx.window.set_cursor_visible(false);
-                    } else {
-                        let _ = self.ctx.window.set_cursor_grab(CursorGrabMode::None);
-                        self.ctx.window.set_cursor_visible(true);
-                    }
-                    self.controller.frozen = !self.controller.frozen;
-                }
-                #[cfg(feature = "profiling")]
-                VirtualKeyCode::P => if *state == Pressed {
-                    self.chunks.log_profile();
-                    self.world.log_profile();
-                    self.entities.log_profile();
-                }
-                _ => {  }
+            if let Some(action) = self.bindings.action(*key) {
+                self.handle_action(action, *state == Pressed);
             }
         };
 
@@ -144,42 +311,56 @@ impl App for State {
         self.controller.handle_device_event(event);
     }
 
-    fn update(&mut self) {
+    fn update(&mut self) -> bool {
+        profiler::end_frame();
         let dt = Instant::now() - self.controller.last_update;
-        self.controller.update(&self.ctx, &mut self.camera);
-        self.logic.run_tick(self, dt);
+        self.controller.update(&mut self.camera);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(&self.camera.camera);
+        }
+        if let Some(benchmark) = &mut self.benchmark {
+            if !benchmark.frame(&mut self.camera.camera) {
+                self.finish_benchmark();
+                return false;
+            }
+        }
+        {
+            let _span = profiler::span("lua_tick");
+            self.logic.run_tick(self, dt);
+        }
+        // Lua only asks for meshes on ticks but the workers can finish any frame.
+        self.world.finish_meshes(self.render.as_mut());
+        let evicted = self.render.take_evicted();
+        if !evicted.is_empty() {
+            self.world.remesh(&evicted, self.render.as_mut());
+        }
+        #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+        if self.assets.changed() {
+            self.reload_textures();
+        }
+        #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+        if self.shaders.changed() {
+            self.render.reload_shaders();
+        }
+        let camera = &self.camera.camera;
+        self.target = self.world.raycast(camera.pos, camera.facing(), State::REACH);
+        self.render.set_selection(self.target.map(|hit| hit.block));
+        let world = &self.world;
+        self.camera.update_view(dt.as_secs_f32(), |pos, dir, max| world.raycast(pos, dir, max).map(|hit| hit.distance));
+        if self.debug_hud {
+            let text = self.debug_text();
+            self.render.set_debug_text(text);
+        }
+        true
     }
 
     fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
-        let mut encoder = self.ctx.command_encoder("render");
-
-        let output = self.ctx.surface.get_current_texture()?;
-        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
-
-        {
-            let mut render_pass = self.ctx.render_pass(&mut encoder, &view, &self.depth_texture.view);
-            render_pass.set_pipeline(&self.render_pipeline);
-
-            render_pass.set_bind_group(0, &self.camera.camera_bind_group, &[]);
-            render_pass.set_bind_group(2, &self.atlas.bind_group, &[]);
-            self.chunks.render(&mut render_pass, BlockPos::vec(self.camera.camera.pos).chunk());
-            self.entities.render(&mut render_pass);
-
-        };
-
-        self.ctx.queue.submit([
-            encoder.finish()
-        ]);
-
-        output.present();
-
-        Ok(())
+        self.render.render(&self.camera.view, self.logic)
     }
 
-    fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
-        if self.ctx.resize(&new_size) {
-            self.depth_texture = Texture::create_depth_texture(&self.ctx.device, &self.ctx.config.borrow(), "depth_texture");
-            self.camera.camera.resize(new_size.width, new_size.height);
+    fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        if self.render.resize(new_size) {
+            self.camera.resize(new_size.width, new_size.height);
         }
     }
 }
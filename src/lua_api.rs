@@ -1,21 +1,21 @@
-use crate::pos::{BlockPos, Chunk, ChunkPos};
-use crate::{gen, State};
+use crate::pos::{BlockPos, Chunk, ChunkPos, LocalPos};
+use crate::State;
 use std::hint::black_box;
 use std::sync::atomic::{AtomicIsize, Ordering};
 use common::pos::Tile;
 use std::alloc::{GlobalAlloc, Layout};
 use std::ptr;
-use glam::{Mat4, Vec3};
-use crate::worldgen::generate;
+use glam::Vec3;
+use crate::worldgen::{generate, LogicChunks};
 use instant::Duration;
-use crate::entity_render::EntityInfo;
-use crate::window::{App, ref_to_bytes};
+use common::input::Action;
 
 #[cfg(not(target_arch = "wasm32"))]
 pub mod lua {
     use mlua::{Function, LightUserData, Lua};
     use std::ffi::c_void;
     use std::time::Duration;
+    use common::anim::Query;
     use crate::State;
 
     pub struct GameLogic {
@@ -39,6 +39,19 @@ pub mod lua {
                 panic!("{}", e);
             });
         }
+
+        /// Makes math.random give the same numbers every run.
+        pub fn seed_random(&self, seed: u32) {
+            self.lua.load(format!("math.randomseed({})", seed)).exec().unwrap();
+        }
+
+        /// Runs one of gen::animations::EXPRESSIONS.
+        pub fn eval_expression(&self, index: usize, query: Query) -> f32 {
+            let eval: Function = self.lua.globals().get("eval_expression").unwrap();
+            eval.call((index, query.anim_time, query.life_time)).unwrap_or_else(|e| {
+                panic!("{}: {}", crate::gen::animations::EXPRESSIONS[index], e);
+            })
+        }
     }
 }
 
@@ -48,6 +61,7 @@ pub mod lua {
     use crate::State;
     use wasm_bindgen::prelude::*;
     use instant::Duration;
+    use common::anim::Query;
 
     pub struct GameLogic {}
 
@@ -60,11 +74,20 @@ pub mod lua {
             let pos = state.camera.camera.pos;
             run_tick(state, pos.x, pos.y, pos.z, dt.as_secs_f32());
         }
+
+        // Math.random can't be seeded. Only --benchmark needs it and that's native only.
+        pub fn seed_random(&self, _: u32) {}
+
+        /// Runs one of gen::animations::EXPRESSIONS.
+        pub fn eval_expression(&self, index: usize, query: Query) -> f32 {
+            eval_expression(index as u32, query.anim_time, query.life_time)
+        }
     }
 
     #[wasm_bindgen]
     extern "C" {
         fn run_tick(state: *mut State, playerx: f32, playery: f32, playerz: f32, dt_sec: f32);
+        fn eval_expression(index: u32, anim_time: f32, life_time: f32) -> f32;
     }
 }
 
@@ -127,24 +150,39 @@ pub extern "C" fn random_chunk(state: &mut State) -> *mut Chunk {
 #[no_mangle]
 pub extern "C" fn unload_chunk(state: &mut State, x: i32, y: i32, z: i32) {
     let pos = ChunkPos::new(x, y, z);
-    state.chunks.remove(pos);
+    state.render.remove_chunk(pos);
     state.world.chunks.remove(&pos);
 }
 
 #[no_mangle]
 pub extern "C" fn get_chunk(state: &mut State, x: i32, y: i32, z: i32) -> *mut Chunk {
     let pos = ChunkPos::new(x, y, z);
-    state.world.get_or_gen(pos, &mut state.chunks)
+    state.world.get_or_gen(pos, state.render.as_mut())
 }
 
 #[no_mangle]
 pub extern "C" fn update_mesh(state: &mut State) {
-    state.world.update_meshes(&mut state.chunks);
+    state.world.update_meshes(state.render.as_mut());
+}
+
+#[no_mangle]
+pub extern "C" fn render_distance(state: &mut State) -> i32 {
+    state.render.render_distance() as i32
+}
+
+#[no_mangle]
+pub extern "C" fn unload_distance(state: &mut State) -> i32 {
+    LogicChunks::unload_distance(state.render.as_ref()) as i32
+}
+
+#[no_mangle]
+pub extern "C" fn take_screenshot(state: &mut State) {
+    state.render.screenshot();
 }
 
 #[no_mangle]
 pub extern "C" fn gc_chunks(state: &mut State, x: i32, y: i32, z: i32) {
-    state.world.gc(BlockPos::new(x, y, z), &mut state.chunks);
+    state.world.gc(BlockPos::new(x, y, z), state.render.as_mut());
 }
 
 // TODO: fix my lua transpiler so i can access fields and not write this stupid boilerplate.
@@ -155,7 +193,7 @@ pub extern "C" fn chunk_set_block(chunk: &mut Chunk, index: u32, tile: u32) -> u
     let new = Tile(tile as u16);
     chunk.tiles[index as usize] = new;
     if old != new {
-        chunk.dirty.set(true);
+        chunk.touch(LocalPos::from_index(index as usize));
         1
     } else {
         0
@@ -169,40 +207,27 @@ pub extern "C" fn chunk_get_block(chunk: &mut Chunk, index: u32) -> u32 {
 
 #[no_mangle]
 pub extern "C" fn render_entity(state: &mut State, id: i32, ty: i32, x: f32, y: f32, z: f32) {
-    state.entities.update(id, |ctx, info| {
-        match ty {
-            1 => {  // FallingBlock
-                let transform =  Mat4::from_translation(Vec3::new(x, y, z));
-                match info {
-                    EntityInfo::None => {
-                        let builder = &mut state.chunks.builder;
-                        builder.clear();
-                        builder.add_cube(gen::tiles::stone, Vec3::new(0f32, 0f32, 0f32), true, true, true, true, true, true);
-                        let builder = &state.chunks.builder;
-                        let mesh = match state.chunks.mesh_pool.pop() {
-                            None => state.chunks.init_mesh(&builder.vert, &builder.indi, transform),
-                            Some(mut mesh) => {
-                                state.chunks.reuse_mesh(&mut mesh, &builder.vert, &builder.indi, transform);
-                                mesh
-                            }
-                        };
-                        *info = EntityInfo::SingleMesh(mesh);
-                    }
-                    EntityInfo::SingleMesh(mesh) => {
-                        mesh.transform.transform = transform.to_cols_array_2d();
-                        ctx.write_buffer(&mesh.info_buffer, ref_to_bytes(&mesh.transform));
-                    }
-                }
-            }
-            _ => debug_assert!(false, "Invalid entity type {}, id={}", ty, id),
-        }
-    });
+    state.render.update_entity(id, ty, Vec3::new(x, y, z));
 }
 
 
 #[no_mangle]
 pub extern "C" fn forget_entity(state: &mut State, id: i32) {
-    state.entities.remove(&mut state.chunks, id);
+    state.render.remove_entity(id);
+}
+
+/// Indexes into gen::models::ALL and gen::animations::ALL. Negative animations means it doesn't have any.
+#[no_mangle]
+pub extern "C" fn register_entity_type(state: &mut State, ty: i32, model: i32, animations: i32) {
+    let animations = if animations < 0 { None } else { Some(animations as usize) };
+    state.render.register_entity_type(ty, model as usize, animations);
+}
+
+/// Index into gen.actions. Returns 1 while a key bound to it is held.
+#[no_mangle]
+pub extern "C" fn action_held(state: &mut State, action: i32) -> i32 {
+    let action = usize::try_from(action).ok().and_then(Action::from_index);
+    action.map_or(0, |action| state.held_actions.contains(&action) as i32)
 }
 
 pub fn reference_extern() {
@@ -216,8 +241,13 @@ pub fn reference_extern() {
         lua_alloc as _,
         random_chunk as _,
         gc_chunks as _,
+        render_distance as _,
+        unload_distance as _,
+        take_screenshot as _,
         render_entity as _,
         forget_entity as _,
+        register_entity_type as _,
+        action_held as _,
     ];
     black_box(funcs);
 }
@@ -0,0 +1,166 @@
+use std::sync::mpsc::{channel, Receiver, Sender};
+use crate::chunk_mesh::{ChunkGeometry, MeshBuilder, Neighbours};
+use crate::pos::{Chunk, ChunkPos};
+use crate::profiler;
+
+/// Everything needed to mesh a chunk without looking at the world, so it can be done on another thread.
+pub struct MeshJob {
+    pub pos: ChunkPos,
+    /// Chunk::mesh_version when the copy was taken.
+    pub version: u64,
+    pub chunk: Box<Chunk>,
+    pub neighbours: Neighbours,
+}
+
+pub struct MeshResult {
+    pub pos: ChunkPos,
+    pub version: u64,
+    pub layers: ChunkGeometry,
+}
+
+impl MeshJob {
+    fn run(self, builder: &mut MeshBuilder) -> MeshResult {
+        let _span = profiler::span("meshing");
+        builder.build_chunk(&self.chunk, &self.neighbours);
+        MeshResult {
+            pos: self.pos,
+            version: self.version,
+            layers: builder.take(),
+        }
+    }
+}
+
+/// Builds chunk meshes on background threads. Results come back in whatever order they finish.
+/// With no threads (always on wasm) jobs are done straight away when they're sent.
+pub struct MeshWorkers {
+    jobs: Option<Sender<MeshJob>>,
+    finished: Sender<MeshResult>,
+    results: Receiver<MeshResult>,
+    /// For meshing on this thread.
+    builder: MeshBuilder,
+    /// Sent but not back yet.
+    pub pending: usize,
+}
+
+impl MeshWorkers {
+    pub fn new(threads: usize) -> Self {
+        let (finished, results) = channel();
+        let jobs = if threads == 0 { None } else { Some(Self::spawn(threads, &finished)) };
+        MeshWorkers {
+            jobs,
+            finished,
+            results,
+            builder: MeshBuilder::new(),
+            pending: 0,
+        }
+    }
+
+    /// Leaves a core for the main thread.
+    pub fn default_threads() -> usize {
+        if cfg!(target_arch = "wasm32") {
+            0
+        } else {
+            std::thread::available_parallelism().map_or(1, |cores| cores.get().saturating_sub(1)).clamp(1, 4)
+        }
+    }
+
+    // The workers share one queue so a slow chunk doesn't hold up the rest.
+    // They stop when the MeshWorkers is dropped since that drops the only Sender.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn spawn(threads: usize, finished: &Sender<MeshResult>) -> Sender<MeshJob> {
+        use std::sync::{Arc, Mutex};
+        let (jobs, queue) = channel::<MeshJob>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = queue.clone();
+            let finished = finished.clone();
+            std::thread::Builder::new().name(format!("mesh{}", i)).spawn(move || {
+                let mut builder = MeshBuilder::new();
+                loop {
+                    let job = queue.lock().unwrap().recv();
+                    let Ok(job) = job else { break };
+                    if finished.send(job.run(&mut builder)).is_err() {
+                        break;
+                    }
+                }
+            }).unwrap();
+        }
+        jobs
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn spawn(_: usize, _: &Sender<MeshResult>) -> Sender<MeshJob> {
+        unreachable!("no threads on wasm")
+    }
+
+    pub fn submit(&mut self, job: MeshJob) {
+        self.pending += 1;
+        let job = match &self.jobs {
+            Some(jobs) => match jobs.send(job) {
+                Ok(()) => return,
+                // Every worker died (they only stop on a panic) so do it here instead.
+                Err(failed) => failed.0,
+            },
+            None => job,
+        };
+        self.finished.send(job.run(&mut self.builder)).unwrap();
+    }
+
+    /// Everything that's done since last time. Doesn't wait.
+    pub fn finished(&mut self) -> Vec<MeshResult> {
+        let results: Vec<_> = self.results.try_iter().collect();
+        self.pending -= results.len();
+        results
+    }
+}
+
+#[test]
+fn mesh_workers() {
+    use instant::{Duration, Instant};
+    use crate::gen;
+    use crate::pos::{Direction, LocalPos};
+
+    let job = |x: i32| {
+        let mut chunk = Chunk::full(gen::tiles::empty, ChunkPos::new(x, 0, 0));
+        chunk.set(LocalPos::new(3, 4, 5), gen::tiles::stone);
+        MeshJob { pos: chunk.pos, version: x as u64, chunk: Box::new(chunk), neighbours: Default::default() }
+    };
+
+    let mut inline = MeshWorkers::new(0);
+    inline.submit(job(0));
+    assert_eq!(inline.pending, 1);
+    let expected = inline.finished();
+    assert_eq!(inline.pending, 0);
+    assert_eq!(expected.len(), 1);
+
+    let mut threads = MeshWorkers::new(2);
+    for x in 1..=10 {
+        threads.submit(job(x));
+    }
+    let start = Instant::now();
+    let mut results = vec![];
+    while results.len() < 10 {
+        assert!(start.elapsed() < Duration::from_secs(10), "Workers never finished.");
+        results.extend(threads.finished());
+        std::thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(threads.pending, 0);
+    results.sort_by_key(|result| result.version);
+    for (x, result) in (1..=10).zip(results) {
+        assert_eq!(result.pos, ChunkPos::new(x, 0, 0));
+        for (a, b) in result.layers.iter().zip(expected[0].layers.iter()) {
+            assert_eq!(a.indi, b.indi);
+            assert_eq!(a.vert.len(), b.vert.len());
+        }
+    }
+
+    // A block on the edge hides its face against a loaded neighbour but not an unloaded one.
+    let mut edge = job(0);
+    edge.chunk.set(LocalPos::new(15, 4, 5), gen::tiles::stone);
+    let faces = |job: MeshJob| job.run(&mut MeshBuilder::new()).layers.iter().map(|layer| layer.indi.len() / 6).sum::<usize>();
+    let mut covered = job(0);
+    covered.chunk.set(LocalPos::new(15, 4, 5), gen::tiles::stone);
+    covered.neighbours[Direction::North as usize] = Some(Box::new(Chunk::full(gen::tiles::stone, ChunkPos::new(1, 0, 0))));
+    assert_eq!(faces(edge), 12);
+    assert_eq!(faces(covered), 11);
+}
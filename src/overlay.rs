@@ -0,0 +1,236 @@
+use std::mem::size_of;
+use std::rc::Rc;
+use glam::{Mat4, Vec2};
+use wgpu::{BindGroup, Buffer, BufferUsages, RenderPass, RenderPipeline};
+use crate::window::{OverlayVertex, PipelineOptions, ref_to_bytes, slice_to_bytes, WindowContext};
+
+/// Flat 2D shapes drawn on top of the world. Positions are in pixels from the top left of the window.
+/// It's tiny so the whole thing is rebuilt and uploaded every frame.
+pub struct Overlay {
+    ctx: Rc<WindowContext>,
+    pipeline: RenderPipeline,
+    projection: Buffer,
+    bind_group: BindGroup,
+    vertices: Buffer,
+    count: u32,
+    pub builder: OverlayBuilder,
+}
+
+impl Overlay {
+    const START_VERTICES: u64 = 1 << 14;
+
+    pub fn new(ctx: Rc<WindowContext>) -> Self {
+        let layout = ctx.bind_group_layout_buffer("overlay", &[
+            (wgpu::ShaderStages::VERTEX, wgpu::BufferBindingType::Uniform)
+        ]);
+        let projection = ctx.buffer_init(
+            "overlay_projection", ref_to_bytes(&Mat4::IDENTITY.to_cols_array_2d()),
+            BufferUsages::UNIFORM | BufferUsages::COPY_DST
+        );
+        let bind_group = ctx.bind_group("overlay", &layout, &[
+            projection.as_entire_binding()
+        ]);
+
+        let pipeline = ctx.render_pipeline(
+            "Overlay", &ctx.pipeline_layout(&[&layout]), &[wgpu::VertexBufferLayout {
+                array_stride: size_of::<OverlayVertex>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: OverlayVertex::ATTRIBS
+            }], include_str!("overlay.wgsl"), PipelineOptions::OVERLAY
+        );
+
+        Overlay {
+            vertices: Self::vertex_buffer(&ctx, Self::START_VERTICES),
+            ctx,
+            pipeline,
+            projection,
+            bind_group,
+            count: 0,
+            builder: OverlayBuilder::default(),
+        }
+    }
+
+    fn vertex_buffer(ctx: &WindowContext, count: u64) -> Buffer {
+        ctx.buffer_empty("overlay_vertex", count * size_of::<OverlayVertex>() as u64, BufferUsages::VERTEX | BufferUsages::COPY_DST)
+    }
+
+    /// Sends whatever is in the builder to the gpu. Call before the render pass.
+    pub fn upload(&mut self) {
+        let size = *self.ctx.size.borrow();
+        let projection = Mat4::orthographic_rh(0.0, size.width as f32, size.height as f32, 0.0, -1.0, 1.0);
+        self.ctx.write_buffer(&self.projection, ref_to_bytes(&projection.to_cols_array_2d()));
+
+        let vert = &self.builder.vert;
+        self.count = vert.len() as u32;
+        if vert.is_empty() {
+            return;
+        }
+        if (vert.len() * size_of::<OverlayVertex>()) as u64 > self.vertices.size() {
+            self.vertices = Self::vertex_buffer(&self.ctx, (vert.len() as u64).next_power_of_two());
+        }
+        self.ctx.queue.write_buffer(&self.vertices, 0, slice_to_bytes(vert));
+    }
+
+    /// Call last so it's on top of everything.
+    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        if self.count == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertices.slice(..));
+        render_pass.draw(0..self.count, 0..1);
+    }
+}
+
+#[derive(Default)]
+pub struct OverlayBuilder {
+    vert: Vec<OverlayVertex>,
+}
+
+impl OverlayBuilder {
+    pub fn clear(&mut self) {
+        self.vert.clear();
+    }
+
+    pub fn rect(&mut self, pos: Vec2, size: Vec2, colour: [u8; 4]) {
+        let top_right = pos + Vec2::new(size.x, 0.0);
+        let bottom_left = pos + Vec2::new(0.0, size.y);
+        for corner in [pos, top_right, bottom_left, top_right, pos + size, bottom_left] {
+            self.vert.push(OverlayVertex {
+                position: corner.to_array(),
+                colour,
+            });
+        }
+    }
+
+    /// Each pixel of the font becomes a square `scale` pixels wide. Lower case letters are drawn as upper case.
+    pub fn text(&mut self, pos: Vec2, scale: f32, text: &str, colour: [u8; 4]) {
+        let mut cursor = pos;
+        for c in text.chars() {
+            if c == '\n' {
+                cursor = Vec2::new(pos.x, cursor.y + font::LINE_HEIGHT * scale);
+                continue;
+            }
+            for (row, bits) in font::glyph(c).iter().enumerate() {
+                for col in 0..font::WIDTH {
+                    if (bits >> (font::WIDTH - 1 - col)) & 1 == 1 {
+                        self.rect(cursor + Vec2::new(col as f32, row as f32) * scale, Vec2::splat(scale), colour);
+                    }
+                }
+            }
+            cursor.x += font::ADVANCE * scale;
+        }
+    }
+
+    /// A plus in the middle of the screen. Drawn with an outline so it shows up on both light and dark blocks.
+    pub fn crosshair(&mut self, centre: Vec2, scale: f32) {
+        let (length, width) = (scale * 5.0, scale);
+        for (grow, colour) in [(scale * 0.5, [0, 0, 0, 160]), (0.0, [255, 255, 255, 220])] {
+            let horizontal = Vec2::new(length, width) + grow * 2.0;
+            let vertical = Vec2::new(width, length) + grow * 2.0;
+            self.rect((centre - horizontal / 2.0).round(), horizontal, colour);
+            self.rect((centre - vertical / 2.0).round(), vertical, colour);
+        }
+    }
+
+    /// Lines of text on a dark background so they're readable over the world.
+    pub fn panel(&mut self, pos: Vec2, scale: f32, lines: &[String]) {
+        let padding = scale * 2.0;
+        let mut y = pos.y;
+        for line in lines {
+            let size = font::measure(line, scale);
+            self.rect(Vec2::new(pos.x, y), size + padding * 2.0, [0, 0, 0, 120]);
+            self.text(Vec2::new(pos.x + padding, y + padding), scale, line, [255, 255, 255, 255]);
+            y += size.y + padding * 2.0;
+        }
+    }
+}
+
+/// A tiny 5x7 bitmap font. Only has upper case letters, digits and some punctuation.
+pub mod font {
+    use glam::Vec2;
+
+    pub const WIDTH: u8 = 5;
+    pub const HEIGHT: f32 = 7.0;
+    pub const ADVANCE: f32 = 6.0;
+    pub const LINE_HEIGHT: f32 = 9.0;
+
+    /// Size in pixels.
+    pub fn measure(text: &str, scale: f32) -> Vec2 {
+        let widest = text.lines().map(|line| line.chars().count()).max().unwrap_or(0);
+        let lines = text.lines().count().max(1);
+        let width = (widest as f32 * ADVANCE - (ADVANCE - WIDTH as f32)).max(0.0);
+        let height = (lines - 1) as f32 * LINE_HEIGHT + HEIGHT;
+        Vec2::new(width, height) * scale
+    }
+
+    /// Rows from top to bottom. The highest of the 5 bits is the left pixel. Anything unknown is a question mark.
+    pub fn glyph(c: char) -> [u8; 7] {
+        match c.to_ascii_uppercase() {
+            ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+            '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+            '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+            '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+            '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+            '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+            '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+            '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+            '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+            '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+            '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+            'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+            'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+            'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+            'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+            'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+            'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+            'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+            'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+            'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+            'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+            'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+            'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+            'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+            'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+            'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+            'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+            'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+            'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+            'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+            'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+            'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+            'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+            'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+            'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+            'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+            'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+            '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+            ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+            ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+            '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+            '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+            '=' => [0x00, 0x00, 0x1F, 0x00, 0x1F, 0x00, 0x00],
+            '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+            '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+            ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+            '%' => [0x18, 0x19, 0x02, 0x04, 0x08, 0x13, 0x03],
+            '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+            '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+            _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],  // ?
+        }
+    }
+}
+
+#[test]
+fn text_quads() {
+    let mut overlay = OverlayBuilder::default();
+    overlay.text(Vec2::ZERO, 2.0, "1 -", [255; 4]);
+    // One quad for each pixel: 10 in the 1, nothing for the space and 5 in the dash.
+    assert_eq!(overlay.vert.len(), 15 * 6);
+    let dash = &overlay.vert[10 * 6..];
+    assert_eq!(dash[0].position, [2.0 * font::ADVANCE * 2.0, 3.0 * 2.0]);
+
+    assert_eq!(font::measure("1 -", 2.0), Vec2::new(17.0 * 2.0, 7.0 * 2.0));
+    assert_eq!(font::measure("a\nbc", 1.0), Vec2::new(11.0, 16.0));
+}
@@ -0,0 +1,31 @@
+// Flat coloured shapes on top of the world. See Overlay in overlay.rs.
+
+struct OverlayUniform {
+    projection: mat4x4<f32>,  // Pixels to clip space.
+};
+
+@group(0) @binding(0)
+var<uniform> overlay: OverlayUniform;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) colour: vec4<f32>,
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) colour: vec4<f32>,
+}
+
+@vertex
+fn vs_main(model: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = overlay.projection * vec4<f32>(model.position, 0.0, 1.0);
+    out.colour = model.colour;
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    return in.colour;
+}
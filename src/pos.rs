@@ -1,6 +1,7 @@
 use std::cell::Cell;
 use glam::Vec3;
 use common::pos::Tile;
+use crate::biome::Biome;
 
 pub const CHUNK_SIZE: usize = 16;
 
@@ -33,11 +34,17 @@ pub struct ChunkPos {
 pub struct Chunk {
     pub(crate) pos: ChunkPos,
     pub tiles: [Tile; Chunk::LENGTH],
-    pub dirty: Cell<bool>
+    pub dirty: Cell<bool>,
+    /// One for each column, indexed by x then z. After dirty so lua's definition of the struct still lines up.
+    pub biomes: [Biome; CHUNK_SIZE * CHUNK_SIZE],
+    /// Sides with a block changed since the last mesh. The neighbour there gets remeshed too since its faces might be uncovered.
+    pub(crate) edges: Cell<DirSet>,
+    /// Which mesh job has the latest tiles (see LogicChunks::update_meshes).
+    pub(crate) mesh_version: u64,
 }
 
 impl Chunk {
-    const LENGTH: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+    pub const LENGTH: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
 
     // TODO: dont return by value
     pub fn full(tile: Tile, pos: ChunkPos) -> Self {
@@ -45,6 +52,9 @@ impl Chunk {
             pos,
             tiles: [tile; Self::LENGTH],
             dirty: Cell::new(true),
+            biomes: [Biome::default(); CHUNK_SIZE * CHUNK_SIZE],
+            edges: Cell::new(DirSet::empty()),
+            mesh_version: 0,
         }
     }
 
@@ -53,9 +63,32 @@ impl Chunk {
     }
 
     pub fn set(&mut self, pos: LocalPos, block: Tile) {
-        self.dirty.set(true);
+        self.touch(pos);
         self.tiles[pos.0] = block;
     }
+
+    /// Marks it for remeshing after the block at pos changed.
+    pub fn touch(&self, pos: LocalPos) {
+        self.dirty.set(true);
+        let (x, y, z) = pos.xyz();
+        let last = CHUNK_SIZE - 1;
+        let mut edges = self.edges.get();
+        for (on_edge, dir) in [(y == last, Direction::Up), (y == 0, Direction::Down), (x == last, Direction::North), (x == 0, Direction::South), (z == last, Direction::East), (z == 0, Direction::West)] {
+            if on_edge {
+                edges.add(dir);
+            }
+        }
+        self.edges.set(edges);
+    }
+
+    pub fn biome(&self, x: usize, z: usize) -> Biome {
+        self.biomes[x * CHUNK_SIZE + z]
+    }
+
+    pub fn set_biome(&mut self, x: usize, z: usize, biome: Biome) {
+        self.dirty.set(true);
+        self.biomes[x * CHUNK_SIZE + z] = biome;
+    }
 }
 
 impl LocalPos {
@@ -65,6 +98,16 @@ impl LocalPos {
         LocalPos((y * CHUNK_SIZE * CHUNK_SIZE) + (x * CHUNK_SIZE) + z)
     }
 
+    /// Same order as Chunk::tiles.
+    pub fn from_index(index: usize) -> LocalPos {
+        debug_assert!(index < Chunk::LENGTH);
+        LocalPos(index)
+    }
+
+    pub fn xyz(self) -> (usize, usize, usize) {
+        (((self.0 / CHUNK_SIZE) % CHUNK_SIZE), self.0 / CHUNK_SIZE / CHUNK_SIZE, self.0 % CHUNK_SIZE)
+    }
+
     // TODO: I like the idea of these fitting in a register but maybe its really dumb since now
     //       I have to do a bunch of work to actually use them.
     pub fn normalized(self) -> Vec3 {
@@ -84,6 +127,12 @@ impl ChunkPos {
         ChunkPos { x, y, z }
     }
 
+    /// The chunk next to this one.
+    pub fn offset(&self, dir: Direction) -> ChunkPos {
+        let offset = dir.offset();
+        ChunkPos::new(self.x + offset.x, self.y + offset.y, self.z + offset.z)
+    }
+
     pub fn axis_distance(&self, other: &ChunkPos) -> u32 {
         (self.x.abs_diff(other.x).max(self.y.abs_diff(other.y)).max(self.z.abs_diff(other.z)))
     }
@@ -109,8 +158,28 @@ impl BlockPos {
     pub fn local(&self) -> LocalPos {
         LocalPos::new((self.x.unsigned_abs() % CHUNK_SIZE as u32) as usize, (self.y.unsigned_abs() % CHUNK_SIZE as u32) as usize, (self.z.unsigned_abs() % CHUNK_SIZE as u32) as usize)
     }
+
+    /// The block a point is inside. Rounds down so negative positions work (vec rounds towards zero).
+    pub fn containing(pos: Vec3) -> BlockPos {
+        let pos = pos.floor();
+        BlockPos::new(pos.x as i32, pos.y as i32, pos.z as i32)
+    }
+
+    /// The chunk and local position the block is drawn at (meshes go at ChunkPos * CHUNK_SIZE). Rounds down so negative positions work, unlike local().
+    pub fn split(&self) -> (ChunkPos, LocalPos) {
+        let size = CHUNK_SIZE as i32;
+        let chunk = ChunkPos::new(self.x.div_euclid(size), self.y.div_euclid(size), self.z.div_euclid(size));
+        let local = LocalPos::new(self.x.rem_euclid(size) as usize, self.y.rem_euclid(size) as usize, self.z.rem_euclid(size) as usize);
+        (chunk, local)
+    }
+
+    /// The corner with the smallest coordinates.
+    pub fn as_vec(&self) -> Vec3 {
+        Vec3::new(self.x as f32, self.y as f32, self.z as f32)
+    }
 }
 #[repr(u8)]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum Direction {
     Up = 0,
     Down = 1,
@@ -125,6 +194,8 @@ pub enum Direction {
 pub struct DirSet(u8);
 
 impl Direction {
+    pub const ALL: [Direction; 6] = [Direction::Up, Direction::Down, Direction::North, Direction::South, Direction::East, Direction::West];
+
     pub fn offset(self) -> BlockPos {
         const OFFSETS: [BlockPos; 6] = [
             BlockPos::new(0, 1, 0),
@@ -0,0 +1,227 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use instant::{Duration, Instant};
+
+/// Spans are only recorded with the profiling feature. Without it span() is just an Option check.
+const ENABLED: bool = cfg!(feature = "profiling");
+
+/// Shared by every thread so mesh workers show up too. Their spans land in whatever frame is current when they finish.
+static PROFILER: Mutex<Profiler> = Mutex::new(Profiler::new());
+static NEXT_THREAD: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    // Small numbers are nicer than ThreadId in the trace viewer.
+    static THREAD: u32 = NEXT_THREAD.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Debug)]
+pub struct Span {
+    pub name: &'static str,
+    pub thread: u32,
+    pub start: Instant,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct Frame {
+    pub start: Instant,
+    pub duration: Duration,
+    /// The thread that ended the frame.
+    pub thread: u32,
+    pub spans: Vec<Span>,
+}
+
+/// Keeps the spans of the last few frames. Use the free functions to get the global one.
+pub struct Profiler {
+    frames: VecDeque<Frame>,
+    current: Vec<Span>,
+    frame_start: Option<Instant>,
+    threads: Vec<(u32, String)>,
+}
+
+/// Records the time from span() until it's dropped.
+#[must_use]
+pub struct SpanGuard {
+    name: &'static str,
+    start: Option<Instant>,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            global().record(self.name, start, start.elapsed());
+        }
+    }
+}
+
+/// Times everything until the guard goes out of scope. Spans can nest.
+pub fn span(name: &'static str) -> SpanGuard {
+    SpanGuard { name, start: ENABLED.then(Instant::now) }
+}
+
+/// Finishes the last frame and starts the next. Call once a frame.
+pub fn end_frame() {
+    if ENABLED {
+        global().end_frame(Instant::now());
+    }
+}
+
+/// A few lines for the debug hud.
+pub fn summary() -> Vec<String> {
+    global().summary()
+}
+
+/// Writes the frames in the ring buffer to a file that chrome://tracing or ui.perfetto.dev can open.
+pub fn save_trace(path: &str) -> std::io::Result<()> {
+    let trace = global().chrome_trace();
+    std::fs::write(path, trace)
+}
+
+// A thread that panicked while holding it can only have left a half pushed span so the data is still fine.
+fn global() -> MutexGuard<'static, Profiler> {
+    PROFILER.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Profiler {
+    /// About 5 seconds at 60 fps.
+    pub const FRAMES: usize = 300;
+    /// Where LogProfile saves the trace.
+    pub const TRACE_FILE: &'static str = "trace.json";
+
+    pub const fn new() -> Self {
+        Profiler {
+            frames: VecDeque::new(),
+            current: Vec::new(),
+            frame_start: None,
+            threads: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, name: &'static str, start: Instant, duration: Duration) {
+        let thread = THREAD.with(|thread| *thread);
+        if !self.threads.iter().any(|(id, _)| *id == thread) {
+            let name = std::thread::current().name().unwrap_or("thread").to_string();
+            self.threads.push((thread, name));
+        }
+        self.current.push(Span { name, thread, start, duration });
+    }
+
+    /// Spans recorded before the first frame starts are thrown away since there's nothing to measure them against.
+    pub fn end_frame(&mut self, now: Instant) {
+        let Some(start) = self.frame_start.replace(now) else {
+            self.current.clear();
+            return;
+        };
+        if self.frames.len() == Self::FRAMES {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(Frame {
+            start,
+            duration: now - start,
+            thread: THREAD.with(|thread| *thread),
+            spans: std::mem::take(&mut self.current),
+        });
+    }
+
+    pub fn frames(&self) -> &VecDeque<Frame> {
+        &self.frames
+    }
+
+    /// Milliseconds per frame spent in each kind of span, averaged over the ring buffer. Slowest first.
+    /// Nested spans are counted in their parent as well.
+    pub fn averages(&self) -> Vec<(&'static str, f32)> {
+        let mut totals: Vec<(&'static str, Duration)> = vec![];
+        for span in self.frames.iter().flat_map(|frame| &frame.spans) {
+            match totals.iter_mut().find(|(name, _)| *name == span.name) {
+                Some((_, total)) => *total += span.duration,
+                None => totals.push((span.name, span.duration)),
+            }
+        }
+        let frames = self.frames.len().max(1) as f32;
+        let mut averages: Vec<_> = totals.into_iter().map(|(name, total)| (name, total.as_secs_f32() * 1000.0 / frames)).collect();
+        averages.sort_by(|a, b| b.1.total_cmp(&a.1));
+        averages
+    }
+
+    pub fn summary(&self) -> Vec<String> {
+        if self.frames.is_empty() {
+            return vec![];
+        }
+        let ms = |duration: Duration| duration.as_secs_f32() * 1000.0;
+        let worst = self.frames.iter().map(|frame| ms(frame.duration)).fold(0.0, f32::max);
+        let average = self.frames.iter().map(|frame| ms(frame.duration)).sum::<f32>() / self.frames.len() as f32;
+        let mut lines = vec![format!("frames: {:.2} ms avg, {:.2} ms worst (last {})", average, worst, self.frames.len())];
+        let spans: Vec<_> = self.averages().iter().map(|(name, ms)| format!("{} {:.2}", name, ms)).collect();
+        for row in spans.chunks(4) {
+            lines.push(row.join(", "));
+        }
+        lines
+    }
+
+    /// The trace event format: https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+    /// Every span is a complete ("X") event with times in microseconds since the oldest frame.
+    pub fn chrome_trace(&self) -> String {
+        let Some(origin) = self.frames.front().map(|frame| frame.start) else {
+            return "{\"traceEvents\": []}".to_string();
+        };
+        let micros = |time: Instant| time.saturating_duration_since(origin).as_secs_f64() * 1e6;
+        let mut events = vec![];
+        for (thread, name) in &self.threads {
+            events.push(format!("{{\"name\": \"thread_name\", \"ph\": \"M\", \"pid\": 1, \"tid\": {}, \"args\": {{\"name\": \"{}\"}}}}", thread, name));
+        }
+        let event = |name: &str, thread: u32, start: Instant, duration: Duration| {
+            format!("{{\"name\": \"{}\", \"ph\": \"X\", \"pid\": 1, \"tid\": {}, \"ts\": {:.1}, \"dur\": {:.1}}}", name, thread, micros(start), duration.as_secs_f64() * 1e6)
+        };
+        for frame in &self.frames {
+            events.push(event("frame", frame.thread, frame.start, frame.duration));
+            for span in &frame.spans {
+                events.push(event(span.name, span.thread, span.start, span.duration));
+            }
+        }
+        format!("{{\"displayTimeUnit\": \"ms\", \"traceEvents\": [\n{}\n]}}", events.join(",\n"))
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn profiler() {
+    let mut profiler = Profiler::new();
+    let start = Instant::now();
+    let ms = Duration::from_millis;
+
+    // Nothing to measure against before the first frame.
+    profiler.record("early", start, ms(1));
+    profiler.end_frame(start);
+    assert!(profiler.frames().is_empty());
+
+    profiler.record("lua_tick", start, ms(3));
+    profiler.record("worldgen", start + ms(1), ms(1));
+    profiler.record("worldgen", start + ms(2), ms(1));
+    profiler.end_frame(start + ms(10));
+    profiler.record("lua_tick", start + ms(10), ms(1));
+    profiler.end_frame(start + ms(20));
+    assert_eq!(profiler.frames().len(), 2);
+    assert_eq!(profiler.frames()[0].spans.len(), 3);
+    let averages = profiler.averages();
+    assert_eq!(averages.iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["lua_tick", "worldgen"]);
+    assert!((averages[0].1 - 2.0).abs() < 0.01 && (averages[1].1 - 1.0).abs() < 0.01);
+    assert_eq!(profiler.summary()[0], "frames: 10.00 ms avg, 10.00 ms worst (last 2)");
+
+    let trace = profiler.chrome_trace();
+    assert!(trace.contains("\"ts\": 2000.0, \"dur\": 1000.0"));
+    assert_eq!(trace.matches("\"ph\": \"X\"").count(), 6);
+    assert_eq!(trace.matches("\"ph\": \"M\"").count(), 1);
+
+    // Old frames fall off the end of the ring buffer.
+    for i in 0..Profiler::FRAMES as u64 {
+        profiler.end_frame(start + ms(30 + i));
+    }
+    assert_eq!(profiler.frames().len(), Profiler::FRAMES);
+    assert!(profiler.frames().iter().all(|frame| frame.spans.is_empty()));
+}
@@ -0,0 +1,249 @@
+use glam::{Mat4, Vec2, Vec3, Vec3Swizzles, Vec4, Vec4Swizzles};
+use image::{Rgba, RgbaImage};
+use common::atlas::UvIndex;
+use common::blocks::RenderLayer;
+use crate::camera::CameraPerspective;
+use crate::chunk_mesh::{Geometry, MeshBuilder};
+use crate::gen;
+use crate::window::{ModelVertex, SKY_COLOUR};
+
+/// Draws meshes on the cpu following the same rules as shader.wgsl (with the atlas, no mipmaps).
+/// Much too slow for the game but doesn't need a gpu so tests can check what meshing produces by comparing pictures.
+pub struct Rasterizer {
+    width: u32,
+    height: u32,
+    colour: Vec<Vec4>,
+    depth: Vec<f32>,
+    atlas: RgbaImage,
+}
+
+// What the vertex shader would pass to the fragment shader.
+// Everything but the screen position is divided by w so it can be interpolated with perspective.
+#[derive(Copy, Clone)]
+struct Varying {
+    screen: Vec3,  // pixels, pixels, depth
+    inv_w: f32,
+    world: Vec3,
+    uv: Vec2,
+}
+
+impl Rasterizer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut raster = Rasterizer {
+            width,
+            height,
+            colour: vec![],
+            depth: vec![],
+            atlas: image::load_from_memory(gen::ATLAS_PNG).unwrap().to_rgba8(),
+        };
+        raster.clear();
+        raster
+    }
+
+    pub fn clear(&mut self) {
+        let len = (self.width * self.height) as usize;
+        let sky = Vec4::new(SKY_COLOUR.r as f32, SKY_COLOUR.g as f32, SKY_COLOUR.b as f32, SKY_COLOUR.a as f32);
+        self.colour = vec![sky; len];
+        self.depth = vec![1.0; len];
+    }
+
+    /// Draws every layer in the same order as GpuRenderer so blending comes out the same.
+    pub fn draw_mesh(&mut self, mesh: &MeshBuilder, transform: Mat4, camera: &CameraPerspective) {
+        for layer in RenderLayer::ALL {
+            self.draw(mesh.geometry(layer), layer, transform, camera);
+        }
+    }
+
+    pub fn draw(&mut self, geometry: &Geometry, layer: RenderLayer, transform: Mat4, camera: &CameraPerspective) {
+        let view_proj = camera.calc_matrix();
+        for triangle in geometry.indi.chunks_exact(3) {
+            let vertices = [0, 1, 2].map(|i| geometry.vert[triangle[i] as usize]);
+            // TODO: clip against the near plane instead of dropping the whole triangle.
+            let Some(a) = self.vertex(vertices[0], transform, view_proj) else { continue };
+            let Some(b) = self.vertex(vertices[1], transform, view_proj) else { continue };
+            let Some(c) = self.vertex(vertices[2], transform, view_proj) else { continue };
+
+            // Flat attributes come from the first vertex, like wgpu does.
+            let first = vertices[0];
+            let tint = Vec3::from_array([0, 1, 2].map(|i| first.tint[i] as f32 / 255.0));
+            let light = tint * (first.light() as f32 / 255.0 * face_shade(first.face()));
+            self.triangle([a, b, c], layer, light, camera);
+        }
+    }
+
+    fn vertex(&self, vertex: ModelVertex, transform: Mat4, view_proj: Mat4) -> Option<Varying> {
+        let world = transform * vertex.pos().extend(1.0);
+        let clip = view_proj * world;
+        if clip.w <= 0.0 {
+            return None;
+        }
+        let inv_w = 1.0 / clip.w;
+        let ndc = clip.xyz() * inv_w;
+        Some(Varying {
+            screen: Vec3::new((ndc.x + 1.0) / 2.0 * self.width as f32, (1.0 - ndc.y) / 2.0 * self.height as f32, ndc.z),
+            inv_w,
+            world: world.xyz() * inv_w,
+            uv: block_uv(vertex.uv(), vertex.corner()) * inv_w,
+        })
+    }
+
+    /// Light is already multiplied by the tint.
+    fn triangle(&mut self, v: [Varying; 3], layer: RenderLayer, light: Vec3, camera: &CameraPerspective) {
+        let edge = |a: Vec3, b: Vec3, p: Vec2| (b.x - a.x) * (p.y - a.y) - (b.y - a.y) * (p.x - a.x);
+        let area = edge(v[0].screen, v[1].screen, v[2].screen.xy());
+        if area == 0.0 {
+            return;
+        }
+
+        let min = v[0].screen.min(v[1].screen).min(v[2].screen);
+        let max = v[0].screen.max(v[1].screen).max(v[2].screen);
+        let x_range = (min.x.floor().max(0.0) as u32)..(max.x.ceil().min(self.width as f32) as u32);
+        let y_range = (min.y.floor().max(0.0) as u32)..(max.y.ceil().min(self.height as f32) as u32);
+
+        for y in y_range {
+            for x in x_range.clone() {
+                // Sample at the pixel centre. Dividing by the area makes these work for both windings (nothing is culled).
+                // TODO: pixels exactly on a shared edge get drawn twice which matters for translucent.
+                let p = Vec2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let w = Vec3::new(edge(v[1].screen, v[2].screen, p), edge(v[2].screen, v[0].screen, p), edge(v[0].screen, v[1].screen, p)) / area;
+                if w.min_element() < 0.0 {
+                    continue;
+                }
+
+                let depth = w.x * v[0].screen.z + w.y * v[1].screen.z + w.z * v[2].screen.z;
+                let i = (y * self.width + x) as usize;
+                if !(0.0..=1.0).contains(&depth) || depth >= self.depth[i] {
+                    continue;
+                }
+
+                let inv_w = w.x * v[0].inv_w + w.y * v[1].inv_w + w.z * v[2].inv_w;
+                let uv = (v[0].uv * w.x + v[1].uv * w.y + v[2].uv * w.z) / inv_w;
+                let world = (v[0].world * w.x + v[1].world * w.y + v[2].world * w.z) / inv_w;
+
+                let texel = self.sample(uv);
+                if layer == RenderLayer::Cutout && texel.w < 0.5 {
+                    continue;
+                }
+                let colour = apply_fog(texel.xyz() * light, world, camera);
+
+                match layer {
+                    RenderLayer::Opaque => self.colour[i] = colour.extend(texel.w),
+                    RenderLayer::Cutout => self.colour[i] = colour.extend(1.0),
+                    RenderLayer::Translucent => {
+                        self.colour[i] = colour.extend(texel.w) * texel.w + self.colour[i] * (1.0 - texel.w);
+                        continue;  // No depth write.
+                    }
+                }
+                self.depth[i] = depth;
+            }
+        }
+    }
+
+    // Nearest filtering and clamped to the edge, same as the sampler in Texture::from_image.
+    fn sample(&self, uv: Vec2) -> Vec4 {
+        let x = ((uv.x * self.atlas.width() as f32) as u32).min(self.atlas.width() - 1);
+        let y = ((uv.y * self.atlas.height() as f32) as u32).min(self.atlas.height() - 1);
+        Vec4::from_array(self.atlas.get_pixel(x, y).0.map(|c| c as f32 / 255.0))
+    }
+
+    /// The window ignores alpha so the picture does too.
+    pub fn image(&self) -> RgbaImage {
+        RgbaImage::from_fn(self.width, self.height, |x, y| {
+            let colour = self.colour[(y * self.width + x) as usize];
+            let [r, g, b] = colour.xyz().to_array().map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            Rgba([r, g, b, 255])
+        })
+    }
+}
+
+// These three match the functions with the same names in the shaders.
+
+fn block_uv(index: UvIndex, corner: u8) -> Vec2 {
+    let rect = gen::uvs::ALL[index.0 as usize];
+    Vec2::new(rect.x, rect.y) + Vec2::new((corner & 1) as f32, (corner >> 1) as f32) * rect.size
+}
+
+fn face_shade(face: u8) -> f32 {
+    match face {
+        0 => 1.0,
+        1 => 0.5,
+        2 | 3 => 0.8,
+        4 | 5 => 0.65,
+        _ => 0.9,
+    }
+}
+
+fn apply_fog(colour: Vec3, world: Vec3, camera: &CameraPerspective) -> Vec3 {
+    let sky = Vec3::new(SKY_COLOUR.r as f32, SKY_COLOUR.g as f32, SKY_COLOUR.b as f32);
+    let amount = ((world.distance(camera.pos) - camera.fog_start) / (camera.fog_end - camera.fog_start)).clamp(0.0, 1.0);
+    colour.lerp(sky, amount)
+}
+
+/// Compares against the png in tests/golden. Run with BLESS=1 to save new pictures after an intentional change.
+#[cfg(test)]
+fn assert_golden(name: &str, raster: &Rasterizer) {
+    let path = format!("{}/tests/golden/{}.png", env!("CARGO_MANIFEST_DIR"), name);
+    let actual = raster.image();
+    if std::env::var("BLESS").is_ok() {
+        actual.save(&path).unwrap();
+        return;
+    }
+
+    let expected = image::open(&path).unwrap_or_else(|e| panic!("{}: {}", path, e)).to_rgba8();
+    assert_eq!(expected.dimensions(), actual.dimensions());
+    let different = expected.pixels().zip(actual.pixels())
+        .filter(|(a, b)| a.0.iter().zip(b.0.iter()).any(|(a, b)| a.abs_diff(*b) > 2))
+        .count();
+
+    // Let a few edge pixels be off so float differences between platforms don't matter.
+    if different * 1000 > actual.len() / 4 {
+        let out = std::env::temp_dir().join(format!("{}.png", name));
+        actual.save(&out).unwrap();
+        panic!("{} pixels are different from {}. Saved it to {:?}", different, path, out);
+    }
+}
+
+#[cfg(test)]
+fn looking_at(pos: Vec3, yaw: f32, pitch: f32, width: u32, height: u32) -> CameraPerspective {
+    let mut camera = CameraPerspective::new();
+    camera.pos = pos;
+    camera.yaw = yaw.to_radians();
+    camera.pitch = pitch.to_radians();
+    camera.resize(width, height);
+    camera
+}
+
+#[test]
+fn golden_blocks() {
+    use crate::pos::{Chunk, ChunkPos, LocalPos};
+    use gen::tiles::*;
+
+    // One of each block standing on a strip of grass.
+    let mut chunk = Chunk::full(empty, ChunkPos::new(0, 0, 0));
+    for x in 0..9 {
+        for z in 0..3 {
+            chunk.set(LocalPos::new(x, 0, z), grass);
+        }
+    }
+    for (x, tile) in [stone, dirt, grass, log, leaf, glass, sapling, wheat, wheat3].into_iter().enumerate() {
+        chunk.set(LocalPos::new(x, 1, 1), tile);
+    }
+
+    let mut mesh = MeshBuilder::new();
+    mesh.build_chunk(&chunk, &Default::default());
+    let camera = looking_at(Vec3::new(4.5, 3.5, 6.5), -90.0, -25.0, 160, 90);
+    let mut raster = Rasterizer::new(160, 90);
+    raster.draw_mesh(&mesh, Mat4::IDENTITY, &camera);
+    assert_golden("blocks", &raster);
+}
+
+#[test]
+fn golden_cube() {
+    // Same as an entity. Looking at a corner shows which way up each face's texture is.
+    let mut mesh = MeshBuilder::new();
+    mesh.add_cube(gen::tiles::log, Vec3::ZERO, true, true, true, true, true, true);
+    let camera = looking_at(Vec3::new(-1.2, 2.0, -1.2), 45.0, -35.0, 64, 64);
+    let mut raster = Rasterizer::new(64, 64);
+    raster.draw_mesh(&mesh, Mat4::IDENTITY, &camera);
+    assert_golden("cube", &raster);
+}
@@ -0,0 +1,527 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::mem::size_of;
+use std::rc::Rc;
+use glam::{Vec2, Vec3};
+use wgpu::{RenderPipeline, VertexBufferLayout};
+use winit::dpi::PhysicalSize;
+use winit::window::CursorGrabMode;
+use common::blocks::RenderLayer;
+#[cfg(feature = "hot_reload")]
+use common::blocks::Textures;
+use crate::camera::{CameraBuffer, CameraPerspective};
+use crate::chunk_mesh::{ChunkGeometry, ChunkList, TextureAtlas};
+use crate::entity_render::EntityRender;
+use crate::gen;
+use crate::lua_api::lua::GameLogic;
+use crate::pos::{BlockPos, ChunkPos};
+use crate::profiler;
+use crate::overlay::Overlay;
+use crate::screenshot::Screenshots;
+use crate::selection::SelectionOutline;
+use crate::window::{ModelVertex, PipelineOptions, Texture, WindowContext};
+
+/// Everything the game logic needs from the graphics side.
+/// State only talks to this so the world and lua can run without a window (see RecordingRenderer).
+pub trait Renderer {
+    /// Replaces the mesh for a chunk that was just generated or had its tiles changed (see LogicChunks::update_meshes).
+    fn update_chunk(&mut self, pos: ChunkPos, layers: &ChunkGeometry);
+    fn remove_chunk(&mut self, pos: ChunkPos);
+    /// Says how to draw entities of a type. Indexes into gen::models::ALL and gen::animations::ALL.
+    /// Must happen before any update_entity with that type.
+    fn register_entity_type(&mut self, ty: i32, model: usize, animations: Option<usize>);
+    /// Called every tick for every entity.
+    fn update_entity(&mut self, id: i32, ty: i32, pos: Vec3);
+    fn remove_entity(&mut self, id: i32);
+
+    /// In chunks.
+    fn render_distance(&self) -> u32;
+    fn set_render_distance(&mut self, distance: u32);
+    /// Bytes of gpu memory chunk meshes can use before the furthest ones are dropped.
+    fn set_mesh_budget(&mut self, bytes: u64);
+    /// Chunks whose meshes were dropped for the budget and should be meshed again now they're back in view.
+    fn take_evicted(&mut self) -> Vec<ChunkPos>;
+
+    /// Lua gets asked to evaluate any expressions in entity animations.
+    fn render(&mut self, camera: &CameraPerspective, logic: &GameLogic) -> Result<(), wgpu::SurfaceError>;
+    /// Returns false if the new size was ignored (ie. minimized).
+    fn resize(&mut self, new_size: PhysicalSize<u32>) -> bool;
+    fn set_cursor_lock(&self, locked: bool);
+    /// Saves the next frame to a png (see Screenshots).
+    fn screenshot(&mut self);
+    /// The block to draw an outline around. None when you're not looking at anything in reach.
+    fn set_selection(&mut self, block: Option<BlockPos>);
+
+    fn stats(&self) -> RenderStats;
+    /// Drawn in the corner every frame until it's set again. Empty hides it.
+    fn set_debug_text(&mut self, lines: Vec<String>);
+
+    /// Swaps in textures rebuilt at runtime. The uvs must be in the same order as gen::uvs::ALL.
+    #[cfg(feature = "hot_reload")]
+    fn reload_textures(&mut self, textures: &Textures);
+    /// Rebuilds the chunk pipelines from GpuRenderer::CHUNK_SHADERS in src/. Compile errors are printed and the old pipelines are kept.
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    fn reload_shaders(&mut self);
+
+    #[cfg(feature = "profiling")]
+    fn log_profile(&self);
+}
+
+/// Numbers for the debug HUD.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RenderStats {
+    pub frame_time_ms: f32,
+    pub chunk_meshes: usize,
+    pub entities: usize,
+    /// (used, capacity) of the mesh arena's vertices, indices and transform slots.
+    pub arena: [(u32, u32); 3],
+    /// How many times any chunk has been meshed since the renderer was made.
+    pub chunks_meshed: u64,
+}
+
+pub struct GpuRenderer {
+    ctx: Rc<WindowContext>,
+    depth_texture: Texture,
+    camera: CameraBuffer,
+    pipelines: [RenderPipeline; RenderLayer::COUNT],
+    #[cfg(feature = "hot_reload")]
+    pipeline_layout: wgpu::PipelineLayout,
+    chunks: ChunkList,
+    atlas: TextureAtlas,
+    entities: EntityRender,
+    screenshots: Screenshots,
+    overlay: Overlay,
+    selection: SelectionOutline,
+    debug_text: Vec<String>,
+}
+
+impl GpuRenderer {
+    /// What the chunk pipelines are built from. The entity, overlay and outline shaders are only read at startup.
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    pub const CHUNK_SHADERS: &'static [&'static str] = &["shader.wgsl", "texture_array.wgsl", "texture_atlas.wgsl"];
+
+    pub fn new(ctx: Rc<WindowContext>, camera: &CameraPerspective) -> Self {
+        let atlas = TextureAtlas::new(&ctx);
+        let depth_texture = Texture::create_depth_texture(&ctx.device, &ctx.config.borrow(), "depth_texture");
+        let camera = CameraBuffer::new(&ctx, camera);
+
+        let chunks = ChunkList::new(ctx.clone());
+
+        let render_pipeline_layout = ctx.pipeline_layout(&[
+            &camera.camera_bind_group_layout,
+            &chunks.arena.layout,
+            &atlas.layout
+        ]);
+
+        let shader = format!("{}\n{}", include_str!("shader.wgsl"), atlas.shader);
+        let pipelines = RenderLayer::ALL.map(|layer| {
+            ctx.render_pipeline(&format!("{:?}", layer), &render_pipeline_layout, &[Self::VERTEX_LAYOUT], &shader, Self::layer_options(layer))
+        });
+
+        GpuRenderer {
+            entities: EntityRender::new(ctx.clone(), &camera.camera_bind_group_layout),
+            overlay: Overlay::new(ctx.clone()),
+            selection: SelectionOutline::new(ctx.clone(), &camera.camera_bind_group_layout),
+            ctx,
+            depth_texture,
+            camera,
+            pipelines,
+            #[cfg(feature = "hot_reload")]
+            pipeline_layout: render_pipeline_layout,
+            chunks,
+            atlas,
+            screenshots: Screenshots::new(),
+            debug_text: vec![],
+        }
+    }
+
+    const VERTEX_LAYOUT: VertexBufferLayout<'static> = VertexBufferLayout {
+        array_stride: size_of::<ModelVertex>() as wgpu::BufferAddress,
+        step_mode: wgpu::VertexStepMode::Vertex,
+        attributes: ModelVertex::ATTRIBS
+    };
+
+    fn layer_options(layer: RenderLayer) -> PipelineOptions<'static> {
+        match layer {
+            RenderLayer::Opaque => PipelineOptions::OPAQUE,
+            RenderLayer::Cutout => PipelineOptions::CUTOUT,
+            RenderLayer::Translucent => PipelineOptions::TRANSLUCENT,
+        }
+    }
+}
+
+impl Renderer for GpuRenderer {
+    fn update_chunk(&mut self, pos: ChunkPos, layers: &ChunkGeometry) {
+        let _span = profiler::span("upload");
+        self.chunks.update_mesh(pos, layers);
+    }
+
+    fn remove_chunk(&mut self, pos: ChunkPos) {
+        self.chunks.remove(pos);
+    }
+
+    fn register_entity_type(&mut self, ty: i32, model: usize, animations: Option<usize>) {
+        self.entities.register(ty, model, animations);
+    }
+
+    fn update_entity(&mut self, id: i32, ty: i32, pos: Vec3) {
+        self.entities.update(id, ty, pos);
+    }
+
+    fn remove_entity(&mut self, id: i32) {
+        self.entities.remove(id);
+    }
+
+    fn render_distance(&self) -> u32 {
+        self.chunks.render_distance
+    }
+
+    fn set_render_distance(&mut self, distance: u32) {
+        self.chunks.set_render_distance(distance);
+    }
+
+    fn set_mesh_budget(&mut self, bytes: u64) {
+        self.chunks.set_budget(bytes);
+    }
+
+    fn take_evicted(&mut self) -> Vec<ChunkPos> {
+        self.chunks.take_evicted()
+    }
+
+    fn render(&mut self, camera: &CameraPerspective, logic: &GameLogic) -> Result<(), wgpu::SurfaceError> {
+        let _span = profiler::span("render");
+        self.screenshots.poll(&self.ctx);
+        {
+            let _span = profiler::span("upload");
+            self.camera.write(&self.ctx, camera);
+            self.chunks.centre = BlockPos::vec(camera.pos).chunk();
+            self.entities.prepare(logic);
+            self.overlay.builder.clear();
+            let scale = (self.ctx.window.scale_factor() as f32 * 2.0).round();
+            let size = *self.ctx.size.borrow();
+            self.overlay.builder.crosshair(Vec2::new(size.width as f32, size.height as f32) / 2.0, scale);
+            self.overlay.builder.panel(Vec2::splat(scale * 2.0), scale, &self.debug_text);
+            self.overlay.upload();
+        }
+        let mut encoder = self.ctx.command_encoder("render");
+
+        let output = self.ctx.surface.get_current_texture()?;
+        let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        {
+            let _span = profiler::span("render_pass");
+            let mut render_pass = self.ctx.render_pass(&mut encoder, &view, &self.depth_texture.view);
+            render_pass.set_bind_group(0, &self.camera.camera_bind_group, &[]);
+
+            // Translucent must be last so everything behind it has already been drawn.
+            for layer in RenderLayer::ALL {
+                render_pass.set_pipeline(&self.pipelines[layer as usize]);
+                // Set every time because the entity pipeline doesn't have it.
+                render_pass.set_bind_group(2, &self.atlas.bind_group, &[]);
+                self.chunks.render(&mut render_pass, camera.pos, layer);
+                if layer == RenderLayer::Opaque {
+                    self.entities.render(&mut render_pass);
+                }
+            }
+            self.selection.render(&mut render_pass);
+            self.overlay.render(&mut render_pass);
+
+        };
+
+        self.screenshots.copy_frame(&self.ctx, &mut encoder, &output.texture);
+        self.ctx.queue.submit([
+            encoder.finish()
+        ]);
+        self.screenshots.map();
+
+        output.present();
+
+        Ok(())
+    }
+
+    fn resize(&mut self, new_size: PhysicalSize<u32>) -> bool {
+        if self.ctx.resize(&new_size) {
+            self.depth_texture = Texture::create_depth_texture(&self.ctx.device, &self.ctx.config.borrow(), "depth_texture");
+            true
+        } else {
+            false
+        }
+    }
+
+    fn set_cursor_lock(&self, locked: bool) {
+        if locked {
+            let _ = self.ctx.window.set_cursor_grab(CursorGrabMode::Locked);
+            self.ctx.window.set_cursor_visible(false);
+        } else {
+            let _ = self.ctx.window.set_cursor_grab(CursorGrabMode::None);
+            self.ctx.window.set_cursor_visible(true);
+        }
+    }
+
+    fn screenshot(&mut self) {
+        self.screenshots.request();
+    }
+
+    fn set_selection(&mut self, block: Option<BlockPos>) {
+        self.selection.set(block);
+    }
+
+    fn stats(&self) -> RenderStats {
+        RenderStats {
+            frame_time_ms: self.ctx.timer.borrow().frame_time_ms,
+            chunk_meshes: self.chunks.len(),
+            entities: self.entities.len(),
+            arena: self.chunks.arena.usage(),
+            chunks_meshed: self.chunks.meshed,
+        }
+    }
+
+    fn set_debug_text(&mut self, lines: Vec<String>) {
+        self.debug_text = lines;
+    }
+
+    #[cfg(feature = "hot_reload")]
+    fn reload_textures(&mut self, textures: &Textures) {
+        self.atlas.reload(&self.ctx, textures);
+        self.entities.set_texture(TextureAtlas::bake(&self.ctx, &textures.atlas));
+    }
+
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    fn reload_shaders(&mut self) {
+        let texture_shader = if self.atlas.array { "texture_array.wgsl" } else { "texture_atlas.wgsl" };
+        let read = |name: &str| std::fs::read_to_string(format!("{}/src/{}", env!("CARGO_MANIFEST_DIR"), name));
+        let shader = match (read("shader.wgsl"), read(texture_shader)) {
+            (Ok(main), Ok(texture)) => format!("{}\n{}", main, texture),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("Failed to read shaders: {}", e);
+                return;
+            }
+        };
+
+        // All or nothing so the layers can't end up drawn by different versions.
+        let pipelines: Result<Vec<_>, _> = RenderLayer::ALL.iter().map(|&layer| {
+            self.ctx.try_render_pipeline(&format!("{:?}", layer), &self.pipeline_layout, &[Self::VERTEX_LAYOUT], &shader, Self::layer_options(layer))
+        }).collect();
+        match pipelines {
+            Ok(pipelines) => {
+                self.pipelines = pipelines.try_into().unwrap();
+                println!("Reloaded the chunk shaders");
+            }
+            Err(e) => println!("Failed to reload the chunk shaders, keeping the old pipelines.\n{}", e),
+        }
+    }
+
+    #[cfg(feature = "profiling")]
+    fn log_profile(&self) {
+        self.chunks.log_profile();
+        self.entities.log_profile();
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum RenderEvent {
+    ChunkCreated(ChunkPos),
+    ChunkUpdated(ChunkPos),
+    ChunkRemoved(ChunkPos),
+    EntityCreated(i32),
+    EntityRemoved(i32),
+    Screenshot,
+}
+
+/// Doesn't draw anything, just writes down what would have changed on the gpu.
+/// A chunk with no visible faces gets no mesh, same as ChunkList.
+pub struct RecordingRenderer {
+    events: Rc<RefCell<Vec<RenderEvent>>>,
+    chunks: HashSet<ChunkPos>,
+    entities: HashSet<i32>,
+    entity_types: HashSet<i32>,
+    render_distance: u32,
+    meshed: u64,
+}
+
+impl RecordingRenderer {
+    /// The renderer gets moved into the State so hold on to a clone of the log to look at it later.
+    pub fn new(events: Rc<RefCell<Vec<RenderEvent>>>) -> Self {
+        RecordingRenderer {
+            events,
+            chunks: Default::default(),
+            entities: Default::default(),
+            entity_types: Default::default(),
+            render_distance: 5,
+            meshed: 0,
+        }
+    }
+
+    fn record(&self, event: RenderEvent) {
+        self.events.borrow_mut().push(event);
+    }
+}
+
+impl Renderer for RecordingRenderer {
+    fn update_chunk(&mut self, pos: ChunkPos, layers: &ChunkGeometry) {
+        self.meshed += 1;
+        let had_mesh = self.chunks.contains(&pos);
+        if layers.iter().all(|geometry| geometry.indi.is_empty()) {
+            if had_mesh {
+                self.chunks.remove(&pos);
+                self.record(RenderEvent::ChunkRemoved(pos));
+            }
+        } else if had_mesh {
+            self.record(RenderEvent::ChunkUpdated(pos));
+        } else {
+            self.chunks.insert(pos);
+            self.record(RenderEvent::ChunkCreated(pos));
+        }
+    }
+
+    fn remove_chunk(&mut self, pos: ChunkPos) {
+        if self.chunks.remove(&pos) {
+            self.record(RenderEvent::ChunkRemoved(pos));
+        }
+    }
+
+    fn register_entity_type(&mut self, ty: i32, model: usize, animations: Option<usize>) {
+        assert!(model < gen::models::ALL.len(), "Invalid model {} for entity type {}", model, ty);
+        if let Some(animations) = animations {
+            assert!(animations < gen::animations::ALL.len(), "Invalid animations {} for entity type {}", animations, ty);
+        }
+        self.entity_types.insert(ty);
+    }
+
+    fn update_entity(&mut self, id: i32, ty: i32, _: Vec3) {
+        assert!(self.entity_types.contains(&ty), "Entity type {} was never registered, id={}", ty, id);
+        if self.entities.insert(id) {
+            self.record(RenderEvent::EntityCreated(id));
+        }
+    }
+
+    fn remove_entity(&mut self, id: i32) {
+        if self.entities.remove(&id) {
+            self.record(RenderEvent::EntityRemoved(id));
+        }
+    }
+
+    fn render_distance(&self) -> u32 {
+        self.render_distance
+    }
+
+    fn set_render_distance(&mut self, distance: u32) {
+        self.render_distance = distance;
+    }
+
+    fn set_mesh_budget(&mut self, _: u64) {}
+
+    fn take_evicted(&mut self) -> Vec<ChunkPos> {
+        vec![]
+    }
+
+    fn render(&mut self, _: &CameraPerspective, _: &GameLogic) -> Result<(), wgpu::SurfaceError> {
+        Ok(())
+    }
+
+    fn resize(&mut self, _: PhysicalSize<u32>) -> bool {
+        true
+    }
+
+    fn set_cursor_lock(&self, _: bool) {}
+
+    fn screenshot(&mut self) {
+        self.record(RenderEvent::Screenshot);
+    }
+
+    fn set_selection(&mut self, _: Option<BlockPos>) {}
+
+    fn stats(&self) -> RenderStats {
+        RenderStats {
+            chunk_meshes: self.chunks.len(),
+            entities: self.entities.len(),
+            chunks_meshed: self.meshed,
+            ..Default::default()
+        }
+    }
+
+    fn set_debug_text(&mut self, _: Vec<String>) {}
+
+    #[cfg(feature = "hot_reload")]
+    fn reload_textures(&mut self, _: &Textures) {}
+
+    #[cfg(all(feature = "hot_reload", not(target_arch = "wasm32")))]
+    fn reload_shaders(&mut self) {}
+
+    #[cfg(feature = "profiling")]
+    fn log_profile(&self) {
+        println!("RecordingRenderer:\n  - chunk meshes: {}\n  - entities: {}", self.chunks.len(), self.entities.len());
+    }
+}
+
+#[test]
+fn headless_chunk_meshes() {
+    use crate::lua_api::{chunk_set_block, get_chunk, unload_chunk, update_mesh};
+    use crate::State;
+    use RenderEvent::*;
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut state = State::headless(events.clone());
+
+    // Hills and trees never reach this high so it's all air and never gets a mesh.
+    get_chunk(&mut state, 0, 1, 0);
+    let ground = get_chunk(&mut state, 0, -1, 0);
+    assert_eq!(*events.borrow(), [ChunkCreated(ChunkPos::new(0, -1, 0))]);
+    events.borrow_mut().clear();
+
+    // Changes only get remeshed when lua asks.
+    chunk_set_block(unsafe { &mut *ground }, 0, gen::tiles::empty.0 as u32);
+    assert!(events.borrow().is_empty());
+    update_mesh(&mut state);
+    assert_eq!(*events.borrow(), [ChunkUpdated(ChunkPos::new(0, -1, 0))]);
+    update_mesh(&mut state);
+    assert_eq!(events.borrow().len(), 1);
+    events.borrow_mut().clear();
+
+    unload_chunk(&mut state, 0, 1, 0);
+    unload_chunk(&mut state, 0, -1, 0);
+    assert_eq!(*events.borrow(), [ChunkRemoved(ChunkPos::new(0, -1, 0))]);
+}
+
+#[test]
+fn headless_lua_tick() {
+    use instant::Duration;
+    use crate::lua_api::reference_extern;
+    use crate::State;
+
+    reference_extern();  // Otherwise the linker drops functions only lua calls.
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut state = State::headless(events.clone());
+    let tick = Duration::from_millis(50);
+
+    let radius = state.render.render_distance() as i32;
+    let created = |events: &Rc<RefCell<Vec<RenderEvent>>>| -> Vec<ChunkPos> {
+        events.borrow().iter().filter_map(|event| match event {
+            RenderEvent::ChunkCreated(pos) => Some(*pos),
+            _ => None,
+        }).collect()
+    };
+
+    // First tick loads the flat square the player is in. Only the chunks with something in them get a mesh.
+    state.logic.run_tick(&mut state, tick);
+    assert_eq!(state.world.chunks.len(), (2 * radius as usize + 1).pow(2));
+    for pos in created(&events) {
+        assert!(pos.y == 0 && pos.x.abs() <= radius && pos.z.abs() <= radius, "{:?}", pos);
+    }
+    events.borrow_mut().clear();
+
+    // Then a ring one layer up and down each tick after.
+    state.logic.run_tick(&mut state, tick);
+    let ring = |pos: &ChunkPos| pos.y.abs() == 1 && pos.x.abs().max(pos.z.abs()) == radius;
+    let created = created(&events);
+    assert!(created.iter().all(ring), "{:?}", created);
+    for i in -radius..=radius {
+        for (x, z) in [(i, -radius), (i, radius), (-radius, i), (radius, i)] {
+            for y in [-1, 1] {
+                assert!(state.world.chunks.contains_key(&ChunkPos::new(x, y, z)), "{} {} {}", x, y, z);
+            }
+            // The ground is always somewhere in the layer below and the outside of the ring has nothing loaded to hide it.
+            assert!(created.contains(&ChunkPos::new(x, -1, z)), "{} {}", x, z);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use image::RgbaImage;
+use wgpu::{Buffer, CommandEncoder, Texture, TextureFormat};
+use crate::window::WindowContext;
+
+/// Copies a frame into a buffer after the render pass and saves it once the gpu is done with it.
+/// The buffer is checked again each frame instead of waiting on it so taking a picture doesn't freeze the game.
+pub struct Screenshots {
+    requested: bool,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    buffer: Buffer,
+    width: u32,
+    height: u32,
+    padded_row: u32,
+    format: TextureFormat,
+    mapped: bool,
+    ready: Arc<AtomicBool>,
+}
+
+impl Screenshots {
+    pub const DIRECTORY: &'static str = "screenshots";
+
+    pub fn new() -> Self {
+        Screenshots {
+            requested: false,
+            pending: None,
+        }
+    }
+
+    /// Taken at the end of the next frame.
+    pub fn request(&mut self) {
+        if cfg!(target_arch = "wasm32") {
+            println!("Screenshots aren't supported on the web.");
+            return;
+        }
+        self.requested = true;
+    }
+
+    /// Call after the render pass. Only does anything if a screenshot was requested (and the last one is finished).
+    pub fn copy_frame(&mut self, ctx: &WindowContext, encoder: &mut CommandEncoder, texture: &Texture) {
+        if !self.requested || self.pending.is_some() {
+            return;
+        }
+        if !texture.usage().contains(wgpu::TextureUsages::COPY_SRC) {
+            println!("Can't take a screenshot because the surface doesn't support copying.");
+            self.requested = false;
+            return;
+        }
+        self.requested = false;
+
+        // Rows in the buffer need to be padded to the alignment.
+        let (width, height) = (texture.width(), texture.height());
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_row = (width * 4).div_ceil(align) * align;
+        let buffer = ctx.buffer_empty("screenshot", (padded_row * height) as u64, wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ);
+
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(padded_row),
+                    rows_per_image: Some(height),
+                },
+            },
+            texture.size(),
+        );
+
+        self.pending = Some(Pending {
+            buffer,
+            width,
+            height,
+            padded_row,
+            format: texture.format(),
+            mapped: false,
+            ready: Arc::new(AtomicBool::new(false)),
+        });
+    }
+
+    /// Call after submitting the frame that copy_frame was called for. The buffer can't be mapped until then.
+    pub fn map(&mut self) {
+        if let Some(pending) = self.pending.as_mut().filter(|pending| !pending.mapped) {
+            pending.mapped = true;
+            let ready = pending.ready.clone();
+            pending.buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+                result.expect("Failed to read screenshot");
+                ready.store(true, Ordering::SeqCst);
+            });
+        }
+    }
+
+    /// Call once a frame. Saves the picture if the gpu has finished copying it.
+    pub fn poll(&mut self, ctx: &WindowContext) {
+        if self.pending.is_none() {
+            return;
+        }
+        ctx.device.poll(wgpu::Maintain::Poll);
+        if !self.pending.as_ref().unwrap().ready.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let pending = self.pending.take().unwrap();
+        let image = {
+            let data = pending.buffer.slice(..).get_mapped_range();
+            let bgra = matches!(pending.format, TextureFormat::Bgra8Unorm | TextureFormat::Bgra8UnormSrgb);
+            let mut image = RgbaImage::new(pending.width, pending.height);
+            for (y, row) in data.chunks_exact(pending.padded_row as usize).enumerate() {
+                for (x, pixel) in row[..(pending.width * 4) as usize].chunks_exact(4).enumerate() {
+                    let rgba = if bgra { [pixel[2], pixel[1], pixel[0], 255] } else { [pixel[0], pixel[1], pixel[2], 255] };
+                    image.put_pixel(x as u32, y as u32, image::Rgba(rgba));
+                }
+            }
+            image
+        };
+        pending.buffer.unmap();
+
+        #[cfg(not(target_arch = "wasm32"))]
+        save(image);
+    }
+}
+
+// Encoding the png is slow enough to notice so do it on another thread.
+#[cfg(not(target_arch = "wasm32"))]
+fn save(image: RgbaImage) {
+    let time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap();
+    let path = format!("{}/screenshot-{}-{:03}.png", Screenshots::DIRECTORY, time.as_secs(), time.subsec_millis());
+    std::thread::spawn(move || {
+        std::fs::create_dir_all(Screenshots::DIRECTORY).unwrap();
+        match image.save(&path) {
+            Ok(_) => println!("Saved {}", path),
+            Err(e) => eprintln!("Failed to save {}: {}", path, e),
+        }
+    });
+}
@@ -0,0 +1,76 @@
+use std::mem::size_of;
+use std::rc::Rc;
+use glam::Vec3;
+use wgpu::{BindGroupLayout, Buffer, BufferUsages, RenderPass, RenderPipeline};
+use crate::pos::BlockPos;
+use crate::window::{PipelineOptions, slice_to_bytes, WindowContext};
+
+/// Wireframe box around the block the player is looking at. Drawn after the chunks with the same camera.
+pub struct SelectionOutline {
+    ctx: Rc<WindowContext>,
+    pipeline: RenderPipeline,
+    vertices: Buffer,
+    block: Option<BlockPos>,
+}
+
+impl SelectionOutline {
+    /// Pushed out a tiny bit so the lines aren't hidden inside the block's faces.
+    const GROW: f32 = 0.002;
+
+    pub fn new(ctx: Rc<WindowContext>, camera_layout: &BindGroupLayout) -> Self {
+        let pipeline = ctx.render_pipeline(
+            "Outline", &ctx.pipeline_layout(&[camera_layout]), &[wgpu::VertexBufferLayout {
+                array_stride: size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &wgpu::vertex_attr_array![0 => Float32x3]
+            }], include_str!("outline.wgsl"), PipelineOptions::OUTLINE
+        );
+        let vertices = ctx.buffer_empty("outline_vertex", (size_of::<[f32; 3]>() * 24) as u64, BufferUsages::VERTEX | BufferUsages::COPY_DST);
+
+        SelectionOutline {
+            ctx,
+            pipeline,
+            vertices,
+            block: None,
+        }
+    }
+
+    pub fn set(&mut self, block: Option<BlockPos>) {
+        if self.block == block {
+            return;
+        }
+        self.block = block;
+        if let Some(block) = block {
+            let lines = box_lines(block.as_vec() - Self::GROW, Vec3::splat(1.0 + Self::GROW * 2.0));
+            self.ctx.write_buffer(&self.vertices, slice_to_bytes(&lines));
+        }
+    }
+
+    /// Expects the camera bind group to already be set.
+    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
+        if self.block.is_none() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_vertex_buffer(0, self.vertices.slice(..));
+        render_pass.draw(0..24, 0..1);
+    }
+}
+
+/// Pairs of points for the 12 edges of a box.
+fn box_lines(min: Vec3, size: Vec3) -> [[f32; 3]; 24] {
+    let corner = |i: usize| (min + size * Vec3::new((i & 1) as f32, ((i >> 1) & 1) as f32, ((i >> 2) & 1) as f32)).to_array();
+    let mut lines = [[0.0; 3]; 24];
+    let mut n = 0;
+    for i in 0..8 {
+        // Connect each corner to the ones one step further along each axis.
+        for axis in [1, 2, 4] {
+            if i & axis == 0 {
+                lines[n] = corner(i);
+                lines[n + 1] = corner(i | axis);
+                n += 2;
+            }
+        }
+    }
+    lines
+}
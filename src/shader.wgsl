@@ -1,6 +1,9 @@
 struct CameraUniform {
     view_proj: mat4x4<f32>,
-    view_pos: vec4<f32>
+    view_pos: vec4<f32>,
+    fog: vec4<f32>,  // start, end
+    fog_colour: vec4<f32>,
+    time: vec4<f32>,  // seconds since the game started
 };
 
 struct MeshUniform {
@@ -13,38 +16,86 @@ var<uniform> camera: CameraUniform;
 @group(1) @binding(0)
 var<uniform> meshInfo: MeshUniform;
 
+// See ModelVertex::pack for the layout.
 struct VertexInput {
-    @location(0) world_position: vec4<f32>,
-    @location(1) uv: vec2<f32>,
+    @location(0) position: u32,
+    @location(1) data: u32,
+    @location(2) tint: vec4<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) world_position: vec4<f32>,
     @location(1) uv: vec2<f32>,
+    @location(2) light: f32,
+    @location(3) @interpolate(flat) layer: u32,
+    @location(4) tint: vec3<f32>,
 }
 
+// The texture bindings (group 2) and the block_uv/sample_block functions are appended from
+// either texture_atlas.wgsl or texture_array.wgsl depending on what TextureAtlas decided to use.
+
 @vertex
 fn vs_main(
     model: VertexInput,
     @builtin(vertex_index) in_vertex_index: u32,
 ) -> VertexOutput {
+    let local = vec3<f32>(
+        f32(model.position & 1023u),
+        f32((model.position >> 10u) & 1023u),
+        f32((model.position >> 20u) & 1023u)
+    ) / 32.0;
+    let corner = model.position >> 30u;
+    let index = animate(model.data & 65535u);
+    let face = (model.data >> 16u) & 7u;
 
     var out: VertexOutput;
-    out.clip_position = camera.view_proj * meshInfo.transform * model.world_position;
-    out.world_position = meshInfo.transform * model.world_position;
-    out.uv = model.uv;
+    out.world_position = meshInfo.transform * vec4<f32>(local, 1.0);
+    out.clip_position = camera.view_proj * out.world_position;
+    out.uv = block_uv(index, corner);
+    out.layer = index;
+    out.light = f32(model.data >> 24u) / 255.0 * face_shade(face);
+    out.tint = model.tint.rgb;
     return out;
 }
 
-// these corrispond to texture_bind_group_layout
-@group(2) @binding(0)
-var t_diffuse: texture_2d<f32>;
-@group(2) @binding(1)
-var s_diffuse: sampler;
+// Animated textures have their frames at consecutive uv indexes and the mesh always has the first.
+fn animate(index: u32) -> u32 {
+    let frames = uvs.frames[index];
+    if frames.x <= 1.0 {
+        return index;
+    }
+    return index + u32(camera.time.x / frames.y) % u32(frames.x);
+}
+
+// Fake lighting so you can tell the sides of a block apart. Indexed by Direction (6 is quads that aren't the side of a cube).
+fn face_shade(face: u32) -> f32 {
+    switch face {
+        case 0u: { return 1.0; }  // Up
+        case 1u: { return 0.5; }  // Down
+        case 2u, 3u: { return 0.8; }  // North, South
+        case 4u, 5u: { return 0.65; }  // East, West
+        default: { return 0.9; }
+    }
+}
+
+fn apply_fog(colour: vec3<f32>, world_position: vec4<f32>) -> vec3<f32> {
+    let dist = distance(world_position.xyz, camera.view_pos.xyz);
+    let amount = clamp((dist - camera.fog.x) / (camera.fog.y - camera.fog.x), 0.0, 1.0);
+    return mix(colour, camera.fog_colour.rgb, amount);
+}
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    let object_colour = textureSample(t_diffuse, s_diffuse, in.uv);
-    return object_colour;
+    let object_colour = sample_block(in.uv, in.layer);
+    return vec4<f32>(apply_fog(object_colour.rgb * in.tint * in.light, in.world_position), object_colour.a);
+}
+
+@fragment
+fn fs_cutout(in: VertexOutput) -> @location(0) vec4<f32> {
+    let object_colour = sample_block(in.uv, in.layer);
+    if object_colour.a < 0.5 {
+        discard;
+    }
+    return vec4<f32>(apply_fog(object_colour.rgb * in.tint * in.light, in.world_position), 1.0);
 }
@@ -0,0 +1,23 @@
+// Every block texture is its own layer (the uv index) so they can have mipmaps.
+
+// these corrispond to texture_bind_group_layout
+@group(2) @binding(0)
+var t_diffuse: texture_2d_array<f32>;
+@group(2) @binding(1)
+var s_diffuse: sampler;
+@group(2) @binding(2)
+var<uniform> uvs: UvTable;
+
+// Same as texture_atlas.wgsl but only the frames are used.
+struct UvTable {
+    rects: array<vec4<f32>, 256>,
+    frames: array<vec4<f32>, 256>,
+};
+
+fn block_uv(index: u32, corner: u32) -> vec2<f32> {
+    return vec2<f32>(f32(corner & 1u), f32(corner >> 1u));
+}
+
+fn sample_block(uv: vec2<f32>, layer: u32) -> vec4<f32> {
+    return textureSample(t_diffuse, s_diffuse, uv, layer);
+}
@@ -0,0 +1,24 @@
+// Every block texture packed into one image. Used when texture arrays aren't available.
+
+// Indexed by ModelVertex::uv
+struct UvTable {
+    rects: array<vec4<f32>, 256>,  // xy is the top left corner and z is the size.
+    frames: array<vec4<f32>, 256>,  // frame count, seconds per frame (see gen::uvs::FRAMES)
+};
+
+// these corrispond to texture_bind_group_layout
+@group(2) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(2) @binding(1)
+var s_diffuse: sampler;
+@group(2) @binding(2)
+var<uniform> uvs: UvTable;
+
+fn block_uv(index: u32, corner: u32) -> vec2<f32> {
+    let rect = uvs.rects[index];
+    return rect.xy + vec2<f32>(f32(corner & 1u), f32(corner >> 1u)) * rect.z;
+}
+
+fn sample_block(uv: vec2<f32>, layer: u32) -> vec4<f32> {
+    return textureSample(t_diffuse, s_diffuse, uv);
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use instant::{Duration, Instant};
+
+/// Notices when files in a folder (and its subfolders) are added, removed or saved.
+/// Polls the modified times instead of asking the os so it doesn't need another dependency.
+pub struct FileWatcher {
+    dir: PathBuf,
+    extension: &'static str,
+    /// Set by only().
+    names: Option<&'static [&'static str]>,
+    times: HashMap<PathBuf, SystemTime>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    /// Walking the folder every frame would be a waste.
+    const INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Only looks at files ending with `extension` (without the dot).
+    pub fn new(dir: impl Into<PathBuf>, extension: &'static str) -> Self {
+        let dir = dir.into();
+        let mut times = HashMap::new();
+        scan(&dir, extension, None, &mut times);
+        FileWatcher {
+            dir,
+            extension,
+            names: None,
+            times,
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Ignores everything except files with these names (in any subfolder).
+    pub fn only(mut self, names: &'static [&'static str]) -> Self {
+        self.names = Some(names);
+        self.times.clear();
+        scan(&self.dir, self.extension, self.names, &mut self.times);
+        self
+    }
+
+    /// True if anything changed since the last time this returned true.
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < Self::INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let mut times = HashMap::with_capacity(self.times.len());
+        scan(&self.dir, self.extension, self.names, &mut times);
+        if times != self.times {
+            self.times = times;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// Files that can't be read are skipped. They'll count as changed once they can.
+fn scan(dir: &Path, extension: &str, names: Option<&[&str]>, times: &mut HashMap<PathBuf, SystemTime>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else { continue };
+        if meta.is_dir() {
+            scan(&path, extension, names, times);
+        } else if path.extension().is_some_and(|e| e == extension) {
+            if names.is_some_and(|names| !names.iter().any(|name| path.file_name().is_some_and(|file| file == *name))) {
+                continue;
+            }
+            if let Ok(modified) = meta.modified() {
+                times.insert(path, modified);
+            }
+        }
+    }
+}
+
+#[test]
+fn watch_files() {
+    let dir = std::env::temp_dir().join(format!("blockgame_watch_{}", std::process::id()));
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    fs::write(dir.join("a.png"), "a").unwrap();
+    let mut watcher = FileWatcher::new(&dir, "png");
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(!watcher.changed());
+
+    // Other extensions are ignored but subfolders aren't.
+    fs::write(dir.join("notes.txt"), "b").unwrap();
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(!watcher.changed());
+    fs::write(dir.join("sub/b.png"), "b").unwrap();
+    assert!(!watcher.changed(), "Checked again too soon.");
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(watcher.changed());
+
+    fs::remove_file(dir.join("a.png")).unwrap();
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(watcher.changed());
+
+    // Narrowed down to some names the rest are ignored.
+    let mut watcher = FileWatcher::new(&dir, "png").only(&["a.png"]);
+    fs::write(dir.join("sub/b.png"), "bb").unwrap();
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(!watcher.changed());
+    fs::write(dir.join("sub/a.png"), "a").unwrap();
+    watcher.last_check -= FileWatcher::INTERVAL;
+    assert!(watcher.changed());
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -4,34 +4,20 @@ use std::slice;
 use std::mem::size_of;
 use instant::Instant;
 use image::{GenericImageView};
+use glam::Vec3;
+use common::atlas::UvIndex;
+use common::input::Action;
+use crate::input::KeyBindings;
 
 use wgpu::PresentMode;
 use winit::dpi::PhysicalSize;
-use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent};
+use winit::event::{DeviceEvent, ElementState, Event, KeyboardInput, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::{CursorGrabMode, Window, WindowBuilder};
 use wgpu::*;
 use wgpu::util::DeviceExt;
 
 
-pub struct Mesh {
-    pub vertex_buffer: wgpu::Buffer,
-    pub index_buffer: wgpu::Buffer,
-    pub num_elements: u32,
-    pub transform: MeshUniform,
-    pub(crate) info_buffer: Buffer,
-    pub(crate) info_bind_group: BindGroup,
-}
-
-impl Mesh {
-    pub fn render<'a>(&'a self, render_pass: &mut RenderPass<'a>) {
-        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
-        render_pass.set_bind_group(1, &self.info_bind_group, &[]);
-        render_pass.draw_indexed(0..self.num_elements, 0, 0..1);
-    }
-}
-
 #[repr(C)]
 #[derive(Copy, Clone, Debug, Default)]
 pub struct MeshUniform {
@@ -46,12 +32,16 @@ pub struct WindowContext {
     pub size: RefCell<PhysicalSize<u32>>,
     pub window: Window,
     pub timer: RefCell<FrameTimer>,
+    pub downlevel: DownlevelFlags,
+    pub bindings: KeyBindings,
 }
 
 pub struct FrameTimer {
     pub frame_count: i32,
     pub micro_seconds: u128,
     pub last: Instant,
+    /// Smoothed over the last few frames so it's readable on screen (the printed numbers only change every 5 seconds).
+    pub frame_time_ms: f32,
 }
 
 impl FrameTimer {
@@ -60,12 +50,15 @@ impl FrameTimer {
             frame_count: 0,
             micro_seconds: 0,
             last: Instant::now(),
+            frame_time_ms: 0.0,
         }
     }
 
     pub fn update(&mut self){
         let now = Instant::now();
-        self.micro_seconds += self.last.elapsed().as_micros();
+        let elapsed = self.last.elapsed();
+        self.frame_time_ms = self.frame_time_ms * 0.95 + elapsed.as_secs_f32() * 1000.0 * 0.05;
+        self.micro_seconds += elapsed.as_micros();
         self.last = now;
         self.frame_count += 1;
 
@@ -91,7 +84,8 @@ pub trait App {
     // winit::event::WindowEvent::CursorMoved warns against using it for 3D camera control so now we have two event callbacks
     fn handle_device_event(&mut self, event: &DeviceEvent);
     /// Just called before render. But there's a semantic separation and I think it's nice to put it in the same impl block.
-    fn update(&mut self);
+    /// Return false to close the window (after this frame is rendered).
+    fn update(&mut self) -> bool;
     fn render(&mut self) -> Result<(), wgpu::SurfaceError>;
     fn resize(&mut self, new_size: PhysicalSize<u32>);
 }
@@ -146,8 +140,9 @@ impl WindowContext {
             .copied().find(|f| f.is_srgb())
             .unwrap_or(surface_caps.formats[0]);
 
+        // Copying out of the surface is only needed for screenshots so don't ask for it if it's not supported.
         let config = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC),
             format: surface_format,
             width: size.width,
             height: size.height,
@@ -159,13 +154,15 @@ impl WindowContext {
         surface.configure(&device, &config);
 
         (Rc::new(WindowContext {
+            downlevel: adapter.get_downlevel_capabilities().flags,
             window,
             surface,
             device,
             queue,
             config: RefCell::new(config),
             size: RefCell::new(size),
-            timer: RefCell::new(FrameTimer::new())
+            timer: RefCell::new(FrameTimer::new()),
+            bindings: KeyBindings::load(),
         }), event_loop)
     }
 
@@ -185,32 +182,31 @@ impl WindowContext {
                 ref event,
                 window_id,
             } if window_id == ctx.window.id() => if !app.handle_window_event(event) {
-                match event {
-                    WindowEvent::CloseRequested
-                    | WindowEvent::KeyboardInput {
+                let action = match event {
+                    WindowEvent::KeyboardInput {
                         input:
                         KeyboardInput {
                             state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::Escape),
+                            virtual_keycode: Some(key),
                             ..
                         },
                         ..
-                    } => *control_flow = ControlFlow::Exit,
+                    } => ctx.bindings.action(*key),
+                    _ => None,
+                };
+                match event {
+                    WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
                     WindowEvent::Resized(physical_size) => {
                         app.resize(*physical_size)
                     }
                     WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
                         app.resize(**new_inner_size)
                     }
-                    WindowEvent::KeyboardInput {
-                        input:
-                        KeyboardInput {
-                            state: ElementState::Pressed,
-                            virtual_keycode: Some(VirtualKeyCode::V),
-                            ..
-                        },
-                        ..
-                    } => {
+                    _ => {}
+                }
+                match action {
+                    Some(Action::Quit) => *control_flow = ControlFlow::Exit,
+                    Some(Action::ToggleVsync) => {
                         vsync_on = !vsync_on;
                         ctx.config.borrow_mut().present_mode = if vsync_on { PresentMode::AutoVsync } else { PresentMode::AutoNoVsync };
                         let size = { *ctx.size.borrow() };
@@ -222,7 +218,9 @@ impl WindowContext {
                 }
             },
             Event::RedrawRequested(window_id) if window_id == ctx.window.id() => {
-                app.update();
+                if !app.update() {
+                    *control_flow = ControlFlow::Exit;
+                }
                 match app.render() {
                     Ok(_) => {}
                     Err(wgpu::SurfaceError::Lost) => {
@@ -294,6 +292,15 @@ impl WindowContext {
         )
     }
 
+    pub fn buffer_empty(&self, label: &str, size: u64, usage: BufferUsages) -> Buffer {
+        self.device.create_buffer(&BufferDescriptor {
+            label: Some(&*concat(label, "Buffer")),
+            size,
+            usage,
+            mapped_at_creation: false,
+        })
+    }
+
     pub fn bind_group_layout_buffer(&self, label: &str, entries: &[(ShaderStages, BufferBindingType)]) -> BindGroupLayout {
         let entries: Vec<_> = entries.iter().enumerate().map(|(i, (visibility, ty))| {
             BindGroupLayoutEntry {
@@ -314,6 +321,23 @@ impl WindowContext {
         })
     }
 
+    // One uniform buffer holding many values. Each draw picks which one with a dynamic offset.
+    pub fn bind_group_layout_dynamic(&self, label: &str, visibility: ShaderStages, size: u64) -> BindGroupLayout {
+        self.device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[BindGroupLayoutEntry {
+                binding: 0,
+                visibility,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: BufferSize::new(size),
+                },
+                count: None,
+            }],
+            label: Some(&*concat(label, "Bind Group Layout")),
+        })
+    }
+
     pub fn bind_group(&self, label: &str, layout: &BindGroupLayout, entries: &[BindingResource]) -> BindGroup {
         let entries: Vec<_> = entries.iter().enumerate().map(|(i, e)| {
             BindGroupEntry {
@@ -329,46 +353,50 @@ impl WindowContext {
         })
     }
 
-    pub fn bind_group_layout_texture(&self) -> BindGroupLayout {
-        self.device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Texture {
-                        multisampled: false,
-                        view_dimension: TextureViewDimension::D2,
-                        sample_type: TextureSampleType::Float { filterable: true },  // same
-                    },
-                    count: None,
+    /// Entity models have real uvs so they don't need the uv table.
+    pub fn bind_group_layout_texture(&self, view_dimension: TextureViewDimension, uv_table: bool) -> BindGroupLayout {
+        let entries = [
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Texture {
+                    multisampled: false,
+                    view_dimension,
+                    sample_type: TextureSampleType::Float { filterable: true },  // same
                 },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStages::FRAGMENT,
-                    ty: BindingType::Sampler(SamplerBindingType::Filtering),   // as here
-                    count: None,
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStages::FRAGMENT,
+                ty: BindingType::Sampler(SamplerBindingType::Filtering),   // as here
+                count: None,
+            },
+            // Table of uv rectangles so vertices only need to store an index.
+            BindGroupLayoutEntry {
+                binding: 2,
+                visibility: ShaderStages::VERTEX,
+                ty: BindingType::Buffer {
+                    ty: BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
                 },
-                // TODO: why was this here?
-                // BindGroupLayoutEntry {
-                //     binding: 2,
-                //     visibility: ShaderStages::FRAGMENT,
-                //     ty: BindingType::Buffer {
-                //         ty: BufferBindingType::Uniform,
-                //         has_dynamic_offset: false,
-                //         min_binding_size: None,
-                //     },
-                //     count: None,
-                // }
-            ],
+                count: None,
+            }
+        ];
+        self.device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: if uv_table { &entries } else { &entries[..2] },
             label: Some("texture_bind_group_layout"),
         })
     }
 
-    pub fn bind_group_texture(&self, layout: &BindGroupLayout, texture: &Texture) -> BindGroup {
-        self.bind_group("texture", layout, &[
+    pub fn bind_group_texture(&self, layout: &BindGroupLayout, texture: &Texture, uv_table: Option<&Buffer>) -> BindGroup {
+        let mut entries = vec![
             wgpu::BindingResource::TextureView(&texture.view),
-            wgpu::BindingResource::Sampler(&texture.sampler)
-        ])
+            wgpu::BindingResource::Sampler(&texture.sampler),
+        ];
+        entries.extend(uv_table.map(|buffer| buffer.as_entire_binding()));
+        self.bind_group("texture", layout, &entries)
     }
 
     // This could go right in create_render_pipeline but maybe its good to let you reuse layouts.
@@ -382,7 +410,7 @@ impl WindowContext {
         )
     }
 
-    pub fn render_pipeline(&self, label: &str, layout: &PipelineLayout, vertex_layouts: &[VertexBufferLayout], shader: &str) -> RenderPipeline {
+    pub fn render_pipeline(&self, label: &str, layout: &PipelineLayout, vertex_layouts: &[VertexBufferLayout], shader: &str, options: PipelineOptions) -> RenderPipeline {
         let shader = self.device.create_shader_module(ShaderModuleDescriptor {
             label: Some(&*concat(label, "Render Shader")),
             source: ShaderSource::Wgsl(shader.into()),
@@ -397,23 +425,15 @@ impl WindowContext {
             },
             fragment: Some(FragmentState {
                 module: &shader,
-                entry_point: "fs_main",
+                entry_point: options.fs_entry,
                 targets: &[Some(ColorTargetState {
                     format: self.config.borrow().format,
-                    // TODO: is this slower than REPLACE? Is it worth having two pipelines where one does things that I know don't have transparency and then you draw the rest on top?
-                    blend: Some(BlendState {
-                        color: BlendComponent {
-                            src_factor: BlendFactor::SrcAlpha,
-                            dst_factor: BlendFactor::OneMinusSrcAlpha,
-                            operation: BlendOperation::Add,
-                        },
-                        alpha: BlendComponent::OVER,
-                    }),
+                    blend: options.blend,
                     write_mask: ColorWrites::ALL,
                 })],
             }),
             primitive: PrimitiveState {
-                topology: PrimitiveTopology::TriangleList,
+                topology: options.topology,
                 strip_index_format: None,
                 front_face: FrontFace::Ccw,
                 cull_mode: None,// Some(Face::Back),  // TODO: currently rendering both sides of triangles
@@ -423,8 +443,8 @@ impl WindowContext {
             },
             depth_stencil: Some(DepthStencilState {
                 format: Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: CompareFunction::Less,
+                depth_write_enabled: options.depth_write,
+                depth_compare: options.depth_compare,
                 stencil: StencilState::default(),
                 bias: DepthBiasState::default(),
             }),
@@ -438,6 +458,18 @@ impl WindowContext {
         })
     }
 
+    /// Like render_pipeline but a shader that doesn't compile (or doesn't match the layout) is returned
+    /// as an error instead of crashing. Used for hot reloading so a typo doesn't lose your game.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn try_render_pipeline(&self, label: &str, layout: &PipelineLayout, vertex_layouts: &[VertexBufferLayout], shader: &str, options: PipelineOptions) -> Result<RenderPipeline, Error> {
+        self.device.push_error_scope(ErrorFilter::Validation);
+        let pipeline = self.render_pipeline(label, layout, vertex_layouts, shader, options);
+        match pollster::block_on(self.device.pop_error_scope()) {
+            Some(error) => Err(error),
+            None => Ok(pipeline),
+        }
+    }
+
     pub fn command_encoder(&self, label: &str) -> CommandEncoder {
         self.device.create_command_encoder(&CommandEncoderDescriptor {
             label: Some(&*concat(label, "Command Encoder")),
@@ -452,14 +484,7 @@ impl WindowContext {
                     view: screen_texture,
                     resolve_target: None,
                     ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(
-                            wgpu::Color {
-                                r: 0.1,
-                                g: 0.2,
-                                b: 0.3,
-                                a: 1.0,
-                            }
-                        ),
+                        load: wgpu::LoadOp::Clear(SKY_COLOUR),
                         store: StoreOp::Store,
                     }
                 })
@@ -478,6 +503,73 @@ impl WindowContext {
     }
 }
 
+/// The parts of a RenderPipeline that change between passes.
+#[derive(Copy, Clone, Debug)]
+pub struct PipelineOptions<'a> {
+    pub fs_entry: &'a str,
+    pub blend: Option<BlendState>,
+    pub depth_write: bool,
+    pub depth_compare: CompareFunction,
+    pub topology: PrimitiveTopology,
+}
+
+impl<'a> PipelineOptions<'a> {
+    /// Nothing can be seen through it so there's no need to blend.
+    pub const OPAQUE: PipelineOptions<'static> = PipelineOptions {
+        fs_entry: "fs_main",
+        blend: Some(BlendState::REPLACE),
+        depth_write: true,
+        depth_compare: CompareFunction::Less,
+        topology: PrimitiveTopology::TriangleList,
+    };
+
+    /// Like opaque but transparent pixels are discarded so you can see through holes (fs_cutout does the alpha test).
+    pub const CUTOUT: PipelineOptions<'static> = PipelineOptions {
+        fs_entry: "fs_cutout",
+        blend: Some(BlendState::REPLACE),
+        depth_write: true,
+        depth_compare: CompareFunction::Less,
+        topology: PrimitiveTopology::TriangleList,
+    };
+
+    /// Drawn after everything opaque, so it doesn't write depth and things behind it still show up.
+    pub const TRANSLUCENT: PipelineOptions<'static> = PipelineOptions {
+        fs_entry: "fs_main",
+        blend: Some(BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::OneMinusSrcAlpha,
+                operation: BlendOperation::Add,
+            },
+            alpha: BlendComponent::OVER,
+        }),
+        depth_write: false,
+        depth_compare: CompareFunction::Less,
+        topology: PrimitiveTopology::TriangleList,
+    };
+
+    /// 2D things on top of the world (see Overlay). Blends like translucent but ignores depth completely.
+    pub const OVERLAY: PipelineOptions<'static> = PipelineOptions {
+        depth_compare: CompareFunction::Always,
+        ..Self::TRANSLUCENT
+    };
+
+    /// Lines around the block you're looking at (see SelectionOutline). LessEqual so they still show where they touch the block's faces.
+    pub const OUTLINE: PipelineOptions<'static> = PipelineOptions {
+        depth_compare: CompareFunction::LessEqual,
+        topology: PrimitiveTopology::LineList,
+        ..Self::TRANSLUCENT
+    };
+}
+
+/// Background colour. Fog fades to this so the edge of the loaded world blends in.
+pub const SKY_COLOUR: Color = Color {
+    r: 0.1,
+    g: 0.2,
+    b: 0.3,
+    a: 1.0,
+};
+
 fn concat<'a>(a: &'a str, b: &'a str) -> String {
     let s = String::from(a) + " " + b;
     s
@@ -546,6 +638,79 @@ impl Texture {
         Self { texture, view, sampler }
     }
 
+    /// Each layer is a square stacked vertically in the image. Mipmaps are generated for each layer separately.
+    pub(crate) fn array_from_image(device: &wgpu::Device, queue: &wgpu::Queue, img: &image::DynamicImage, label: Option<&str>) -> Self {
+        let rgba = img.to_rgba8();
+        let layer_size = rgba.width() as usize;
+        let layer_count = rgba.height() as usize / layer_size;
+        let layer_bytes = layer_size * layer_size * 4;
+
+        // levels[mip] is every layer at that size, one after another, which is the layout write_texture wants.
+        let mut levels = vec![rgba.as_raw().clone()];
+        for layer in rgba.as_raw().chunks_exact(layer_bytes) {
+            for (i, mip) in common::atlas::mip_chain(layer, layer_size).into_iter().enumerate() {
+                if levels.len() <= i + 1 {
+                    levels.push(Vec::with_capacity(mip.len() * layer_count));
+                }
+                levels[i + 1].extend(mip);
+            }
+        }
+
+        let size = wgpu::Extent3d {
+            width: layer_size as u32,
+            height: layer_size as u32,
+            depth_or_array_layers: layer_count as u32,
+        };
+
+        let texture = device.create_texture(
+            &wgpu::TextureDescriptor {
+                label,
+                size,
+                mip_level_count: levels.len() as u32,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba8UnormSrgb,
+                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+                view_formats: &[],
+            }
+        );
+
+        for (mip, data) in levels.iter().enumerate() {
+            let mip_size = size.mip_level_size(mip as u32, wgpu::TextureDimension::D2);
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    texture: &texture,
+                    mip_level: mip as u32,
+                    origin: wgpu::Origin3d::ZERO,
+                    aspect: wgpu::TextureAspect::All,
+                },
+                data,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * mip_size.width),
+                    rows_per_image: Some(mip_size.height),
+                },
+                mip_size,
+            );
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Nearest,  // Still sharp pixels up close.
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Linear,  // But blend between mip levels far away so it doesn't shimmer.
+            ..Default::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+
     pub fn create_depth_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration, label: &str) -> Self {
         let size = wgpu::Extent3d {
             width: config.width,
@@ -602,13 +767,110 @@ pub fn slice_to_bytes<T>(p: &[T]) -> &[u8] {
     }
 }
 
+/// A chunk mesh vertex packed into 12 bytes. Decoded by vs_main in shader.wgsl so keep them in sync.
+/// - position: x, y, z as 10 bit fixed point (1/32 of a block), then 2 bits for which corner of the uv square.
+/// - data: 16 bit index into gen::uvs::ALL, 3 bit face direction, 5 unused bits, 8 bit light level.
+/// - tint: rgba multiplied with the texture (the biome colour for grass and leaves, otherwise white).
 #[repr(C)]
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct ModelVertex {
-    pub position: [f32; 4],  // 4th is ignored (not even used as w in shader!)
-    pub uv: [f32; 2]
+    pub position: u32,
+    pub data: u32,
+    pub tint: [u8; 4],
+}
+
+/// A corner of a flat coloured shape in the overlay. Position is in pixels from the top left of the window.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct OverlayVertex {
+    pub position: [f32; 2],
+    pub colour: [u8; 4],
+}
+
+impl OverlayVertex {
+    pub const ATTRIBS: &'static [VertexAttribute] = &wgpu::vertex_attr_array![0 => Float32x2, 1 => Unorm8x4];
+}
+
+/// A corner of an entity model in its rest pose. Each model uploads these once (see EntityRender).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct EntityVertex {
+    /// In blocks, relative to the model's origin. The instance transform includes the bone's pose.
+    pub position: [f32; 3],
+    /// In the atlas.
+    pub uv: [f32; 2],
+    pub normal: [f32; 3],
+}
+
+impl EntityVertex {
+    pub const ATTRIBS: &'static [VertexAttribute] = &wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x2, 2 => Float32x3];
+}
+
+/// Where one bone of one entity is this frame. Rebuilt every frame. Model space to world space.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct EntityInstance {
+    pub transform: [[f32; 4]; 4],
+}
+
+impl EntityInstance {
+    /// Comes after EntityVertex's.
+    pub const ATTRIBS: &'static [VertexAttribute] = &wgpu::vertex_attr_array![3 => Float32x4, 4 => Float32x4, 5 => Float32x4, 6 => Float32x4];
 }
 
 impl ModelVertex {
-    pub const ATTRIBS: &'static [VertexAttribute] = &wgpu::vertex_attr_array![0 => Float32x4, 1 => Float32x2];
+    pub const ATTRIBS: &'static [VertexAttribute] = &wgpu::vertex_attr_array![0 => Uint32, 1 => Uint32, 2 => Unorm8x4];
+
+    /// Positions are rounded to this fraction of a block.
+    pub const SUBDIVISIONS: f32 = 32.0;
+    /// Face for quads that aren't the side of a cube (like the cross of a sapling).
+    pub const NO_FACE: u8 = 6;
+    pub const FULL_LIGHT: u8 = 255;
+    pub const NO_TINT: [u8; 4] = [255; 4];
+
+    /// Corners are 0=top_left, 1=top_right, 2=bottom_left, 3=bottom_right (same order as Uv's methods).
+    pub fn pack(pos: Vec3, corner: u8, uv: UvIndex, face: u8, light: u8, tint: [u8; 4]) -> Self {
+        debug_assert!(pos.min_element() >= 0.0 && pos.max_element() < (1024.0 / Self::SUBDIVISIONS), "Vertex {:?} out of range.", pos);
+        debug_assert!(corner < 4 && face <= Self::NO_FACE);
+        let fixed = (pos * Self::SUBDIVISIONS).round().as_uvec3();
+        ModelVertex {
+            position: fixed.x | (fixed.y << 10) | (fixed.z << 20) | ((corner as u32) << 30),
+            data: (uv.0 as u32) | ((face as u32) << 16) | ((light as u32) << 24),
+            tint,
+        }
+    }
+
+    pub fn pos(&self) -> Vec3 {
+        let p = self.position;
+        Vec3::new((p & 1023) as f32, ((p >> 10) & 1023) as f32, ((p >> 20) & 1023) as f32) / Self::SUBDIVISIONS
+    }
+
+    pub fn corner(&self) -> u8 {
+        (self.position >> 30) as u8
+    }
+
+    pub fn uv(&self) -> UvIndex {
+        UvIndex((self.data & 0xFFFF) as u16)
+    }
+
+    pub fn face(&self) -> u8 {
+        ((self.data >> 16) & 7) as u8
+    }
+
+    pub fn light(&self) -> u8 {
+        (self.data >> 24) as u8
+    }
+}
+
+#[test]
+fn vertex_packing() {
+    let pos = Vec3::new(16.0, 0.5, 3.8125);
+    let v = ModelVertex::pack(pos, 3, UvIndex(200), 5, 17, [1, 2, 3, 4]);
+    assert_eq!(v.pos(), pos);
+    assert_eq!(v.corner(), 3);
+    assert_eq!(v.uv(), UvIndex(200));
+    assert_eq!(v.face(), 5);
+    assert_eq!(v.light(), 17);
+    assert_eq!(v.tint, [1, 2, 3, 4]);
+    assert_eq!(size_of::<ModelVertex>(), 12);
 }
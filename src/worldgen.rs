@@ -1,63 +1,220 @@
 use std::cell::UnsafeCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
 use common::pos::Tile;
-use crate::chunk_mesh::ChunkList;
+use crate::renderer::Renderer;
 use crate::gen;
-use crate::pos::{BlockPos, Chunk, ChunkPos};
+use glam::Vec3;
+use crate::biome::Biome;
+use crate::meshing::{MeshJob, MeshWorkers};
+use crate::pos::{BlockPos, Chunk, CHUNK_SIZE, ChunkPos, DirSet, Direction, LocalPos};
+use crate::profiler;
+use crate::worldgen::noise::{hash, value};
 use crate::worldgen::rand::{random_numbers, random_seed};
 
+/// A block found by LogicChunks::raycast.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RayHit {
+    pub block: BlockPos,
+    pub tile: Tile,
+    /// The side the ray went in through. None if it started inside the block.
+    pub face: Option<Direction>,
+    /// How far along the ray it went in (0 if it started inside).
+    pub distance: f32,
+}
+
 pub struct LogicChunks {
     pub(crate) chunks: HashMap<ChunkPos, Box<UnsafeCell<Chunk>>>,
+    /// Changing it only affects chunks generated after.
+    pub seed: u32,
+    /// How many chunks have been generated in total (for benchmarks).
+    pub generated: u64,
+    pub(crate) meshing: MeshWorkers,
+    /// Goes up with every mesh job so a result can tell if its chunk changed (or was unloaded and generated again) since.
+    mesh_version: u64,
+    /// Results thrown away because they were out of date by the time they finished.
+    pub stale_meshes: u64,
 }
 
 impl LogicChunks {
+    // TODO: pick a new one for each world once they can be saved.
+    pub const DEFAULT_SEED: u32 = 1234;
+
     pub fn new() -> Self {
         LogicChunks {
             chunks: Default::default(),
+            seed: Self::DEFAULT_SEED,
+            generated: 0,
+            meshing: MeshWorkers::new(MeshWorkers::default_threads()),
+            mesh_version: 0,
+            stale_meshes: 0,
         }
     }
 
-    pub fn update_meshes(&self, render: &mut ChunkList) {
+    /// Sends every changed chunk off to be meshed and gives the renderer any meshes that are done.
+    pub fn update_meshes(&mut self, render: &mut dyn Renderer) {
+        let mut dirty = HashSet::new();
         for (pos, chunk) in self.chunks.iter() {
             let chunk = unsafe {&*chunk.get() };
             if chunk.dirty.get() {
-                render.update_mesh(*pos, chunk);
-                chunk.dirty.set(false);
+                dirty.insert(*pos);
+                let mut edges = chunk.edges.get();
+                for dir in Direction::ALL {
+                    if edges.contains(dir) {
+                        dirty.insert(pos.offset(dir));
+                    }
+                }
+            }
+        }
+        for pos in dirty {
+            if self.chunks.contains_key(&pos) {
+                self.submit_mesh(pos);
+            }
+        }
+        self.finish_meshes(render);
+    }
+
+    // Meshes a copy so lua can keep changing the chunk while it's being done.
+    fn submit_mesh(&mut self, pos: ChunkPos) {
+        self.mesh_version += 1;
+        let chunk = unsafe { &mut *self.chunks[&pos].get() };
+        chunk.dirty.set(false);
+        chunk.edges.set(DirSet::empty());
+        chunk.mesh_version = self.mesh_version;
+        let neighbours = Direction::ALL.map(|dir| {
+            self.chunks.get(&pos.offset(dir)).map(|neighbour| Box::new(unsafe { &*neighbour.get() }.clone()))
+        });
+        self.meshing.submit(MeshJob { pos, version: self.mesh_version, chunk: Box::new(chunk.clone()), neighbours });
+    }
+
+    /// Gives the renderer the meshes that are done. Ones for chunks that changed or unloaded since the job was sent are dropped.
+    pub fn finish_meshes(&mut self, render: &mut dyn Renderer) {
+        for result in self.meshing.finished() {
+            let current = self.chunks.get(&result.pos).map(|chunk| unsafe { &*chunk.get() }.mesh_version);
+            if current == Some(result.version) {
+                render.update_chunk(result.pos, &result.layers);
+            } else {
+                self.stale_meshes += 1;
+            }
+        }
+    }
+
+    /// Meshes these again (like ones the renderer dropped to stay in budget). Any that unloaded since are skipped.
+    pub fn remesh(&mut self, chunks: &[ChunkPos], render: &mut dyn Renderer) {
+        for pos in chunks {
+            if let Some(chunk) = self.chunks.get(pos) {
+                unsafe { &*chunk.get() }.dirty.set(true);
             }
         }
+        self.update_meshes(render);
+    }
+
+    /// Rebuilds the mesh of every loaded chunk (like after the textures change).
+    #[cfg(feature = "hot_reload")]
+    pub fn remesh_all(&mut self, render: &mut dyn Renderer) {
+        for chunk in self.chunks.values() {
+            unsafe { &*chunk.get() }.dirty.set(true);
+        }
+        self.update_meshes(render);
     }
 
-    pub fn get_or_gen(&mut self, pos: ChunkPos, render: &mut ChunkList) -> *mut Chunk {
+    pub fn get_or_gen(&mut self, pos: ChunkPos, render: &mut dyn Renderer) -> *mut Chunk {
         if let Some(chunk) = self.chunks.get(&pos) {
             return chunk.get();
         }
 
         let mut chunk = Chunk::full(gen::tiles::empty, pos);
-        generate(&mut chunk);
-        render.update_mesh(pos, &chunk);
+        {
+            let _span = profiler::span("worldgen");
+            generate(&mut chunk, self.seed);
+        }
+        self.generated += 1;
         let chunk = Box::new(UnsafeCell::new(chunk));
         let ptr = chunk.get();
         self.chunks.insert(pos, chunk);
+        self.submit_mesh(pos);
+        self.finish_meshes(render);
 
         ptr
     }
 
+    /// None if the chunk isn't loaded.
+    pub fn get_block(&self, pos: BlockPos) -> Option<Tile> {
+        let (chunk, local) = pos.split();
+        let chunk = self.chunks.get(&chunk)?;
+        Some(unsafe { &*chunk.get() }.get(local))
+    }
+
+    /// Steps through every block the ray touches in order until one isn't empty (Amanatides & Woo).
+    /// Unloaded chunks count as empty.
+    pub fn raycast(&self, origin: Vec3, dir: Vec3, max_distance: f32) -> Option<RayHit> {
+        let dir = dir.normalize();
+        let mut block = origin.floor();
+        // How far along the ray you need to go to cross one block on each axis, and to reach the next crossing.
+        let delta = dir.recip().abs();
+        let mut next = Vec3::ZERO;
+        for axis in 0..3 {
+            next[axis] = if dir[axis] > 0.0 {
+                (block[axis] + 1.0 - origin[axis]) * delta[axis]
+            } else if dir[axis] < 0.0 {
+                (origin[axis] - block[axis]) * delta[axis]
+            } else {
+                f32::INFINITY
+            };
+        }
+
+        // Which face you go into the next block through when moving positive/negative on each axis.
+        const ENTER: [(Direction, Direction); 3] = [
+            (Direction::South, Direction::North),
+            (Direction::Down, Direction::Up),
+            (Direction::West, Direction::East),
+        ];
+
+        let mut face = None;
+        let mut distance = 0.0;
+        loop {
+            let pos = BlockPos::containing(block);
+            if let Some(tile) = self.get_block(pos) {
+                if !tile.empty() {
+                    return Some(RayHit { block: pos, tile, face, distance });
+                }
+            }
+
+            let axis = if next.x < next.y && next.x < next.z { 0 } else if next.y < next.z { 1 } else { 2 };
+            if next[axis] > max_distance {
+                return None;
+            }
+            let positive = dir[axis] > 0.0;
+            block[axis] += if positive { 1.0 } else { -1.0 };
+            distance = next[axis];
+            next[axis] += delta[axis];
+            face = Some(if positive { ENTER[axis].0 } else { ENTER[axis].1 });
+        }
+    }
+
     pub fn get_rand(&mut self) -> *mut Chunk {
         let choice = random_numbers(random_seed()).next().unwrap() as usize % self.chunks.len();
         self.chunks.iter().nth(choice).unwrap().1.get()
     }
 
+    /// How many chunks past the render distance stay loaded, so walking back and forth over a border doesn't keep
+    /// regenerating them. Lua unloads the ring at this distance as the player moves and gc catches any it skipped.
+    pub const UNLOAD_MARGIN: u32 = 3;
+
+    pub fn unload_distance(render: &dyn Renderer) -> u32 {
+        render.render_distance() + Self::UNLOAD_MARGIN
+    }
+
     // TODO: unload logic chunks at some distance too.
-    pub fn gc(&mut self, player: BlockPos, render: &mut ChunkList) {
-        let unload_radius = 10;
+    pub fn gc(&mut self, player: BlockPos, render: &mut dyn Renderer) {
+        let unload_radius = Self::unload_distance(render);
         let player = player.chunk();
 
         let mut count = 0;
         self.chunks.retain(|pos, _| {
             if player.axis_distance(pos) > unload_radius {
                 count += 1;
-                render.remove(*pos);
+                render.remove_chunk(*pos);
                 false
             } else {
                 true
@@ -70,21 +227,114 @@ impl LogicChunks {
     #[cfg(feature = "profiling")]
     pub fn log_profile(&self) {
         // MB does not include map overhead
-        println!("ChunkLogic:\n  - loaded: {}\n  - core MB: {}", self.chunks.len(), self.chunks.len() * size_of::<Chunk>() / 1024 / 1024);
+        println!("ChunkLogic:\n  - loaded: {}\n  - core MB: {}\n  - meshing: {}\n  - stale meshes: {}", self.chunks.len(), self.chunks.len() * size_of::<Chunk>() / 1024 / 1024, self.meshing.pending, self.stale_meshes);
     }
 }
 
-pub fn generate(chunk: &mut Chunk) {
-    if chunk.pos.y < 0 {
-        let mut n = random_numbers((chunk.pos.x + chunk.pos.y + chunk.pos.z).unsigned_abs());
-        let normal = [gen::tiles::stone, gen::tiles::dirt, gen::tiles::grass, gen::tiles::log, gen::tiles::leaf];
-        let block = (n.next().unwrap() as usize % normal.len());
-        for pos in chunk.tiles.iter_mut() {
-            *pos = normal[block];
+/// The top block of the ground and the biome of a column of the world. Only depends on the block position
+/// so neighbouring chunks line up no matter which is generated first.
+pub fn column(bx: i32, bz: i32, seed: u32) -> (i32, Biome) {
+    let hills = value(bx, bz, 48.0, seed) * 0.7 + value(bx, bz, 12.0, seed + 1) * 0.3;
+    let height = ((hills - 0.5) * 16.0).round() as i32 - 1;
+    let temperature = value(bx, bz, 200.0, seed + 2);
+    let humidity = value(bx, bz, 150.0, seed + 3);
+    (height, Biome::pick(temperature, humidity))
+}
+
+pub fn generate(chunk: &mut Chunk, seed: u32) {
+    let size = CHUNK_SIZE as i32;
+    let (base_x, base_y, base_z) = (chunk.pos.x * size, chunk.pos.y * size, chunk.pos.z * size);
+    for x in 0..CHUNK_SIZE {
+        for z in 0..CHUNK_SIZE {
+            let (bx, bz) = (base_x + x as i32, base_z + z as i32);
+            let (height, biome) = column(bx, bz, seed);
+            chunk.set_biome(x, z, biome);
+            for y in 0..CHUNK_SIZE {
+                let by = base_y + y as i32;
+                let tile = if by > height {
+                    continue;
+                } else if by == height {
+                    biome.surface()
+                } else if by > height - 4 {
+                    gen::tiles::dirt
+                } else {
+                    gen::tiles::stone
+                };
+                chunk.set(LocalPos::new(x, y, z), tile);
+            }
+
+            // Decorations only depend on their column so ones that reach up into the chunk above get the rest
+            // drawn when that one generates. They can't spread sideways since those neighbours might already be meshed.
+            let surface = height - base_y;
+            if surface < -TREE_HEIGHT || surface >= size || biome.surface() != gen::tiles::grass {
+                continue;
+            }
+            let roll = hash(bx, bz, seed + 4);
+            let edge = x < 2 || z < 2 || x >= CHUNK_SIZE - 2 || z >= CHUNK_SIZE - 2;
+            if roll < biome.tree_chance() && !edge {
+                tree(chunk, x, surface + 1, z);
+            } else if roll < biome.tree_chance() + biome.plant_chance() && surface >= -1 && surface < size - 1 {
+                chunk.set(LocalPos::new(x, (surface + 1) as usize, z), gen::tiles::sapling);
+            }
         }
     }
 }
 
+const TREE_HEIGHT: i32 = 6;
+
+/// A trunk from (x, y, z) up with leaves around the top. Leaves don't replace blocks already there.
+/// The y is relative to the chunk and can be outside it. Only the part inside is placed.
+fn tree(chunk: &mut Chunk, x: usize, y: i32, z: usize) {
+    let inside = |y: i32| (0..CHUNK_SIZE as i32).contains(&y);
+    for dy in 0..4 {
+        if inside(y + dy) {
+            chunk.set(LocalPos::new(x, (y + dy) as usize, z), gen::tiles::log);
+        }
+    }
+    for dy in 2..TREE_HEIGHT {
+        if !inside(y + dy) {
+            continue;
+        }
+        let radius = if dy < 4 { 2 } else { 1 };
+        for lx in (x - radius)..=(x + radius) {
+            for lz in (z - radius)..=(z + radius) {
+                let corner = lx.abs_diff(x) == radius && lz.abs_diff(z) == radius;
+                let pos = LocalPos::new(lx, (y + dy) as usize, lz);
+                if !corner && chunk.get(pos).empty() {
+                    chunk.set(pos, gen::tiles::leaf);
+                }
+            }
+        }
+    }
+}
+
+/// Smooth random values for worldgen. Unlike rand, the same inputs always give the same output.
+pub mod noise {
+    /// From 0 to 1 for any point and different for each seed.
+    pub fn hash(x: i32, z: i32, seed: u32) -> f32 {
+        let mut h = seed ^ (x as u32).wrapping_mul(0x27d4eb2d) ^ (z as u32).wrapping_mul(0x165667b1);
+        h ^= h >> 15;
+        h = h.wrapping_mul(0x2c1b3c6d);
+        h ^= h >> 12;
+        h = h.wrapping_mul(0x297a2d39);
+        h ^= h >> 15;
+        h as f32 / u32::MAX as f32
+    }
+
+    /// Value noise from 0 to 1. Hills and valleys are about `scale` blocks apart.
+    pub fn value(x: i32, z: i32, scale: f32, seed: u32) -> f32 {
+        let (fx, fz) = (x as f32 / scale, z as f32 / scale);
+        let (x0, z0) = (fx.floor(), fz.floor());
+        let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+        let (tx, tz) = (smooth(fx - x0), smooth(fz - z0));
+        let (x0, z0) = (x0 as i32, z0 as i32);
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let near = lerp(hash(x0, z0, seed), hash(x0 + 1, z0, seed), tx);
+        let far = lerp(hash(x0, z0 + 1, seed), hash(x0 + 1, z0 + 1, seed), tx);
+        lerp(near, far, tz)
+    }
+}
+
 /// https://blog.orhun.dev/zero-deps-random-in-rust/
 pub mod rand {
     use std::collections::hash_map::RandomState;
@@ -106,3 +356,114 @@ pub mod rand {
         })
     }
 }
+
+#[test]
+fn raycast() {
+    use crate::pos::LocalPos;
+
+    let mut world = LogicChunks::new();
+    for pos in [ChunkPos::new(0, 0, 0), ChunkPos::new(-1, 0, 0)] {
+        world.chunks.insert(pos, Box::new(UnsafeCell::new(Chunk::full(gen::tiles::empty, pos))));
+    }
+    let set = |world: &mut LogicChunks, chunk: ChunkPos, local: LocalPos, tile: Tile| {
+        world.chunks.get_mut(&chunk).unwrap().get_mut().set(local, tile);
+    };
+    set(&mut world, ChunkPos::new(0, 0, 0), LocalPos::new(5, 2, 3), gen::tiles::stone);
+    set(&mut world, ChunkPos::new(-1, 0, 0), LocalPos::new(14, 2, 3), gen::tiles::dirt);
+
+    // Straight along +x hits the west side of the block.
+    let hit = world.raycast(Vec3::new(0.5, 2.5, 3.5), Vec3::X, 10.0).unwrap();
+    assert_eq!(hit.block, BlockPos::new(5, 2, 3));
+    assert_eq!(hit.face, Some(Direction::South));
+    assert_eq!(hit.tile, gen::tiles::stone);
+    assert_eq!(hit.distance, 4.5);
+    assert_eq!(world.raycast(Vec3::new(0.5, 2.5, 3.5), Vec3::X, 4.0), None);
+
+    // Across the chunk border into negative coordinates.
+    let hit = world.raycast(Vec3::new(0.5, 2.5, 3.5), -Vec3::X, 10.0).unwrap();
+    assert_eq!(hit.block, BlockPos::new(-2, 2, 3));
+    assert_eq!(hit.face, Some(Direction::North));
+
+    // Diagonal from above lands on the top.
+    let hit = world.raycast(Vec3::new(5.5, 6.0, 3.5), Vec3::new(0.01, -1.0, 0.0), 10.0).unwrap();
+    assert_eq!(hit.block, BlockPos::new(5, 2, 3));
+    assert_eq!(hit.face, Some(Direction::Up));
+
+    // Starting inside a block has no face.
+    assert_eq!(world.raycast(Vec3::new(5.5, 2.5, 3.5), Vec3::Y, 10.0).unwrap().face, None);
+}
+
+#[test]
+fn mesh_versions() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use crate::renderer::{RecordingRenderer, RenderEvent::*};
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut render = RecordingRenderer::new(events.clone());
+    let mut world = LogicChunks::new();
+    world.meshing = MeshWorkers::new(0);
+    let (a, b) = (ChunkPos::new(0, -1, 0), ChunkPos::new(1, -1, 0));
+    world.get_or_gen(a, &mut render);
+    world.get_or_gen(b, &mut render);
+    assert_eq!(*events.borrow(), [ChunkCreated(a), ChunkCreated(b)]);
+    events.borrow_mut().clear();
+
+    // Changed again before the first mesh came back so only the second one gets used.
+    let chunk = unsafe { &mut *world.chunks[&a].get() };
+    chunk.set(LocalPos::new(5, 0, 5), gen::tiles::empty);
+    world.submit_mesh(a);
+    chunk.set(LocalPos::new(6, 0, 5), gen::tiles::empty);
+    world.submit_mesh(a);
+    world.finish_meshes(&mut render);
+    assert_eq!(world.stale_meshes, 1);
+    assert_eq!(*events.borrow(), [ChunkUpdated(a)]);
+    events.borrow_mut().clear();
+
+    // A change on the edge might uncover a face of the neighbour so that gets remeshed too.
+    chunk.set(LocalPos::new(15, 0, 5), gen::tiles::empty);
+    world.update_meshes(&mut render);
+    assert_eq!(events.borrow().len(), 2);
+    assert!(events.borrow().contains(&ChunkUpdated(a)) && events.borrow().contains(&ChunkUpdated(b)));
+
+    // Results for unloaded chunks are dropped.
+    world.submit_mesh(b);
+    world.chunks.remove(&b);
+    world.finish_meshes(&mut render);
+    assert_eq!(world.stale_meshes, 2);
+}
+
+#[test]
+fn biomes() {
+    assert_eq!(Biome::pick(0.0, 0.5), Biome::Tundra);
+    assert_eq!(Biome::pick(1.0, 0.0), Biome::Desert);
+    assert_eq!(Biome::pick(0.5, 1.0), Biome::Forest);
+    assert_eq!(Biome::pick(0.5, 0.5), Biome::Plains);
+
+    // The chunk with the ground in it remembers each column's biome and uses its surface block.
+    let seed = LogicChunks::DEFAULT_SEED;
+    let (height, _) = column(3, 5, seed);
+    let pos = ChunkPos::new(0, height.div_euclid(CHUNK_SIZE as i32), 0);
+    let mut chunk = Chunk::full(gen::tiles::empty, pos);
+    generate(&mut chunk, seed);
+    for x in 0..CHUNK_SIZE {
+        for z in 0..CHUNK_SIZE {
+            let (height, biome) = column(x as i32, z as i32, seed);
+            assert_eq!(chunk.biome(x, z), biome);
+            let y = height - pos.y * CHUNK_SIZE as i32;
+            if (0..CHUNK_SIZE as i32).contains(&y) {
+                assert_eq!(chunk.get(LocalPos::new(x, y as usize, z)), biome.surface());
+            }
+        }
+    }
+
+    // A tree near the top of a chunk gets finished off by the chunk above.
+    let mut below = Chunk::full(gen::tiles::empty, ChunkPos::new(0, 0, 0));
+    let mut above = Chunk::full(gen::tiles::empty, ChunkPos::new(0, 1, 0));
+    tree(&mut below, 8, 14, 8);
+    tree(&mut above, 8, 14 - CHUNK_SIZE as i32, 8);
+    let tile = |chunk: &Chunk, x, y, z| chunk.get(LocalPos::new(x, y, z));
+    assert_eq!([tile(&below, 8, 14, 8), tile(&below, 8, 15, 8), tile(&above, 8, 0, 8), tile(&above, 8, 1, 8)], [gen::tiles::log; 4]);
+    assert_eq!([tile(&above, 10, 0, 8), tile(&above, 10, 1, 8), tile(&above, 8, 2, 8)], [gen::tiles::leaf; 3]);
+    assert_eq!(tile(&above, 8, 4, 8), gen::tiles::empty);
+}